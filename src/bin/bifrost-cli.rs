@@ -0,0 +1,335 @@
+// Calls services of a running bifrost server, arguments and results as JSON.
+// Services and functions are found through the reflection service of the server.
+
+use bifrost::raft;
+use bifrost::rpc::auth::HmacSigner;
+use bifrost::rpc::reflection::{self, ServiceInfo};
+use bifrost::rpc::{RPCClient, RPCError};
+use bifrost::tcp::client::ClientOptions;
+use bifrost::tcp::tls::{self, TlsClientOptions};
+use bifrost::utils::serde::{codec, set_codec, Codec};
+use futures::prelude::*;
+use serde_json::Value;
+use std::process::exit;
+use std::sync::Arc;
+use std::time::Duration;
+
+const USAGE: &'static str = "\
+Usage: bifrost-cli [options] <address> <command> [arguments]
+
+Commands:
+    services                            List services and their functions
+    call <service> <function> [json]... Call a function, one JSON value per argument
+    cluster-info                        Raft cluster information of the server
+    leader                              Current raft leader
+    members                             Raft cluster members
+    put-offline                         Take the server out of the raft cluster
+
+<service> is a service id or the end of its name, like `raft` for `bifrost::raft`.
+
+Options:
+    --timeout <ms>       Timeout of every call, 10000 by default
+    --raft-service <id>  Service id of raft for raft commands
+    --codec <name>       Codec of the cluster, cbor by default. call needs one that can
+                         be decoded without types, cbor, json or msgpack
+    --cluster-id <id>    Cluster id checked during handshake, 0 by default
+    --tls-ca <file>      Connect over TLS, trusting servers signed by this CA (PEM)
+    --tls-name <name>    Name of the server certificate, the host of <address> by default
+    --tls-cert <file>    Certificate chain presented to servers requiring mutual TLS (PEM)
+    --tls-key <file>     Private key of --tls-cert (PEM)
+    --hmac-key <file>    Sign requests with the shared key in this file
+";
+
+struct Options {
+    timeout: Duration,
+    raft_service_id: u64,
+    cluster_id: u64,
+    tls_ca: Option<String>,
+    tls_name: Option<String>,
+    tls_cert: Option<String>,
+    tls_key: Option<String>,
+    hmac_key: Option<String>,
+    address: String,
+    command: String,
+    args: Vec<String>,
+}
+
+fn fail(message: String) -> ! {
+    eprintln!("{}", message);
+    exit(1)
+}
+
+fn parse_options() -> Options {
+    let mut timeout = Duration::from_secs(10);
+    let mut raft_service_id = raft::DEFAULT_SERVICE_ID;
+    let mut cluster_id = 0;
+    let (mut tls_ca, mut tls_name, mut tls_cert, mut tls_key) = (None, None, None, None);
+    let mut hmac_key = None;
+    let mut positional = vec![];
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => {
+                print!("{}", USAGE);
+                exit(0)
+            }
+            "--codec" => {
+                let codec = args
+                    .next()
+                    .and_then(|name| Codec::from_name(&name))
+                    .unwrap_or_else(|| fail(format!("--codec needs a known codec\n\n{}", USAGE)));
+                set_codec(codec);
+            }
+            "--timeout" | "--raft-service" | "--cluster-id" => {
+                let value = args
+                    .next()
+                    .and_then(|value| value.parse::<u64>().ok())
+                    .unwrap_or_else(|| fail(format!("{} needs a number\n\n{}", arg, USAGE)));
+                match arg.as_str() {
+                    "--timeout" => timeout = Duration::from_millis(value),
+                    "--raft-service" => raft_service_id = value,
+                    _ => cluster_id = value,
+                }
+            }
+            "--tls-ca" | "--tls-name" | "--tls-cert" | "--tls-key" | "--hmac-key" => {
+                let value = args
+                    .next()
+                    .unwrap_or_else(|| fail(format!("{} needs a value\n\n{}", arg, USAGE)));
+                let option = match arg.as_str() {
+                    "--tls-ca" => &mut tls_ca,
+                    "--tls-name" => &mut tls_name,
+                    "--tls-cert" => &mut tls_cert,
+                    "--tls-key" => &mut tls_key,
+                    _ => &mut hmac_key,
+                };
+                *option = Some(value);
+            }
+            _ => positional.push(arg),
+        }
+    }
+    if positional.len() < 2 {
+        fail(String::from(USAGE));
+    }
+    if tls_cert.is_some() != tls_key.is_some() {
+        fail(format!("--tls-cert and --tls-key go together\n\n{}", USAGE));
+    }
+    if tls_ca.is_none() && (tls_name.is_some() || tls_cert.is_some()) {
+        fail(format!("TLS options need --tls-ca\n\n{}", USAGE));
+    }
+    let command = positional.remove(1);
+    let address = positional.remove(0);
+    Options {
+        timeout,
+        raft_service_id,
+        cluster_id,
+        tls_ca,
+        tls_name,
+        tls_cert,
+        tls_key,
+        hmac_key,
+        address,
+        command,
+        args: positional,
+    }
+}
+
+fn read_file(path: &str) -> Vec<u8> {
+    std::fs::read(path).unwrap_or_else(|e| fail(format!("Cannot read {}, {}", path, e)))
+}
+
+fn tls_options(options: &Options) -> Option<TlsClientOptions> {
+    let ca = read_file(options.tls_ca.as_ref()?);
+    let server_name = match &options.tls_name {
+        Some(name) => name.clone(),
+        None => match options.address.rsplit_once(':') {
+            Some((host, _)) => host.to_string(),
+            None => options.address.clone(),
+        },
+    };
+    let identity = match (&options.tls_cert, &options.tls_key) {
+        (Some(cert), Some(key)) => Some((read_file(cert), read_file(key))),
+        _ => None,
+    };
+    let identity = identity
+        .as_ref()
+        .map(|(cert, key)| (cert.as_slice(), key.as_slice()));
+    let tls = tls::client_options(&ca, &server_name, identity)
+        .unwrap_or_else(|e| fail(format!("Cannot set up TLS, {}", e)));
+    Some(tls)
+}
+
+fn print_json(value: &Value) {
+    println!("{}", serde_json::to_string_pretty(value).unwrap());
+}
+
+fn or_fail<T>(res: Result<T, RPCError>, doing: &str) -> T {
+    res.unwrap_or_else(|e| fail(format!("Cannot {}, {:?}", doing, e)))
+}
+
+fn find_service<'a>(services: &'a [ServiceInfo], name: &str) -> &'a ServiceInfo {
+    if let Ok(id) = name.parse::<u64>() {
+        if let Some(info) = services.iter().find(|info| info.id == id) {
+            return info;
+        }
+    }
+    let suffix = format!("::{}", name);
+    let found: Vec<_> = services
+        .iter()
+        .filter(|info| match &info.descriptor {
+            Some(descriptor) => descriptor.name == name || descriptor.name.ends_with(&suffix),
+            None => false,
+        })
+        .collect();
+    match found.as_slice() {
+        [info] => info,
+        [] => fail(format!("No service {}", name)),
+        _ => fail(format!("More than one service matches {}", name)),
+    }
+}
+
+async fn list_services(client: &Arc<RPCClient>) {
+    let services = or_fail(reflection::services(client).await, "list services");
+    for info in services {
+        match info.descriptor {
+            Some(descriptor) => {
+                println!("{} {}", info.id, descriptor.name);
+                for function in descriptor.functions {
+                    let args: Vec<_> = function
+                        .args
+                        .iter()
+                        .map(|(name, ty)| format!("{}: {}", name, ty))
+                        .collect();
+                    let input = match &function.input {
+                        Some((name, ty)) => format!("; {}: stream {}", name, ty),
+                        None => String::new(),
+                    };
+                    let stream = if function.stream { "stream " } else { "" };
+                    println!(
+                        "    {}({}{}) -> {}{}",
+                        function.name,
+                        args.join(", "),
+                        input,
+                        stream,
+                        function.output
+                    );
+                }
+            }
+            None => println!("{} <no descriptor>", info.id),
+        }
+    }
+}
+
+async fn call(client: &Arc<RPCClient>, options: &Options) {
+    if options.args.len() < 2 {
+        fail(String::from(USAGE));
+    }
+    // Arguments and results go through JSON values, which need the types in the data
+    if !codec().self_describing() {
+        fail(format!(
+            "call cannot be used with codec {}, its data cannot be decoded as JSON",
+            codec().name()
+        ));
+    }
+    let services = or_fail(reflection::services(client).await, "list services");
+    let info = find_service(&services, &options.args[0]);
+    let descriptor = info
+        .descriptor
+        .as_ref()
+        .unwrap_or_else(|| fail(format!("Service {} has no descriptor", info.id)));
+    let function = descriptor
+        .function_by_name(&options.args[1])
+        .unwrap_or_else(|| {
+            fail(format!(
+                "No function {} in {}",
+                options.args[1], descriptor.name
+            ))
+        });
+    if function.input.is_some() {
+        fail(format!("{} reads a stream of the caller", function.name));
+    }
+    let args: Vec<Value> = options.args[2..]
+        .iter()
+        .map(|arg| {
+            serde_json::from_str(arg)
+                .unwrap_or_else(|e| fail(format!("Bad argument {}, {}", arg, e)))
+        })
+        .collect();
+    if args.len() != function.args.len() {
+        fail(format!(
+            "{} takes {} arguments, got {}",
+            function.name,
+            function.args.len(),
+            args.len()
+        ));
+    }
+    let timeout = Some(options.timeout);
+    if function.stream {
+        let mut items = or_fail(
+            reflection::stream_json(client, info.id, function, args, timeout).await,
+            "call function",
+        );
+        while let Some(item) = items.next().await {
+            print_json(&or_fail(item, "read stream"));
+        }
+    } else {
+        let res = reflection::call_json(client, info.id, function, args, timeout).await;
+        print_json(&or_fail(res, "call function"));
+    }
+}
+
+async fn cluster_info(client: &Arc<RPCClient>, options: &Options) -> raft::ClientClusterInfo {
+    let service = raft::AsyncServiceClient::new(options.raft_service_id, client)
+        .with_timeout(options.timeout);
+    or_fail(service.c_server_cluster_info().await, "get cluster info")
+}
+
+#[tokio::main]
+async fn main() {
+    let options = parse_options();
+    let client = RPCClient::new_async_with_options(
+        &options.address,
+        ClientOptions {
+            timeout: options.timeout,
+            cluster_id: options.cluster_id,
+            tls: tls_options(&options),
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap_or_else(|e| fail(format!("Cannot connect to {}, {}", options.address, e)));
+    if let Some(path) = &options.hmac_key {
+        client.add_interceptor(Arc::new(HmacSigner::new(&read_file(path))));
+    }
+    match options.command.as_str() {
+        "services" => list_services(&client).await,
+        "call" => call(&client, &options).await,
+        "cluster-info" => {
+            let info = cluster_info(&client, &options).await;
+            print_json(&serde_json::to_value(&info).unwrap());
+        }
+        "leader" => {
+            let info = cluster_info(&client, &options).await;
+            match info.members.iter().find(|(id, _)| *id == info.leader_id) {
+                Some((id, address)) => println!("{} {}", id, address),
+                None => fail(format!("Leader {} is not a known member", info.leader_id)),
+            }
+        }
+        "members" => {
+            let info = cluster_info(&client, &options).await;
+            for (id, address) in &info.members {
+                let leader = if *id == info.leader_id { " leader" } else { "" };
+                println!("{} {}{}", id, address, leader);
+            }
+        }
+        "put-offline" => {
+            let service = raft::AsyncServiceClient::new(options.raft_service_id, &client)
+                .with_timeout(options.timeout);
+            let done = or_fail(service.c_put_offline().await, "put offline");
+            println!("{}", done);
+            if !done {
+                exit(1);
+            }
+        }
+        command => fail(format!("Unknown command {}\n\n{}", command, USAGE)),
+    }
+}
@@ -403,6 +403,7 @@ mod test {
             storage: Storage::default(),
             address: addr.clone(),
             service_id: 0,
+            ..Default::default()
         });
 
         info!("Creating server");
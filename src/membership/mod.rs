@@ -64,6 +64,7 @@ mod test {
             storage: Storage::default(),
             address: addr.clone(),
             service_id: DEFAULT_SERVICE_ID,
+            ..Default::default()
         });
         info!("Creating server");
         let server = Server::new(&addr);
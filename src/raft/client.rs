@@ -54,10 +54,19 @@ pub struct RaftClient {
     last_log_id: AtomicU64,
     last_log_term: AtomicU64,
     service_id: u64,
+    pool: Arc<rpc::ClientPool>,
 }
 
 impl RaftClient {
     pub async fn new(servers: &Vec<String>, service_id: u64) -> Result<Arc<Self>, ClientError> {
+        Self::new_with_pool(servers, service_id, &rpc::DEFAULT_CLIENT_POOL).await
+    }
+    // Connects to the servers with clients from `pool`, e.g. one with another transport
+    pub async fn new_with_pool(
+        servers: &Vec<String>,
+        service_id: u64,
+        pool: &Arc<rpc::ClientPool>,
+    ) -> Result<Arc<Self>, ClientError> {
         let client = RaftClient {
             qry_meta: QryMeta {
                 pos: AtomicU64::new(rand::random::<u64>()),
@@ -70,6 +79,7 @@ impl RaftClient {
             last_log_id: AtomicU64::new(0),
             last_log_term: AtomicU64::new(0),
             service_id,
+            pool: pool.clone(),
         };
         client.update_info(servers).await?;
         Ok(Arc::new(client))
@@ -103,7 +113,7 @@ impl RaftClient {
                         debug!("Checking server info on {}", server_addr);
                         if !members.clients.contains_key(&id) {
                             debug!("Connecting to node {}", server_addr);
-                            match rpc::DEFAULT_CLIENT_POOL.get(&server_addr).await {
+                            match self.pool.get(&server_addr).await {
                                 Ok(client) => {
                                     debug!("Added server info on {} to members", server_addr);
                                     members.clients.insert(
@@ -200,7 +210,7 @@ impl RaftClient {
                 for id in remote_ids.difference(&connected_ids) {
                     let addr = members.id_map.get(id).unwrap().clone();
                     if !members.clients.contains_key(id) {
-                        if let Ok(client) = rpc::DEFAULT_CLIENT_POOL.get(&addr).await {
+                        if let Ok(client) = self.pool.get(&addr).await {
                             members
                                 .clients
                                 .insert(*id, AsyncServiceClient::new(self.service_id, &client));
@@ -221,6 +231,7 @@ impl RaftClient {
         servers: &Vec<String>,
         server_address: &String,
         service_id: u64,
+        pool: &rpc::ClientPool,
     ) -> bool {
         servers
             .iter()
@@ -230,7 +241,7 @@ impl RaftClient {
                         // Should not include the server we are running
                         return false;
                     }
-                    match rpc::DEFAULT_CLIENT_POOL.get(peer_addr).await {
+                    match pool.get(peer_addr).await {
                         Ok(client) => ImmeServiceClient::c_ping(service_id, &client).await.is_ok(),
                         Err(_) => false,
                     }
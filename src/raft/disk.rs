@@ -1,31 +1,154 @@
 // Now only offers log persistent
 
-use crate::raft::{LogEntry, LogsMap, Options, RaftMeta, Storage};
-use async_std::sync::*;
+use crate::raft::storage::{HardState, RaftStorage, RecoveredState};
+use crate::raft::{LogEntry, LogsMap, SnapshotEntity};
+use crate::utils::serde::Codec;
 use serde::{Deserialize, Serialize};
 
-use std::fs::OpenOptions;
+use std::cmp::max;
+use std::fs::{File, OpenOptions};
 use std::io;
-use std::io::Read;
-use std::ops::Bound::*;
-use std::path::Path;
-use tokio::fs::*;
-use tokio::io::*;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 
-// const MAX_LOG_CAPACITY: usize = 10;
+// Logs are kept in segments `log-<first log id>.dat` in the storage path. Each one starts
+// with the magic, format version and id of the codec of its entries, followed by records
+// of the entry length, its bitwise complement, the CRC32 of the length and the entry, and
+// the entry. The complement tells lengths apart from random bytes. A new segment is
+// started once the last one is over `DiskOptions::log_segment_bytes`.
+const LOG_MAGIC: &'static [u8; 4] = b"BFLG";
+const LOG_FORMAT_VERSION: u32 = 2;
+const LOG_HEADER_SIZE: usize = 9;
+const RECORD_HEADER_SIZE: usize = 12;
+
+// `log.dat` next to the storage path from before segments, with a version 1 header or,
+// older, none. Those without are read with the codec of the build that wrote them, JSON
+// in debug and CBOR in release. Their entries are moved into a segment, converted when the
+// process runs with the other one of the two.
+const LEGACY_LOG_FORMAT_VERSION: u32 = 1;
+
+// `snapshot.dat` in the storage path has the same kind of header, followed by one
+// `SnapshotEntity`. It used to be next to the path and is moved in on recovery.
+const SNAPSHOT_MAGIC: &'static [u8; 4] = b"BFSN";
+const SNAPSHOT_FORMAT_VERSION: u32 = 1;
+
+// `hard_state.dat` in the storage path, same header followed by one `HardState`
+const HARD_STATE_MAGIC: &'static [u8; 4] = b"BFHS";
+const HARD_STATE_FORMAT_VERSION: u32 = 1;
 
 #[derive(Clone)]
 pub struct DiskOptions {
     pub path: String,
     pub take_snapshots: bool,
     pub append_logs: bool,
+    // Drop the logs in memory and on disk before the snapshot once it is taken
     pub trim_logs: bool,
+    pub compaction: CompactionPolicy,
+    pub snapshot_transfer: SnapshotTransfer,
+    pub log_segment_bytes: u64,
+}
+
+impl DiskOptions {
+    // Logs and snapshots without compaction
+    pub fn new(path: &str) -> Self {
+        DiskOptions {
+            path: path.to_string(),
+            take_snapshots: true,
+            append_logs: true,
+            trim_logs: false,
+            compaction: CompactionPolicy::default(),
+            snapshot_transfer: SnapshotTransfer::default(),
+            log_segment_bytes: 64 * 1024 * 1024,
+        }
+    }
+}
+
+// When to snapshot the state machines, whichever comes first. Never without any.
+#[derive(Clone, Debug, Default)]
+pub struct CompactionPolicy {
+    // Entries applied since the last snapshot
+    pub log_entries: Option<u64>,
+    // Bytes appended to the log since the last snapshot
+    pub log_bytes: Option<u64>,
+}
+
+// How leaders send snapshots to followers behind the compacted logs
+#[derive(Clone, Debug)]
+pub struct SnapshotTransfer {
+    pub chunk_size: usize,
+    // Unlimited when not set
+    pub max_bytes_per_sec: Option<u64>,
+}
+
+impl Default for SnapshotTransfer {
+    fn default() -> Self {
+        SnapshotTransfer {
+            chunk_size: 1024 * 1024,
+            max_bytes_per_sec: None,
+        }
+    }
+}
+
+struct HardStateFile {
+    path: PathBuf,
+    codec: Codec,
+}
+
+impl HardStateFile {
+    fn load(&self) -> io::Result<Option<HardState>> {
+        let data = match std::fs::read(&self.path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        if !data.starts_with(HARD_STATE_MAGIC) {
+            return Err(invalid_hard_state("unknown file".to_string()));
+        }
+        let codec =
+            read_file_header(&data, HARD_STATE_FORMAT_VERSION).map_err(invalid_hard_state)?;
+        codec
+            .deserialize(&data[LOG_HEADER_SIZE..])
+            .map(Some)
+            .ok_or_else(|| invalid_hard_state(format!("cannot decode with {}", codec.name())))
+    }
+
+    // Replaces the file, synced before it returns
+    fn save(&self, state: &HardState) -> io::Result<()> {
+        let tmp_path = self.path.with_extension("dat.tmp");
+        let mut tmp = std::fs::File::create(&tmp_path)?;
+        tmp.write_all(&file_header(
+            HARD_STATE_MAGIC,
+            HARD_STATE_FORMAT_VERSION,
+            self.codec,
+        ))?;
+        tmp.write_all(&self.codec.serialize(state))?;
+        tmp.sync_all()?;
+        std::fs::rename(&tmp_path, &self.path)?;
+        sync_dir(self.path.parent().unwrap_or(Path::new(".")))
+    }
+}
+
+struct LogSegments {
+    dir: PathBuf,
+    codec: Codec,
+    segment_bytes: u64,
+    // First log id and path of every segment, entries are appended to the last one
+    segments: Vec<(u64, PathBuf)>,
+    active: Option<File>,
+    active_bytes: u64,
 }
 
-pub struct StorageEntity {
-    pub logs: Option<File>,
-    pub snapshot: Option<File>,
-    pub last_term: u64,
+// Logs, snapshot and hard state in files under `DiskOptions::path`
+pub struct DiskStorage {
+    options: DiskOptions,
+    logs: Option<LogSegments>,
+    snapshot: Option<PathBuf>,
+    // Of the entries in the log segments, kept across restarts
+    codec: Codec,
+    hard_state_file: HardStateFile,
+    // Last saved, its term and commit index go along with the entries
+    hard_state: HardState,
+    snapshot_index: u64,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -36,145 +159,899 @@ struct DiskLogEntry {
     log: LogEntry,
 }
 
-impl StorageEntity {
-    pub fn new_with_options(
-        opts: &Options,
-        term: &mut u64,
-        commit_index: &mut u64,
-        last_applied: &mut u64,
-        logs: &mut LogsMap,
-    ) -> io::Result<Option<Self>> {
-        Ok(match &opts.storage {
-            &Storage::DISK(ref options) => {
-                let base_path = Path::new(&options.path);
-                let _ = std::fs::create_dir_all(base_path);
-                let log_path = base_path.with_file_name("log.dat");
-                let snapshot_path = base_path.with_file_name("snapshot.dat");
-                let mut open_opts = OpenOptions::new();
-                open_opts
-                    .write(true)
-                    .create(true)
-                    .read(true)
-                    .truncate(false);
-                Some(Self {
-                    logs: if options.append_logs {
-                        let mut log_file = open_opts.open(log_path.as_path())?;
-                        let mut len_buf = [0u8; 8];
-                        let mut counter = 0;
-                        loop {
-                            if log_file.read_exact(&mut len_buf).is_err() {
-                                break;
-                            }
-                            let len = u64::from_le_bytes(len_buf);
-                            let mut data_buf = vec![0u8; len as usize];
-                            if log_file.read_exact(&mut data_buf).is_err() {
-                                break;
-                            }
-                            let entry = crate::utils::serde::deserialize::<DiskLogEntry>(
-                                data_buf.as_slice(),
-                            )
-                            .unwrap();
-                            *term = entry.term;
-                            *commit_index = entry.commit_index;
-                            *last_applied = entry.last_applied;
-                            logs.insert(entry.log.id, entry.log);
-                            counter += 1;
-                        }
-                        debug!("Recovered {} raft logs", counter);
-                        Some(File::from_std(log_file))
-                    } else {
-                        None
-                    },
-                    snapshot: if options.take_snapshots {
-                        Some(File::from_std(open_opts.open(snapshot_path.as_path())?))
-                    } else {
-                        None
-                    },
-                    last_term: 0,
-                })
+fn file_header(magic: &[u8; 4], version: u32, codec: Codec) -> Vec<u8> {
+    let mut header = Vec::with_capacity(LOG_HEADER_SIZE);
+    header.extend_from_slice(magic);
+    header.extend_from_slice(&version.to_le_bytes());
+    header.push(codec.id());
+    header
+}
+
+fn read_file_header(data: &[u8], expected_version: u32) -> std::result::Result<Codec, String> {
+    if data.len() < LOG_HEADER_SIZE {
+        return Err("truncated header".to_string());
+    }
+    let mut version = [0u8; 4];
+    version.copy_from_slice(&data[LOG_MAGIC.len()..LOG_MAGIC.len() + 4]);
+    let version = u32::from_le_bytes(version);
+    if version != expected_version {
+        return Err(format!("unknown format version {}", version));
+    }
+    let codec_id = data[LOG_HEADER_SIZE - 1];
+    Codec::from_id(codec_id).ok_or_else(|| format!("codec {} is not built in", codec_id))
+}
+
+fn log_header(codec: Codec) -> Vec<u8> {
+    file_header(LOG_MAGIC, LOG_FORMAT_VERSION, codec)
+}
+
+fn read_log_header(data: &[u8]) -> io::Result<Codec> {
+    read_file_header(data, LOG_FORMAT_VERSION).map_err(invalid_log)
+}
+
+fn segment_path(dir: &Path, first_id: u64) -> PathBuf {
+    dir.join(format!("log-{:020}.dat", first_id))
+}
+
+fn list_segments(dir: &Path) -> io::Result<Vec<(u64, PathBuf)>> {
+    let mut segments = vec![];
+    for file in std::fs::read_dir(dir)? {
+        let path = file?.path();
+        let first_id = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix("log-"))
+            .and_then(|name| name.strip_suffix(".dat"))
+            .and_then(|id| id.parse::<u64>().ok());
+        if let Some(first_id) = first_id {
+            segments.push((first_id, path));
+        }
+    }
+    segments.sort();
+    Ok(segments)
+}
+
+fn record_crc(len: &[u8], record: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(len);
+    hasher.update(record);
+    hasher.finalize()
+}
+
+fn encode_records(records: &[Vec<u8>]) -> Vec<u8> {
+    let size = records.iter().map(|r| RECORD_HEADER_SIZE + r.len()).sum();
+    let mut data = Vec::with_capacity(size);
+    for record in records {
+        let len = (record.len() as u32).to_le_bytes();
+        data.extend_from_slice(&len);
+        data.extend_from_slice(&(!(record.len() as u32)).to_le_bytes());
+        data.extend_from_slice(&record_crc(&len, record).to_le_bytes());
+        data.extend_from_slice(record);
+    }
+    data
+}
+
+fn read_u32(data: &[u8], pos: usize) -> u32 {
+    let mut buf = [0u8; 4];
+    buf.copy_from_slice(&data[pos..pos + 4]);
+    u32::from_le_bytes(buf)
+}
+
+// The record at `pos` when it is complete and passes its checksum
+fn intact_record(data: &[u8], pos: usize) -> Option<&[u8]> {
+    if data.len() - pos < RECORD_HEADER_SIZE {
+        return None;
+    }
+    let len = read_u32(data, pos);
+    if len != !read_u32(data, pos + 4) {
+        return None;
+    }
+    let end = pos + RECORD_HEADER_SIZE + len as usize;
+    if end > data.len() {
+        return None;
+    }
+    let record = &data[pos + RECORD_HEADER_SIZE..end];
+    if record_crc(&data[pos..pos + 4], record) != read_u32(data, pos + 8) {
+        return None;
+    }
+    Some(record)
+}
+
+// Records of a segment and the length of its intact part. A torn write only leaves its
+// records at the end, a broken record followed by an intact one is corruption.
+fn read_records<'a>(data: &'a [u8], path: &Path) -> io::Result<(Vec<&'a [u8]>, usize)> {
+    let mut records = vec![];
+    let mut pos = LOG_HEADER_SIZE;
+    while pos < data.len() {
+        match intact_record(data, pos) {
+            Some(record) => {
+                records.push(record);
+                pos += RECORD_HEADER_SIZE + record.len();
+            }
+            None => {
+                if (pos + 1..data.len()).any(|next| intact_record(data, next).is_some()) {
+                    return Err(invalid_log(format!(
+                        "corrupted record at byte {} of {}",
+                        pos,
+                        path.display()
+                    )));
+                }
+                break;
+            }
+        }
+    }
+    Ok((records, pos))
+}
+
+// Makes files created, renamed or removed in `dir` durable
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+impl LogSegments {
+    // Entries in all segments, a torn write at the end of the last one is cut off
+    fn open(dir: &Path, codec: Codec, segment_bytes: u64) -> io::Result<(Self, Vec<DiskLogEntry>)> {
+        let segments = list_segments(dir)?;
+        let mut log = LogSegments {
+            dir: dir.to_path_buf(),
+            codec,
+            segment_bytes,
+            segments: vec![],
+            active: None,
+            active_bytes: 0,
+        };
+        let mut entries = vec![];
+        let num_segments = segments.len();
+        for (i, (first_id, path)) in segments.into_iter().enumerate() {
+            let is_last = i + 1 == num_segments;
+            let data = std::fs::read(&path)?;
+            if is_last
+                && data.len() < LOG_HEADER_SIZE
+                && LOG_MAGIC.starts_with(&data[..4.min(data.len())])
+            {
+                // Crashed while starting the segment
+                std::fs::remove_file(&path)?;
+                break;
+            }
+            if !data.starts_with(LOG_MAGIC) {
+                return Err(invalid_log(format!(
+                    "{} is not a log segment",
+                    path.display()
+                )));
+            }
+            let codec = read_log_header(&data)?;
+            check_codec(codec, log.codec, &path)?;
+            let (records, intact) = read_records(&data, &path)?;
+            if intact < data.len() {
+                if !is_last {
+                    return Err(invalid_log(format!(
+                        "incomplete record at byte {} of {}",
+                        intact,
+                        path.display()
+                    )));
+                }
+                warn!(
+                    "Cutting off torn raft log record at byte {} of {}",
+                    intact,
+                    path.display()
+                );
+                let file = OpenOptions::new().write(true).open(&path)?;
+                file.set_len(intact as u64)?;
+                file.sync_all()?;
             }
-            _ => None,
+            for record in records {
+                let entry = codec.deserialize(record).ok_or_else(|| {
+                    invalid_log(format!(
+                        "cannot decode entry in {} with {}",
+                        path.display(),
+                        codec.name()
+                    ))
+                })?;
+                entries.push(entry);
+            }
+            if is_last {
+                log.active_bytes = intact as u64;
+                log.active = Some(OpenOptions::new().append(true).open(&path)?);
+            }
+            log.segments.push((first_id, path));
+        }
+        Ok((log, entries))
+    }
+
+    fn start_segment(&mut self, first_id: u64) -> io::Result<File> {
+        let path = segment_path(&self.dir, first_id);
+        let mut file = File::create(&path)?;
+        file.write_all(&log_header(self.codec))?;
+        file.sync_all()?;
+        sync_dir(&self.dir)?;
+        self.segments.push((first_id, path));
+        self.active_bytes = LOG_HEADER_SIZE as u64;
+        Ok(file)
+    }
+
+    // Appends and syncs the records of entries from `first_id` on, returns bytes written
+    fn append(&mut self, first_id: u64, records: &[Vec<u8>]) -> io::Result<u64> {
+        if self.active.is_none() || self.active_bytes >= self.segment_bytes {
+            self.active = Some(self.start_segment(first_id)?);
+        }
+        let data = encode_records(records);
+        let file = self.active.as_mut().unwrap();
+        file.write_all(&data)?;
+        file.sync_all()?;
+        self.active_bytes += data.len() as u64;
+        Ok(data.len() as u64)
+    }
+
+    // Removes segments with only entries before `id`
+    fn remove_before(&mut self, id: u64) -> io::Result<()> {
+        while self.segments.len() > 1 && self.segments[1].0 <= id {
+            let (_, path) = self.segments.remove(0);
+            std::fs::remove_file(&path)?;
+        }
+        Ok(())
+    }
+
+    // Replaces all segments with one of the records of entries from `first_id` on. It is
+    // complete on disk before the old segments go.
+    fn reset(&mut self, first_id: u64, records: &[Vec<u8>]) -> io::Result<()> {
+        self.active = None;
+        let path = segment_path(&self.dir, first_id);
+        let old_segments = std::mem::take(&mut self.segments);
+        if !records.is_empty() {
+            let tmp_path = path.with_extension("dat.tmp");
+            let mut data = log_header(self.codec);
+            data.extend_from_slice(&encode_records(records));
+            let mut tmp = File::create(&tmp_path)?;
+            tmp.write_all(&data)?;
+            tmp.sync_all()?;
+            std::fs::rename(&tmp_path, &path)?;
+            sync_dir(&self.dir)?;
+            self.segments.push((first_id, path.clone()));
+            self.active_bytes = data.len() as u64;
+            self.active = Some(OpenOptions::new().append(true).open(&path)?);
+        }
+        for (_, old_path) in old_segments {
+            if old_path != path {
+                std::fs::remove_file(&old_path)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Removes the entries from `id` on, with the segments they start
+    fn truncate_from(&mut self, id: u64) -> io::Result<()> {
+        while self.segments.last().map_or(false, |s| s.0 >= id) {
+            let (_, path) = self.segments.pop().unwrap();
+            std::fs::remove_file(&path)?;
+            self.active = None;
+        }
+        let path = match self.segments.last() {
+            Some((_, path)) => path.clone(),
+            None => return Ok(()),
+        };
+        let data = std::fs::read(&path)?;
+        let codec = read_log_header(&data)?;
+        let (records, mut len) = read_records(&data, &path)?;
+        let mut pos = LOG_HEADER_SIZE;
+        for record in records {
+            let entry: DiskLogEntry = codec
+                .deserialize(record)
+                .ok_or_else(|| invalid_log(format!("cannot decode entry in {}", path.display())))?;
+            if entry.log.id >= id {
+                len = pos;
+                break;
+            }
+            pos += RECORD_HEADER_SIZE + record.len();
+        }
+        let file = OpenOptions::new().append(true).open(&path)?;
+        file.set_len(len as u64)?;
+        file.sync_all()?;
+        self.active = Some(file);
+        self.active_bytes = len as u64;
+        Ok(())
+    }
+}
+
+// JSON entries are objects, CBOR ones are maps which never start with `{`
+fn legacy_log_codec(data: &[u8]) -> Codec {
+    if data.get(8) == Some(&b'{') {
+        Codec::Json
+    } else {
+        Codec::Cbor
+    }
+}
+
+// Entries of a legacy `log.dat` up to the first incomplete one
+fn read_log_entries(mut data: &[u8], codec: Codec) -> io::Result<Vec<DiskLogEntry>> {
+    let mut entries = vec![];
+    while data.len() >= 8 {
+        let mut len_buf = [0u8; 8];
+        len_buf.copy_from_slice(&data[..8]);
+        let len = u64::from_le_bytes(len_buf) as usize;
+        if data.len() - 8 < len {
+            break;
+        }
+        let entry = codec
+            .deserialize::<DiskLogEntry>(&data[8..8 + len])
+            .ok_or_else(|| invalid_log(format!("cannot decode entry with {}", codec.name())))?;
+        entries.push(entry);
+        data = &data[8 + len..];
+    }
+    Ok(entries)
+}
+
+fn read_legacy_log(data: &[u8]) -> io::Result<(Codec, Vec<DiskLogEntry>)> {
+    if data.starts_with(LOG_MAGIC) {
+        let codec = read_file_header(data, LEGACY_LOG_FORMAT_VERSION).map_err(invalid_log)?;
+        Ok((codec, read_log_entries(&data[LOG_HEADER_SIZE..], codec)?))
+    } else {
+        let codec = legacy_log_codec(data);
+        Ok((codec, read_log_entries(data, codec)?))
+    }
+}
+
+fn invalid_log(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("raft log: {}", message))
+}
+
+fn invalid_hard_state(message: String) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("raft hard state: {}", message),
+    )
+}
+
+fn invalid_snapshot(message: String) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("raft snapshot: {}", message),
+    )
+}
+
+// CBOR and JSON represent commands the same way, the data of legacy entries is decoded as
+// JSON values and encoded again with the codec of the process. Other codecs cannot be
+// converted to.
+fn convert_legacy_entries(
+    entries: Vec<DiskLogEntry>,
+    from: Codec,
+    to: Codec,
+    path: &Path,
+) -> io::Result<Vec<DiskLogEntry>> {
+    if from == to || !matches!(to, Codec::Cbor | Codec::Json) {
+        check_codec(from, to, path)?;
+        return Ok(entries);
+    }
+    entries
+        .into_iter()
+        .map(|mut entry| {
+            let data: serde_json::Value = from.deserialize(&entry.log.data).ok_or_else(|| {
+                invalid_log(format!(
+                    "cannot convert log {} from {}",
+                    entry.log.id,
+                    from.name()
+                ))
+            })?;
+            entry.log.data = to.serialize(&data);
+            Ok(entry)
         })
+        .collect()
+}
+
+// The codec of the process, state machines decode the data of entries and snapshots with it
+fn check_codec(found: Codec, expected: Codec, path: &Path) -> io::Result<()> {
+    if found == expected {
+        return Ok(());
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "{} was written with {} but the process uses {}, run it with `set_codec` to {} to read it",
+            path.display(),
+            found.name(),
+            expected.name(),
+            found.name()
+        ),
+    ))
+}
+
+fn read_snapshot(path: &Path, expected: Codec) -> io::Result<Option<SnapshotEntity>> {
+    let data = match std::fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    if data.is_empty() {
+        return Ok(None);
+    }
+    if !data.starts_with(SNAPSHOT_MAGIC) {
+        return Err(invalid_snapshot("unknown file".to_string()));
     }
+    let codec = read_file_header(&data, SNAPSHOT_FORMAT_VERSION).map_err(invalid_snapshot)?;
+    check_codec(codec, expected, path)?;
+    codec
+        .deserialize(&data[LOG_HEADER_SIZE..])
+        .map(Some)
+        .ok_or_else(|| invalid_snapshot(format!("cannot decode with {}", codec.name())))
+}
 
-    pub async fn append_logs<'a>(
-        &mut self,
-        meta: &'a RwLockWriteGuard<'a, RaftMeta>,
-        logs: &'a RwLockWriteGuard<'a, LogsMap>,
-    ) -> io::Result<()> {
-        if let Some(f) = &mut self.logs {
-            let was_last_term = self.last_term;
-            let mut counter = 0;
-            let mut terms_appended = vec![];
-            for (term, log) in logs.range((Excluded(self.last_term), Unbounded)) {
-                let entry = DiskLogEntry {
-                    term: *term,
-                    commit_index: meta.commit_index,
-                    last_applied: meta.last_applied,
-                    log: log.clone(),
-                };
-                let entry_data = crate::utils::serde::serialize(&entry);
-                f.write(&(entry_data.len() as u64).to_le_bytes()).await?;
-                f.write(entry_data.as_slice()).await?;
-                self.last_term = *term;
-                terms_appended.push(self.last_term);
-                counter += 1;
+// The old snapshot stays until the new one is synced
+fn write_snapshot(path: &Path, codec: Codec, snapshot: &SnapshotEntity) -> io::Result<()> {
+    let tmp_path = path.with_extension("dat.tmp");
+    let mut tmp = std::fs::File::create(&tmp_path)?;
+    tmp.write_all(&file_header(SNAPSHOT_MAGIC, SNAPSHOT_FORMAT_VERSION, codec))?;
+    tmp.write_all(&codec.serialize(snapshot))?;
+    tmp.sync_all()?;
+    std::fs::rename(&tmp_path, path)?;
+    sync_dir(path.parent().unwrap_or_else(|| Path::new(".")))
+}
+
+// Restores what `entries` recorded, leaving out logs already in the snapshot
+fn recover_entries(
+    entries: Vec<DiskLogEntry>,
+    snapshot_index: u64,
+    term: &mut u64,
+    commit_index: &mut u64,
+    logs: &mut LogsMap,
+) {
+    for entry in entries {
+        *term = entry.term;
+        *commit_index = max(*commit_index, entry.commit_index);
+        if entry.log.id >= snapshot_index {
+            logs.insert(entry.log.id, entry.log);
+        }
+    }
+}
+
+impl DiskStorage {
+    pub fn new(options: &DiskOptions) -> Self {
+        let base_path = Path::new(&options.path);
+        let codec = crate::utils::serde::codec();
+        DiskStorage {
+            options: options.clone(),
+            logs: None,
+            snapshot: if options.take_snapshots {
+                Some(base_path.join("snapshot.dat"))
+            } else {
+                None
+            },
+            codec,
+            hard_state_file: HardStateFile {
+                path: base_path.join("hard_state.dat"),
+                codec,
+            },
+            hard_state: HardState::default(),
+            snapshot_index: 0,
+        }
+    }
+}
+
+impl RaftStorage for DiskStorage {
+    // Restores the logs after the snapshot, later entries than it are applied again
+    fn recover(&mut self) -> io::Result<RecoveredState> {
+        let options = &self.options;
+        let base_path = Path::new(&options.path);
+        let _ = std::fs::create_dir_all(base_path);
+        let legacy_path = base_path.with_file_name("log.dat");
+        let mut state = RecoveredState::default();
+        let (mut term, mut commit_index) = (0, 0);
+        if let Some(path) = &self.snapshot {
+            // Snapshots used to be kept next to the directory, where nodes sharing a parent
+            // would overwrite each other's
+            let legacy_snapshot = base_path.with_file_name("snapshot.dat");
+            if !path.exists() && legacy_snapshot.exists() {
+                info!(
+                    "Moving raft snapshot from {} to {}",
+                    legacy_snapshot.display(),
+                    path.display()
+                );
+                std::fs::rename(&legacy_snapshot, path)?;
+                sync_dir(base_path)?;
             }
-            if counter > 0 {
-                f.sync_all().await?;
+            if let Some(entity) = read_snapshot(path.as_path(), self.codec)? {
                 debug!(
-                    "Appended and persisted {} logs, was {}, appended {:?}",
-                    counter, was_last_term, terms_appended
+                    "Recovered raft snapshot at {}, term {}",
+                    entity.last_applied, entity.term
                 );
+                self.snapshot_index = entity.last_applied;
+                term = entity.term;
+                commit_index = entity.commit_index;
+                state.snapshot = Some(entity);
             }
         }
-        Ok(())
+        if options.append_logs {
+            let (mut segments, mut entries) =
+                LogSegments::open(base_path, self.codec, options.log_segment_bytes)?;
+            if legacy_path.exists() {
+                if segments.segments.is_empty() {
+                    let (legacy_codec, legacy) = read_legacy_log(&std::fs::read(&legacy_path)?)?;
+                    let legacy =
+                        convert_legacy_entries(legacy, legacy_codec, self.codec, &legacy_path)?;
+                    info!(
+                        "Migrating {} raft logs from {} in {} to segments with {}",
+                        legacy.len(),
+                        legacy_codec.name(),
+                        legacy_path.display(),
+                        self.codec.name()
+                    );
+                    let records: Vec<_> = legacy
+                        .iter()
+                        .map(|entry| self.codec.serialize(entry))
+                        .collect();
+                    let first_id = legacy.first().map_or(1, |entry| entry.log.id);
+                    segments.reset(first_id, &records)?;
+                    entries = legacy;
+                }
+                std::fs::remove_file(&legacy_path)?;
+            }
+            debug!("Recovered {} raft logs", entries.len());
+            recover_entries(
+                entries,
+                self.snapshot_index,
+                &mut term,
+                &mut commit_index,
+                &mut state.logs,
+            );
+            self.logs = Some(segments);
+        }
+        let mut vote_for = None;
+        if let Some(hard_state) = self.hard_state_file.load()? {
+            debug!("Recovered raft hard state {:?}", hard_state);
+            if hard_state.term >= term {
+                term = hard_state.term;
+                vote_for = hard_state.vote_for;
+            }
+            commit_index = max(commit_index, hard_state.commit_index);
+        }
+        self.hard_state = HardState {
+            term,
+            vote_for,
+            commit_index,
+        };
+        state.hard_state = self.hard_state.clone();
+        Ok(state)
+    }
+
+    fn append(&mut self, entries: &[LogEntry]) -> io::Result<u64> {
+        let segments = match (&mut self.logs, entries.first()) {
+            (Some(segments), Some(_)) => segments,
+            _ => return Ok(0),
+        };
+        let (hard_state, snapshot_index) = (&self.hard_state, self.snapshot_index);
+        let records: Vec<_> = entries
+            .iter()
+            .map(|log| {
+                segments.codec.serialize(&DiskLogEntry {
+                    term: hard_state.term,
+                    commit_index: hard_state.commit_index,
+                    last_applied: snapshot_index,
+                    log: log.clone(),
+                })
+            })
+            .collect();
+        segments.append(entries[0].id, &records)
+    }
+
+    fn truncate_suffix(&mut self, id: u64) -> io::Result<()> {
+        match &mut self.logs {
+            Some(segments) => segments.truncate_from(id),
+            None => Ok(()),
+        }
+    }
+
+    fn compact_prefix(&mut self, id: u64) -> io::Result<()> {
+        match &mut self.logs {
+            Some(segments) => segments.remove_before(id),
+            None => Ok(()),
+        }
     }
 
-    pub async fn post_processing<'a>(
-        &mut self,
-        meta: &RwLockWriteGuard<'a, RaftMeta>,
-        logs: RwLockWriteGuard<'a, LogsMap>,
-    ) -> io::Result<()> {
-        // TODO: trim logs in memory
-        // TODO: trim logs on disk
-        self.append_logs(meta, &logs).await?;
+    fn save_hard_state(&mut self, state: &HardState) -> io::Result<()> {
+        self.hard_state_file.save(state)?;
+        self.hard_state = state.clone();
+        Ok(())
+    }
 
+    fn save_snapshot(&mut self, snapshot: &SnapshotEntity) -> io::Result<()> {
+        if let Some(path) = &self.snapshot {
+            write_snapshot(path.as_path(), self.codec, snapshot)?;
+        }
+        self.snapshot_index = snapshot.last_applied;
         Ok(())
+    }
+
+    fn load_snapshot(&self) -> io::Result<Option<SnapshotEntity>> {
+        match &self.snapshot {
+            Some(path) => read_snapshot(path.as_path(), self.codec),
+            None => Ok(None),
+        }
+    }
+
+    // Compacting without snapshots would lose the entries
+    fn compaction(&self) -> CompactionPolicy {
+        if self.options.take_snapshots {
+            self.options.compaction.clone()
+        } else {
+            CompactionPolicy::default()
+        }
+    }
+
+    fn trim_logs(&self) -> bool {
+        self.options.trim_logs
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::raft::storage::{post_processing, StorageEntity};
+    use crate::raft::RaftMeta;
+    use async_std::sync::RwLock;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+
+    fn open_with(options: DiskOptions) -> (DiskStorage, RecoveredState) {
+        let mut storage = DiskStorage::new(&options);
+        let state = storage.recover().unwrap();
+        (storage, state)
+    }
+
+    fn open(path: &str) -> (DiskStorage, RecoveredState) {
+        open_with(DiskOptions {
+            take_snapshots: false,
+            ..DiskOptions::new(path)
+        })
+    }
+
+    fn log_entry(id: u64) -> LogEntry {
+        LogEntry {
+            id,
+            term: 1,
+            sm_id: 1,
+            fn_id: 2,
+            data: vec![id as u8],
+        }
+    }
+
+    // Recovers as a process running with `codec`
+    fn open_with_codec(options: &DiskOptions, codec: Codec) -> io::Result<RecoveredState> {
+        let mut storage = DiskStorage::new(options);
+        storage.codec = codec;
+        storage.hard_state_file.codec = codec;
+        storage.recover()
+    }
+
+    #[test]
+    fn legacy_log_migration() {
+        let dir = std::env::temp_dir().join(format!("bifrost-disk-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("raft");
+        let log_path = dir.join("log.dat");
+        let member = |id| format!("127.0.0.1:{}", 2100 + id);
+        // Written by a debug build before the header, `new_member_` commands of the configs
+        let mut legacy = vec![];
+        for id in 1..=3 {
+            let entry = Codec::Json.serialize(&DiskLogEntry {
+                term: id,
+                commit_index: id,
+                last_applied: id,
+                log: LogEntry {
+                    id,
+                    term: id,
+                    sm_id: crate::raft::state_machine::configs::CONFIG_SM_ID,
+                    fn_id: 2,
+                    data: Codec::Json.serialize(&(member(id),)),
+                },
+            });
+            legacy.extend_from_slice(&(entry.len() as u64).to_le_bytes());
+            legacy.extend_from_slice(&entry);
+        }
+        // Torn write
+        legacy.extend_from_slice(&[1, 2, 3]);
+        std::fs::write(&log_path, &legacy).unwrap();
+        let options = DiskOptions {
+            take_snapshots: false,
+            ..DiskOptions::new(path.to_str().unwrap())
+        };
+
+        // Commands cannot be converted to codecs representing them differently
+        #[cfg(feature = "msgpack")]
+        {
+            let err = open_with_codec(&options, Codec::MessagePack).err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert!(log_path.exists());
+        }
+
+        // A migration that crashed before its segment was complete, the commands are
+        // converted to the codec of the process
+        std::fs::create_dir_all(&path).unwrap();
+        let tmp_path = segment_path(&path, 1).with_extension("dat.tmp");
+        std::fs::write(&tmp_path, log_header(Codec::Cbor)).unwrap();
+        let state = open_with_codec(&options, Codec::Cbor).unwrap();
+        assert_eq!((state.logs.len(), state.hard_state.term), (3, 3));
+        let data = &state.logs.get(&2).unwrap().data;
+        assert_eq!(
+            Codec::Cbor.deserialize::<(String,)>(data),
+            Some((member(2),))
+        );
+        assert!(!log_path.exists());
+        let data = std::fs::read(segment_path(&path, 1)).unwrap();
+        assert_eq!(read_log_header(&data).unwrap(), Codec::Cbor);
+
+        let state = open_with_codec(&options, Codec::Cbor).unwrap();
+        assert_eq!((state.logs.len(), state.hard_state.term), (3, 3));
+        assert!(open_with_codec(&options, Codec::Json).is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn torn_and_corrupt_records() {
+        let dir = std::env::temp_dir().join(format!("bifrost-disk-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let codec = crate::utils::serde::codec();
+        let record = |id| {
+            codec.serialize(&DiskLogEntry {
+                term: 1,
+                commit_index: 0,
+                last_applied: 0,
+                log: log_entry(id),
+            })
+        };
+        let (mut segments, entries) = LogSegments::open(&dir, codec, 1024).unwrap();
+        assert!(entries.is_empty());
+        segments
+            .reset(1, &(1..=3).map(record).collect::<Vec<_>>())
+            .unwrap();
+        drop(segments);
+        let path = segment_path(&dir, 1);
+        let intact = std::fs::read(&path).unwrap();
+
+        // Cut in the header and in the entry
+        for cut in &[10, RECORD_HEADER_SIZE + 2] {
+            let mut torn = intact.clone();
+            torn.extend_from_slice(&encode_records(&[record(4)])[..*cut]);
+            std::fs::write(&path, &torn).unwrap();
+            let (_, entries) = LogSegments::open(&dir, codec, 1024).unwrap();
+            assert_eq!(entries.len(), 3);
+            assert_eq!(std::fs::read(&path).unwrap(), intact);
+        }
+        let mut torn = intact.clone();
+        let mut last = encode_records(&[record(4)]);
+        let last_byte = last.len() - 1;
+        last[last_byte] ^= 1;
+        torn.extend_from_slice(&last);
+        std::fs::write(&path, &torn).unwrap();
+        let (_, entries) = LogSegments::open(&dir, codec, 1024).unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(std::fs::read(&path).unwrap(), intact);
+
+        let mut corrupt = intact.clone();
+        let second_entry = LOG_HEADER_SIZE + 2 * RECORD_HEADER_SIZE + record(1).len();
+        corrupt[second_entry] ^= 1;
+        std::fs::write(&path, &corrupt).unwrap();
+        let err = LogSegments::open(&dir, codec, 1024).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // A flipped length is no torn write when intact records follow it
+        let mut corrupt = intact.clone();
+        corrupt[LOG_HEADER_SIZE + 3] ^= 0x40;
+        std::fs::write(&path, &corrupt).unwrap();
+        let err = LogSegments::open(&dir, codec, 1024).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(std::fs::read(&path).unwrap(), corrupt);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn compaction() {
+        let dir = std::env::temp_dir().join(format!("bifrost-disk-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("raft");
+        let options = DiskOptions {
+            trim_logs: true,
+            compaction: CompactionPolicy {
+                log_entries: Some(3),
+                log_bytes: None,
+            },
+            // A segment for every append
+            log_segment_bytes: 1,
+            ..DiskOptions::new(path.to_str().unwrap())
+        };
+        let (storage, _) = StorageEntity::recover(Box::new(DiskStorage::new(&options))).unwrap();
+        let meta = RwLock::new(RaftMeta {
+            term: 1,
+            vote_for: None,
+            timeout: 0,
+            last_checked: 0,
+            membership: crate::raft::Membership::Undefined,
+            logs: Arc::new(RwLock::new(LogsMap::new())),
+            state_machine: Arc::new(RwLock::new(
+                crate::raft::state_machine::master::MasterStateMachine::new(0),
+            )),
+            commit_index: 0,
+            last_applied: 0,
+            leader_id: 0,
+            last_saved_id: storage.shared_last_saved_id(),
+            storage: Arc::new(parking_lot::Mutex::new(storage)),
+        });
+        let mut meta = meta.write().await;
+        for id in 1..=4 {
+            meta.commit_index = id;
+            meta.last_applied = id - 1;
+            crate::raft::save_hard_state(&meta).await.unwrap();
+            let mut logs = meta.logs.write().await;
+            logs.insert(id, log_entry(id));
+            post_processing(&meta, logs).await.unwrap();
+        }
+        // Taken at entry 3, which stays for entry 4 to follow
+        let logs = meta.logs.read().await;
+        assert_eq!(logs.keys().cloned().collect::<Vec<_>>(), vec![3, 4]);
+        assert_eq!(meta.storage.lock().snapshot_index, 3);
+        assert_eq!(meta.storage.lock().last_saved_id(), 4);
+        assert_eq!(meta.last_saved_id.load(Ordering::Relaxed), 4);
+        let segments: Vec<_> = list_segments(&path).unwrap().iter().map(|s| s.0).collect();
+        assert_eq!(segments, vec![3, 4]);
+
+        let (_, state) = open_with(options.clone());
+        assert_eq!(state.logs.keys().cloned().collect::<Vec<_>>(), vec![3, 4]);
+        let snapshot = state.snapshot.unwrap();
+        assert_eq!(
+            (state.hard_state.term, state.hard_state.commit_index),
+            (1, 4)
+        );
+        assert_eq!(snapshot.last_applied, 3);
+
+        // Moved in from where it used to be, next to the storage path
+        std::fs::rename(path.join("snapshot.dat"), dir.join("snapshot.dat")).unwrap();
+        let (_, state) = open_with(options);
+        assert_eq!(state.snapshot.unwrap().last_applied, 3);
+        assert!(path.join("snapshot.dat").exists());
+        assert!(!dir.join("snapshot.dat").exists());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn truncate_suffix() {
+        let dir = std::env::temp_dir().join(format!("bifrost-disk-{}", rand::random::<u64>()));
+        let path = dir.join("raft");
+        let options = DiskOptions {
+            log_segment_bytes: 1,
+            ..DiskOptions::new(path.to_str().unwrap())
+        };
+        let (mut storage, _) = open_with(options.clone());
+        storage.append(&[log_entry(1), log_entry(2)]).unwrap();
+        storage.append(&[log_entry(3), log_entry(4)]).unwrap();
+        storage.append(&[log_entry(5)]).unwrap();
+        // Into the middle of a segment, dropping the one after it
+        storage.truncate_suffix(4).unwrap();
+        let mut replaced = log_entry(4);
+        replaced.term = 2;
+        storage.append(&[replaced]).unwrap();
+        let segments: Vec<_> = list_segments(&path).unwrap().iter().map(|s| s.0).collect();
+        assert_eq!(segments, vec![1, 3, 4]);
+
+        let (_, state) = open_with(options);
+        assert_eq!(
+            state.logs.keys().cloned().collect::<Vec<_>>(),
+            vec![1, 2, 3, 4]
+        );
+        assert_eq!(state.logs.get(&4).unwrap().term, 2);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
 
-        // let (last_log_id, _) = get_last_log_info!(self, logs);
-        // let expecting_oldest_log = if last_log_id > MAX_LOG_CAPACITY as u64 {
-        //     last_log_id - MAX_LOG_CAPACITY as u64
-        // } else {
-        //     0
-        // };
-        // let double_cap = MAX_LOG_CAPACITY << 1;
-        // if logs.len() > double_cap && meta.last_applied > expecting_oldest_log {
-        //     debug!("trim logs");
-        //     while logs.len() > MAX_LOG_CAPACITY {
-        //         let first_key = *logs.iter().next().unwrap().0;
-        //         logs.remove(&first_key).unwrap();
-        //     }
-        //     if let Some(ref storage) = meta.storage {
-        //         let mut storage = storage.write().await;
-        //         let snapshot = SnapshotEntity {
-        //             term: meta.term,
-        //             commit_index: meta.commit_index,
-        //             last_applied: meta.last_applied,
-        //             snapshot: meta.state_machine.read().await.snapshot().unwrap(),
-        //         };
-        //         storage
-        //             .snapshot
-        //             .write_all(crate::utils::serde::serialize(&snapshot).as_slice())?;
-        //         storage.snapshot.sync_all().unwrap();
-        //     }
-        // }
-        // if let Some(ref storage) = meta.storage {
-        //     let mut storage = storage.write().await;
-        //     let logs_data = crate::utils::serde::serialize(&*meta.logs.read().await);
-        //     // TODO: async file system calls
-        //     storage.logs.write_all(logs_data.as_slice())?;
-        //     storage.logs.sync_all().unwrap();
-        // }
+    #[test]
+    fn hard_state() {
+        let dir = std::env::temp_dir().join(format!("bifrost-disk-{}", rand::random::<u64>()));
+        let path = dir.join("raft");
+        let (mut storage, _) = open(path.to_str().unwrap());
+        let state = HardState {
+            term: 5,
+            vote_for: Some(7),
+            commit_index: 3,
+        };
+        storage.save_hard_state(&state).unwrap();
+        assert!(path.join("hard_state.dat").exists());
+        let (_, recovered) = open(path.to_str().unwrap());
+        assert_eq!(recovered.hard_state, state);
+        let _ = std::fs::remove_dir_all(&dir);
     }
 }
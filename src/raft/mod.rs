@@ -1,10 +1,17 @@
 use self::state_machine::configs::commands::{del_member_, member_address, new_member_};
 use self::state_machine::configs::{RaftMember, CONFIG_SM_ID};
-use self::state_machine::master::{ExecError, ExecResult, MasterStateMachine, SubStateMachine};
+use self::state_machine::master::{
+    ExecError, ExecResult, MasterStateMachine, SnapshotDataItems, SubStateMachine,
+};
 use self::state_machine::OpType;
 use crate::raft::client::RaftClient;
 use crate::raft::disk::*;
 use crate::raft::state_machine::StateMachineCtl;
+use crate::raft::storage::*;
+use crate::rpc::{ClientPool, DEFAULT_CLIENT_POOL};
+use crate::tcp::client::ClientOptions;
+use crate::tcp::server::ServerOptions;
+use crate::tcp::transport::SharedTransport;
 use crate::utils::time::get_time;
 use async_std::sync::*;
 use bifrost_hasher::hash_str;
@@ -12,14 +19,15 @@ use bifrost_plugins::hash_ident;
 use futures::future::BoxFuture;
 use futures::prelude::*;
 use futures::stream::FuturesUnordered;
+use parking_lot::Mutex as SyncMutex;
 use rand::Rng;
 use serde::{Deserialize, Serialize};
 use std::cmp::{max, min};
 use std::collections::Bound::{Included, Unbounded};
 use std::collections::{BTreeMap, HashMap};
 use std::io;
-use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering::Relaxed;
+use std::sync::atomic::{AtomicBool, AtomicU64};
 use std::time::Duration;
 use tokio::runtime;
 use tokio::time::*;
@@ -28,8 +36,11 @@ use tokio::time::*;
 pub mod state_machine;
 pub mod client;
 pub mod disk;
+pub mod storage;
 
 pub static DEFAULT_SERVICE_ID: u64 = hash_ident!(BIFROST_RAFT_DEFAULT_SERVICE) as u64;
+pub const DEFAULT_RESERVED_SLOTS: usize = 2;
+pub const DEFAULT_MAX_IN_FLIGHT: usize = 1024;
 
 pub trait RaftMsg<R>: Send + Sync {
     fn encode(self) -> (u64, OpType, Vec<u8>);
@@ -69,10 +80,10 @@ pub enum ClientQryResponse {
 }
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ClientClusterInfo {
-    members: Vec<(u64, String)>,
-    last_log_id: u64,
-    last_log_term: u64,
-    leader_id: u64,
+    pub members: Vec<(u64, String)>,
+    pub last_log_id: u64,
+    pub last_log_term: u64,
+    pub leader_id: u64,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -80,23 +91,38 @@ pub enum AppendEntriesResult {
     Ok,
     TermOut(u64),
     LogMismatch,
+    // Entries could not be saved, the leader sends them again later
+    StorageFailure,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum InstallSnapshotResult {
+    // Bytes of the snapshot received so far, where the next chunk starts
+    Received(u64),
+    Installed,
+    TermOut,
+    // Failed its checksum or cannot be decoded, to be sent again from the start
+    Invalid,
+    // Could not be saved, nothing was installed. To be sent again from the start.
+    StorageFailure,
 }
 
-#[derive(Serialize, Deserialize)]
+// State machines as of entry `last_applied`, `term` is the term of that entry
+#[derive(Serialize, Deserialize, Clone)]
 pub struct SnapshotEntity {
-    term: u64,
-    commit_index: u64,
-    last_applied: u64,
-    snapshot: Vec<u8>,
+    pub term: u64,
+    pub commit_index: u64,
+    pub last_applied: u64,
+    pub snapshot: Vec<u8>,
 }
 
 type LogEntries = Vec<LogEntry>;
-type LogsMap = BTreeMap<u64, LogEntry>;
+pub type LogsMap = BTreeMap<u64, LogEntry>;
 
 service! {
     rpc append_entries(term: u64, leader_id: u64, prev_log_id: u64, prev_log_term: u64, entries: Option<LogEntries>, leader_commit: u64) -> (u64, AppendEntriesResult);
     rpc request_vote(term: u64, candidate_id: u64, last_log_id: u64, last_log_term: u64) -> ((u64, u64), bool); // term, voteGranted
-    rpc install_snapshot(term: u64, leader_id: u64, last_included_index: u64, last_included_term: u64, data: Vec<u8>) -> u64;
+    rpc install_snapshot(term: u64, leader_id: u64, last_included_index: u64, last_included_term: u64, offset: u64, data: Vec<u8>, done: bool, checksum: u32) -> (u64, InstallSnapshotResult);
     rpc c_command(entry: LogEntry) -> ClientCmdResponse;
     rpc c_query(entry: LogEntry) -> ClientQryResponse;
     rpc c_server_cluster_info() -> ClientClusterInfo;
@@ -105,6 +131,28 @@ service! {
     rpc c_ping();
 }
 
+// Limits for the raft service keeping `reserved` slots of the server capacity for each of
+// replication, elections and snapshots, so client commands and queries cannot starve them.
+// Register with `rpc::Server::register_service_with_limits`, as `new_server` does. Nothing
+// is reserved until the capacity is set with `rpc::Server::set_max_in_flight`, which
+// `new_server` does from `Options::max_in_flight`.
+pub fn service_limits(reserved: usize) -> crate::rpc::limits::ServiceLimits {
+    use crate::rpc::limits::{Limits, ServiceLimits};
+    ServiceLimits::new(Limits::new())
+        .function(
+            hash_ident!(append_entries) as u64,
+            Limits::new().reserve(reserved),
+        )
+        .function(
+            hash_ident!(request_vote) as u64,
+            Limits::new().reserve(reserved),
+        )
+        .function(
+            hash_ident!(install_snapshot) as u64,
+            Limits::new().reserve(reserved),
+        )
+}
+
 fn gen_rand(lower: i64, higher: i64) -> i64 {
     let mut rng = rand::thread_rng();
     rng.gen_range(lower..higher)
@@ -117,8 +165,23 @@ fn gen_timeout() -> i64 {
 struct FollowerStatus {
     next_index: u64,
     match_index: u64,
+    // Sending a snapshot, no heartbeats until done
+    installing: bool,
+    // Where to resume the snapshot after a failed transfer
+    snapshot_offset: usize,
+}
+
+// Chunks of a snapshot from the leader, installed when all are received
+struct ReceivingSnapshot {
+    index: u64,
+    term: u64,
+    data: Vec<u8>,
 }
 
+// Give up a snapshot transfer after this many failed chunks in a row, the next heartbeat
+// resumes it
+const SNAPSHOT_CHUNK_RETRIES: u32 = 5;
+
 pub struct LeaderMeta {
     last_updated: i64,
     followers: HashMap<u64, Arc<Mutex<FollowerStatus>>>,
@@ -152,13 +215,19 @@ pub struct RaftMeta {
     commit_index: u64,
     last_applied: u64,
     leader_id: u64,
-    storage: Option<Arc<Mutex<StorageEntity>>>,
+    // Of the storage, read without its lock
+    last_saved_id: Arc<AtomicU64>,
+    storage: Arc<SyncMutex<StorageEntity>>,
 }
 
+// Makes the storage of a custom engine when the service starts
+pub type StorageFactory = Arc<dyn Fn() -> Box<dyn RaftStorage> + Send + Sync>;
+
 #[derive(Clone)]
 pub enum Storage {
     MEMORY,
     DISK(DiskOptions),
+    CUSTOM(StorageFactory),
 }
 
 impl Storage {
@@ -172,6 +241,27 @@ pub struct Options {
     pub storage: Storage,
     pub address: String,
     pub service_id: u64,
+    // Members are reached over `DEFAULT_CLIENT_POOL` without one, otherwise over a pool of
+    // the service with its options and this transport, such as a `SimNetwork` node
+    pub transport: Option<SharedTransport>,
+    // Slots reserved for each of replication, elections and snapshots by `new_server`, see
+    // `service_limits`. They are taken out of `max_in_flight` and need it.
+    pub reserved_slots: usize,
+    // Calls running at once on the server made by `new_server`
+    pub max_in_flight: Option<usize>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            storage: Storage::default(),
+            address: String::new(),
+            service_id: DEFAULT_SERVICE_ID,
+            transport: None,
+            reserved_slots: DEFAULT_RESERVED_SLOTS,
+            max_in_flight: Some(DEFAULT_MAX_IN_FLIGHT),
+        }
+    }
 }
 
 pub struct RaftService {
@@ -179,7 +269,9 @@ pub struct RaftService {
     pub id: u64,
     pub options: Options,
     rt: runtime::Runtime,
+    client_pool: Arc<ClientPool>,
     _is_leader: AtomicBool,
+    receiving_snapshot: Mutex<Option<ReceivingSnapshot>>,
 }
 dispatch_rpc_service_functions!(RaftService);
 
@@ -217,6 +309,13 @@ async fn check_commit(meta: &mut RwLockWriteGuard<'_, RaftMeta>) {
     }
 }
 
+// Entries of the leader before `log_id` are committed along with it, including those left
+// uncommitted by earlier terms
+async fn commit_preceding(meta: &mut RwLockWriteGuard<'_, RaftMeta>, log_id: u64) {
+    meta.commit_index = max(meta.commit_index, log_id - 1);
+    check_commit(meta).await;
+}
+
 fn is_majority(members: u64, granted: u64) -> bool {
     let required = members / 2 + 1;
     let majority = granted >= (required);
@@ -240,47 +339,80 @@ fn is_leader(meta: &RwLockWriteGuard<RaftMeta>) -> bool {
         _ => false,
     }
 }
-fn alter_term(meta: &mut RwLockWriteGuard<RaftMeta>, term: u64) {
+// Moves to `term` unless it cannot be persisted, a node must not forget a term it was in
+async fn alter_term(meta: &mut RwLockWriteGuard<'_, RaftMeta>, term: u64) -> bool {
     if meta.term != term {
+        let (old_term, old_vote) = (meta.term, meta.vote_for);
         meta.term = term;
         meta.vote_for = None;
+        if let Err(e) = save_hard_state(meta).await {
+            error!(
+                "Cannot persist term {}, staying in {}, {}",
+                term, old_term, e
+            );
+            meta.term = old_term;
+            meta.vote_for = old_vote;
+            return false;
+        }
     }
+    true
+}
+
+async fn save_hard_state(meta: &RaftMeta) -> io::Result<()> {
+    let state = HardState {
+        term: meta.term,
+        vote_for: meta.vote_for,
+        commit_index: meta.commit_index,
+    };
+    storage::blocking(&meta.storage, move |storage| {
+        storage.save_hard_state(&state)
+    })
+    .await
 }
 
 impl RaftService {
     pub fn new(opts: Options) -> Arc<RaftService> {
+        Self::try_new(opts).unwrap()
+    }
+    // Like `new`, but returns storage errors, such as a corrupted log, instead of panicking
+    pub fn try_new(opts: Options) -> io::Result<Arc<RaftService>> {
         let server_address = opts.address.clone();
         let server_id = hash_str(&server_address);
 
-        let mut term = 0;
-        let mut logs = BTreeMap::new();
-        let mut commit_index = 0;
-        let mut last_applied = 0;
-
-        let storage_entity = StorageEntity::new_with_options(
-            &opts,
-            &mut term,
-            &mut commit_index,
-            &mut last_applied,
-            &mut logs,
-        )
-        .unwrap();
-
-        let master_sm = MasterStateMachine::new(opts.service_id);
+        let engine: Box<dyn RaftStorage> = match &opts.storage {
+            Storage::MEMORY => Box::new(MemoryStorage::default()),
+            Storage::DISK(options) => Box::new(DiskStorage::new(options)),
+            Storage::CUSTOM(factory) => factory(),
+        };
+        let (storage, recovered) = StorageEntity::recover(engine)?;
+        let hard_state = recovered.hard_state;
+        let last_applied = recovered.snapshot.as_ref().map_or(0, |s| s.last_applied);
+        let client_pool = match &opts.transport {
+            Some(transport) => Arc::new(ClientPool::new_with_options(ClientOptions {
+                transport: transport.clone(),
+                ..DEFAULT_CLIENT_POOL.options()
+            })),
+            None => DEFAULT_CLIENT_POOL.clone(),
+        };
+        let mut master_sm = MasterStateMachine::new_with_pool(opts.service_id, &client_pool);
+        if let Some(snapshot) = recovered.snapshot {
+            master_sm.stash_snapshot(snapshot.snapshot)?;
+        }
 
         let server_obj = RaftService {
             meta: RwLock::new(RaftMeta {
-                term,
-                vote_for: None,
+                term: hard_state.term,
+                vote_for: hard_state.vote_for,
                 timeout: gen_timeout(),
                 last_checked: get_time(),
                 membership: Membership::Undefined,
-                logs: Arc::new(RwLock::new(logs)),
+                logs: Arc::new(RwLock::new(recovered.logs)),
                 state_machine: Arc::new(RwLock::new(master_sm)),
-                commit_index,
+                commit_index: hard_state.commit_index,
                 last_applied,
                 leader_id: 0,
-                storage: storage_entity.map(|e| Arc::new(Mutex::new(e))),
+                last_saved_id: storage.shared_last_saved_id(),
+                storage: Arc::new(SyncMutex::new(storage)),
             }),
             id: server_id,
             options: opts,
@@ -291,9 +423,11 @@ impl RaftService {
                 .max_blocking_threads(num_cpus::get())
                 .build()
                 .unwrap(),
+            client_pool,
             _is_leader: AtomicBool::new(false),
+            receiving_snapshot: Mutex::new(None),
         };
-        Arc::new(server_obj)
+        Ok(Arc::new(server_obj))
     }
     pub async fn start(server: &Arc<RaftService>) -> bool {
         let server_address = server.options.address.clone();
@@ -314,6 +448,7 @@ impl RaftService {
             if !inited {
                 return false;
             }
+            sm.recover_configs().await;
         }
         let checker_ref = server.clone();
         server.rt.spawn(async {
@@ -405,17 +540,38 @@ impl RaftService {
     pub async fn new_server(opts: Options) -> (bool, Arc<RaftService>, Arc<Server>) {
         let address = opts.address.clone();
         let svr_id = opts.service_id;
+        let limits = service_limits(opts.reserved_slots);
+        let max_in_flight = opts.max_in_flight;
+        if opts.reserved_slots > 0 && max_in_flight.is_none() {
+            warn!("Raft slots reserved without a server capacity, nothing is reserved");
+        }
+        let server = match &opts.transport {
+            Some(transport) => Server::new_with_options(
+                &address,
+                ServerOptions {
+                    transport: transport.clone(),
+                    ..ServerOptions::default()
+                },
+            ),
+            None => Server::new(&address),
+        };
         let service = RaftService::new(opts);
-        let server = Server::new(&address);
+        server.set_max_in_flight(max_in_flight);
         Server::listen_and_resume(&server).await;
-        server.register_service(svr_id, &service).await;
+        server
+            .register_service_with_limits(svr_id, &service, limits)
+            .await;
         (RaftService::start(&service).await, service, server)
     }
     pub async fn probe_and_join(&self, servers: &Vec<String>) -> Result<bool, ExecError> {
         debug!("Probing and try to join servers: {:?}", servers);
-        let is_first_node =
-            !RaftClient::probe_servers(servers, &self.options.address, self.options.service_id)
-                .await;
+        let is_first_node = !RaftClient::probe_servers(
+            servers,
+            &self.options.address,
+            self.options.service_id,
+            &self.client_pool,
+        )
+        .await;
         if is_first_node {
             debug!("There is no live node in the server list, will bootstrap");
             self.bootstrap().await;
@@ -431,15 +587,19 @@ impl RaftService {
             let logs = meta.logs.read().await;
             get_last_log_info!(self, logs)
         };
+        // Replay recovered logs up to the persisted commit index. Later ones may never have
+        // reached a quorum, the first entry of the new leader commits them.
+        check_commit(&mut meta).await;
         self.become_leader(&mut meta, last_log_id).await;
     }
     pub async fn conservative_bootstrap(&self, servers: &Vec<String>) {
         let meta = self.meta.read().await;
         debug!("Conservative bootstrap, checking storage");
-        if let Some(storage) = &meta.storage {
-            debug!("There are storage, checking last term");
-            if storage.lock().await.last_term > 0 {
-                debug!("There are logged term, will probe and join or bootstrap");
+        let persistent = meta.storage.lock().persistent();
+        if persistent {
+            debug!("There are storage, checking saved logs");
+            if meta.last_saved_id.load(Relaxed) > 0 {
+                debug!("There are saved logs, will probe and join or bootstrap");
                 drop(meta);
                 self.probe_and_join(servers).await.unwrap();
             } else {
@@ -455,7 +615,8 @@ impl RaftService {
     }
     pub async fn join(&self, servers: &Vec<String>) -> Result<bool, ExecError> {
         debug!("Trying to join cluster with id {}", self.id);
-        let client = RaftClient::new(servers, self.options.service_id).await;
+        let client =
+            RaftClient::new_with_pool(servers, self.options.service_id, &self.client_pool).await;
         if let Ok(client) = client {
             debug!(
                 "Executing in SM to create new member {}, {}",
@@ -481,7 +642,7 @@ impl RaftService {
                 }
             }
             debug!("Become follower bacause of join: {}", self.id);
-            self.become_follower(&mut meta, 0, client.leader_id());
+            self.become_follower(&mut meta, 0, client.leader_id()).await;
             debug!("Resetting last checked for join: {}", self.id);
             self.reset_last_checked(&mut meta);
             debug!(
@@ -502,7 +663,9 @@ impl RaftService {
             .iter()
             .map(|&(_, ref address)| address.clone())
             .collect();
-        if let Ok(client) = RaftClient::new(&servers, self.options.service_id).await {
+        let client =
+            RaftClient::new_with_pool(&servers, self.options.service_id, &self.client_pool).await;
+        if let Ok(client) = client {
             client
                 .execute(CONFIG_SM_ID, del_member_::new(&self.options.address))
                 .await
@@ -574,7 +737,7 @@ impl RaftService {
     pub async fn register_state_machine(&self, state_machine: SubStateMachine) {
         let meta = self.meta.read().await;
         let mut master_sm = meta.state_machine.write().await;
-        master_sm.register(state_machine);
+        master_sm.register(state_machine).await;
     }
     fn switch_membership(&self, meta: &mut RwLockWriteGuard<RaftMeta>, membership: Membership) {
         self.reset_last_checked(meta);
@@ -600,6 +763,8 @@ impl RaftService {
             Arc::new(Mutex::new(FollowerStatus {
                 next_index: last_log_id + 1,
                 match_index: 0,
+                installing: false,
+                snapshot_offset: 0,
             }))
         });
     }
@@ -625,9 +790,16 @@ impl RaftService {
         let server_id = self.id;
         debug!("{} become candidate", server_id);
         self.reset_last_checked(meta);
-        let term = meta.term;
-        alter_term(meta, term + 1);
+        let (old_term, old_vote) = (meta.term, meta.vote_for);
+        meta.term += 1;
         meta.vote_for = Some(server_id);
+        if let Err(e) = save_hard_state(meta).await {
+            // Could vote again in the term after a restart
+            error!("Cannot persist vote for self, not campaigning, {}", e);
+            meta.term = old_term;
+            meta.vote_for = old_vote;
+            return;
+        }
         self.switch_membership(meta, Membership::Candidate);
         let term = meta.term;
         let (last_log_id, last_log_term) = {
@@ -697,7 +869,8 @@ impl RaftService {
                 }
                 match res {
                     Ok(RequestVoteResponse::TermOut(remote_term, remote_leader_id)) => {
-                        self.become_follower(meta, remote_term, remote_leader_id);
+                        self.become_follower(meta, remote_term, remote_leader_id)
+                            .await;
                         break;
                     }
                     Ok(RequestVoteResponse::Granted) => {
@@ -720,10 +893,18 @@ impl RaftService {
         return;
     }
 
-    fn become_follower(&self, meta: &mut RwLockWriteGuard<RaftMeta>, term: u64, leader_id: u64) {
-        alter_term(meta, term);
+    async fn become_follower(
+        &self,
+        meta: &mut RwLockWriteGuard<'_, RaftMeta>,
+        term: u64,
+        leader_id: u64,
+    ) -> bool {
+        if !alter_term(meta, term).await {
+            return false;
+        }
         meta.leader_id = leader_id;
         self.switch_membership(meta, Membership::Follower);
+        true
     }
 
     async fn become_leader(&self, meta: &mut RwLockWriteGuard<'_, RaftMeta>, last_log_id: u64) {
@@ -785,8 +966,8 @@ impl RaftService {
                         meta.commit_index,
                         meta.term,
                         meta.leader_id,
-                        meta.last_applied,
-                        meta.state_machine.clone(),
+                        meta.storage.clone(),
+                        self.snapshot_transfer(),
                         meta.logs.clone(),
                         follower.clone(),
                         member.rpc.clone(),
@@ -839,8 +1020,8 @@ impl RaftService {
         commit_index: u64,
         term: u64,
         leader_id: u64,
-        last_applied: u64,
-        master_sm: Arc<RwLock<MasterStateMachine>>,
+        storage: Arc<SyncMutex<StorageEntity>>,
+        transfer: SnapshotTransfer,
         logs: Arc<RwLock<LogsMap>>,
         follower: Arc<Mutex<FollowerStatus>>,
         rpc: Arc<AsyncServiceClient>,
@@ -855,7 +1036,11 @@ impl RaftService {
         // let master_sm = &meta.state_machine;
         // let logs = &meta.logs;
         trace!("Sending follower heartbeat to {}", member_id);
+        let follower_status = follower.clone();
         let mut follower = follower.lock().await;
+        if follower.installing {
+            return follower.match_index;
+        }
         let logs = logs.read().await;
         let mut is_retry = false;
         loop {
@@ -893,22 +1078,25 @@ impl RaftService {
                 } else {
                     follower.next_index - 1
                 };
+                // detect compacted logs, the first one is the last in the snapshot
+                let first_log_id = logs.keys().next().cloned().unwrap_or(0);
+                if first_log_id > 1 && follower_last_log_id < first_log_id {
+                    debug!("Sending snapshot to follower {}", member_id);
+                    follower.installing = true;
+                    tokio::spawn(Self::send_follower_snapshot(
+                        term,
+                        leader_id,
+                        storage,
+                        transfer,
+                        follower_status,
+                        rpc,
+                        member_id,
+                    ));
+                    return follower.match_index;
+                }
                 if follower_last_log_id == 0 || logs.is_empty() {
                     (0, 0) // 0 represents there is no logs in the leader
                 } else {
-                    // detect cleaned logs
-                    let (first_log_id, _) = logs.iter().next().unwrap();
-                    if *first_log_id > follower_last_log_id {
-                        debug!(
-                            "Taking snapshot of all state machines and install them on follower {}",
-                            member_id
-                        );
-                        let master_sm = master_sm.read().await;
-                        let snapshot = master_sm.snapshot().unwrap();
-                        rpc.install_snapshot(term, leader_id, last_applied, term, snapshot)
-                            .await
-                            .unwrap();
-                    }
                     let follower_last_entry = logs.get(&follower_last_log_id);
                     match follower_last_entry {
                         Some(entry) => (entry.id, entry.term),
@@ -951,6 +1139,10 @@ impl RaftService {
                     AppendEntriesResult::TermOut(_actual_leader_id) => {
                         break;
                     }
+                    AppendEntriesResult::StorageFailure => {
+                        debug!("Follower {} cannot persist logs", member_id);
+                        break;
+                    }
                 },
                 _ => {
                     break;
@@ -961,15 +1153,196 @@ impl RaftService {
         follower.match_index
     }
 
+    // Streams the last snapshot taken in chunks, the follower tells where to go on after
+    // failures. Logs are only compacted once their snapshot is stored, a snapshot of the
+    // state machines now could have more entries applied than the index it is sent with.
+    async fn send_follower_snapshot(
+        term: u64,
+        leader_id: u64,
+        storage: Arc<SyncMutex<StorageEntity>>,
+        transfer: SnapshotTransfer,
+        follower: Arc<Mutex<FollowerStatus>>,
+        rpc: Arc<AsyncServiceClient>,
+        member_id: u64,
+    ) {
+        let stored = storage::blocking(&storage, |storage| storage.load_snapshot()).await;
+        let snapshot = match stored {
+            Ok(Some(snapshot)) => Some(snapshot),
+            Ok(None) => {
+                error!("No snapshot to send to follower {}", member_id);
+                None
+            }
+            Err(e) => {
+                error!("Cannot load snapshot to send, {}", e);
+                None
+            }
+        };
+        let mut offset = follower.lock().await.snapshot_offset;
+        let installed = match &snapshot {
+            Some(snapshot) => {
+                let data = &snapshot.snapshot;
+                let checksum = crc32fast::hash(data);
+                // Throttled chunks have to go out within a heartbeat interval, the follower
+                // only hears from the leader through them during the transfer
+                let chunk_size = match transfer.max_bytes_per_sec {
+                    Some(rate) => min(
+                        transfer.chunk_size,
+                        (rate * HEARTBEAT_MS as u64 / 1000) as usize,
+                    ),
+                    None => transfer.chunk_size,
+                };
+                let chunk_size = max(chunk_size, 1);
+                let mut failures = 0;
+                loop {
+                    offset = min(offset, data.len());
+                    let end = min(offset + chunk_size, data.len());
+                    let chunk = data[offset..end].to_vec();
+                    let sent = chunk.len() as u64;
+                    let res = rpc
+                        .install_snapshot(
+                            term,
+                            leader_id,
+                            snapshot.last_applied,
+                            snapshot.term,
+                            offset as u64,
+                            chunk,
+                            end == data.len(),
+                            checksum,
+                        )
+                        .await;
+                    match res {
+                        Ok((_, InstallSnapshotResult::Installed)) => break true,
+                        Ok((_, InstallSnapshotResult::TermOut)) => break false,
+                        Ok((_, InstallSnapshotResult::Invalid)) => {
+                            failures += 1;
+                            error!("Follower {} rejected snapshot as invalid", member_id);
+                            offset = 0;
+                            if failures >= SNAPSHOT_CHUNK_RETRIES {
+                                break false;
+                            }
+                        }
+                        Ok((_, InstallSnapshotResult::StorageFailure)) => {
+                            failures += 1;
+                            error!("Follower {} cannot persist snapshot", member_id);
+                            offset = 0;
+                            if failures >= SNAPSHOT_CHUNK_RETRIES {
+                                break false;
+                            }
+                            sleep(Duration::from_millis(100 << failures)).await;
+                        }
+                        Ok((_, InstallSnapshotResult::Received(received))) => {
+                            failures = 0;
+                            offset = received as usize;
+                            if let Some(rate) = transfer.max_bytes_per_sec {
+                                sleep(Duration::from_millis(sent * 1000 / max(rate, 1))).await;
+                            }
+                        }
+                        Err(e) => {
+                            failures += 1;
+                            debug!(
+                                "Cannot send snapshot chunk to follower {}, {:?}",
+                                member_id, e
+                            );
+                            if failures >= SNAPSHOT_CHUNK_RETRIES {
+                                break false;
+                            }
+                            sleep(Duration::from_millis(100 << failures)).await;
+                        }
+                    }
+                }
+            }
+            None => false,
+        };
+        let mut follower = follower.lock().await;
+        follower.installing = false;
+        match (installed, snapshot) {
+            (true, Some(snapshot)) => {
+                debug!(
+                    "Installed snapshot at {} on follower {}",
+                    snapshot.last_applied, member_id
+                );
+                follower.next_index = snapshot.last_applied + 1;
+                follower.match_index = snapshot.last_applied;
+                follower.snapshot_offset = 0;
+            }
+            _ => follower.snapshot_offset = offset,
+        }
+    }
+
+    // The snapshot is saved before anything changes, the state machines and logs are left
+    // as they were when it cannot be
+    async fn install_snapshot_data<'a>(
+        &self,
+        meta: &mut RwLockWriteGuard<'a, RaftMeta>,
+        last_included_index: u64,
+        last_included_term: u64,
+        data: Vec<u8>,
+    ) -> io::Result<()> {
+        check_commit(meta).await;
+        if last_included_index <= meta.last_applied {
+            return Ok(());
+        }
+        let commit_index = max(meta.commit_index, last_included_index);
+        // Keep later logs only when they follow the snapshot
+        let installed_logs: LogsMap = {
+            let logs = meta.logs.read().await;
+            let follows = logs
+                .get(&last_included_index)
+                .map_or(false, |entry| entry.term == last_included_term);
+            if follows {
+                logs.range(last_included_index..)
+                    .map(|(id, entry)| (*id, entry.clone()))
+                    .collect()
+            } else {
+                let mut logs = LogsMap::new();
+                logs.insert(
+                    last_included_index,
+                    LogEntry {
+                        id: last_included_index,
+                        term: last_included_term,
+                        sm_id: 0,
+                        fn_id: 0,
+                        data: vec![],
+                    },
+                );
+                logs
+            }
+        };
+        let snapshot = SnapshotEntity {
+            term: last_included_term,
+            commit_index,
+            last_applied: last_included_index,
+            snapshot: data.clone(),
+        };
+        let logs: Vec<_> = installed_logs.values().cloned().collect();
+        storage::blocking(&meta.storage, move |storage| {
+            storage.install_snapshot(&snapshot, logs)
+        })
+        .await?;
+        meta.state_machine.write().await.recover(data).await;
+        meta.commit_index = commit_index;
+        meta.last_applied = last_included_index;
+        *meta.logs.write().await = installed_logs;
+        Ok(())
+    }
+
+    fn snapshot_transfer(&self) -> SnapshotTransfer {
+        match &self.options.storage {
+            Storage::DISK(options) => options.snapshot_transfer.clone(),
+            Storage::MEMORY | Storage::CUSTOM(_) => SnapshotTransfer::default(),
+        }
+    }
+
     //check term number, return reject = false if server term is stale
-    fn check_term(
+    async fn check_term(
         &self,
-        meta: &mut RwLockWriteGuard<RaftMeta>,
+        meta: &mut RwLockWriteGuard<'_, RaftMeta>,
         remote_term: u64,
         leader_id: u64,
     ) -> bool {
         if remote_term > meta.term {
-            self.become_follower(meta, remote_term, leader_id)
+            // Rejected when the term cannot be persisted
+            return self.become_follower(meta, remote_term, leader_id).await;
         } else if remote_term < meta.term {
             return false;
         }
@@ -990,7 +1363,7 @@ impl RaftService {
         &'a self,
         meta: &'a RwLockWriteGuard<'a, RaftMeta>,
         entry: &mut LogEntry,
-    ) -> (u64, u64) {
+    ) -> io::Result<(u64, u64)> {
         let mut logs = meta.logs.write().await;
         let (last_log_id, _last_log_term) = get_last_log_info!(self, logs);
         let new_log_id = last_log_id + 1;
@@ -998,8 +1371,8 @@ impl RaftService {
         entry.term = new_log_term;
         entry.id = new_log_id;
         logs.insert(entry.id, entry.clone());
-        self.logs_post_processing(meta, logs).await.unwrap();
-        (new_log_id, new_log_term)
+        self.logs_post_processing(meta, logs).await?;
+        Ok((new_log_id, new_log_term))
     }
 
     async fn logs_post_processing<'a>(
@@ -1007,11 +1380,7 @@ impl RaftService {
         meta: &'a RwLockWriteGuard<'a, RaftMeta>,
         logs: RwLockWriteGuard<'a, LogsMap>,
     ) -> io::Result<()> {
-        if let Some(storage_mutex) = &meta.storage {
-            let mut storage = storage_mutex.lock().await;
-            storage.post_processing(meta, logs).await?;
-        }
-        Ok(())
+        storage::post_processing(meta, logs).await
     }
 
     async fn try_sync_log_to_followers<'a>(
@@ -1025,8 +1394,11 @@ impl RaftService {
             .send_followers_heartbeat(&mut meta, Some(new_log_id), true)
             .await
         {
+            commit_preceding(&mut meta, new_log_id).await;
             meta.commit_index = new_log_id;
-            Some(commit_command(&mut meta, entry).await)
+            let res = commit_command(&mut meta, entry).await;
+            meta.last_applied = max(meta.last_applied, new_log_id);
+            Some(res)
         } else {
             None
         }
@@ -1039,8 +1411,10 @@ impl RaftService {
     ) -> ExecResult {
         // this will force followers to commit the changes
         debug!("Sync config to followers");
+        commit_preceding(&mut meta, new_log_id).await;
         meta.commit_index = new_log_id;
         let data = commit_command(&meta, &entry).await;
+        meta.last_applied = max(meta.last_applied, new_log_id);
         if let Membership::Leader(ref leader_meta) = meta.membership {
             let mut leader_meta = leader_meta.write().await;
             let member_sm = meta.state_machine.read().await;
@@ -1066,11 +1440,11 @@ impl Service for RaftService {
         async move {
             let mut meta = self.write_meta().await;
             self.reset_last_checked(&mut meta);
-            let term_ok = self.check_term(&mut meta, term, leader_id); // RI, 1
+            let term_ok = self.check_term(&mut meta, term, leader_id).await; // RI, 1
             let result = if term_ok {
                 if let Membership::Candidate = meta.membership {
                     debug!("SWITCH FROM CANDIDATE BACK TO FOLLOWER {}", self.id);
-                    self.become_follower(&mut meta, term, leader_id);
+                    self.become_follower(&mut meta, term, leader_id).await;
                 }
                 if prev_log_id > 0 {
                     check_commit(&mut meta).await;
@@ -1087,13 +1461,16 @@ impl Service for RaftService {
                     }
                     if log_mismatch {
                         //RI, 3
-                        let ids_to_del: Vec<u64> = logs
-                            .range((Included(prev_log_id), Unbounded))
-                            .map(|(id, _)| *id)
-                            .collect();
-                        for id in ids_to_del {
-                            logs.remove(&id);
+                        // Saved entries first, they would come back after a restart
+                        let truncated = storage::blocking(&meta.storage, move |storage| {
+                            storage.truncate_logs(prev_log_id)
+                        })
+                        .await;
+                        if let Err(e) = truncated {
+                            error!("Cannot truncate mismatched logs, {}", e);
+                            return (meta.term, AppendEntriesResult::StorageFailure);
                         }
+                        logs.split_off(&prev_log_id);
                         return (meta.term, AppendEntriesResult::LogMismatch); // log mismatch
                     }
                 }
@@ -1110,7 +1487,10 @@ impl Service for RaftService {
                     } else if !logs.is_empty() {
                         last_new_entry = logs.values().last().unwrap().id;
                     }
-                    self.logs_post_processing(&meta, logs).await.unwrap();
+                    if let Err(e) = self.logs_post_processing(&meta, logs).await {
+                        error!("Cannot persist logs from leader, {}", e);
+                        return (meta.term, AppendEntriesResult::StorageFailure);
+                    }
                 }
                 if leader_commit > meta.commit_index {
                     //RI, 5
@@ -1170,7 +1550,13 @@ impl Service for RaftService {
                 );
             }
             if vote_granted {
+                // The vote must survive restarts before the candidate knows
                 meta.vote_for = Some(candidate_id);
+                if let Err(e) = save_hard_state(&meta).await {
+                    error!("Cannot persist vote, not granted, {}", e);
+                    meta.vote_for = vote_for;
+                    vote_granted = false;
+                }
             }
             debug!(
                 "{} VOTE FOR: {}, granted: {}",
@@ -1187,20 +1573,67 @@ impl Service for RaftService {
         leader_id: u64,
         last_included_index: u64,
         last_included_term: u64,
+        offset: u64,
         data: Vec<u8>,
-    ) -> BoxFuture<u64> {
+        done: bool,
+        checksum: u32,
+    ) -> BoxFuture<(u64, InstallSnapshotResult)> {
         async move {
             let mut meta = self.write_meta().await;
-            let term_ok = self.check_term(&mut meta, term, leader_id);
-            if term_ok {
-                check_commit(&mut meta).await;
+            let term_ok = self.check_term(&mut meta, term, leader_id).await;
+            if !term_ok {
+                return (meta.term, InstallSnapshotResult::TermOut);
             }
-            meta.state_machine.write().await.recover(data);
-            meta.term = last_included_term;
-            meta.commit_index = last_included_index;
-            meta.last_applied = last_included_index;
             self.reset_last_checked(&mut meta);
-            meta.term
+            if last_included_index <= meta.last_applied {
+                return (meta.term, InstallSnapshotResult::Installed);
+            }
+            let mut receiving = self.receiving_snapshot.lock().await;
+            let same_snapshot = match &*receiving {
+                Some(r) => r.index == last_included_index && r.term == last_included_term,
+                None => false,
+            };
+            if !same_snapshot || offset == 0 {
+                if offset > 0 {
+                    // Lost what came before, start over
+                    *receiving = None;
+                    return (meta.term, InstallSnapshotResult::Received(0));
+                }
+                *receiving = Some(ReceivingSnapshot {
+                    index: last_included_index,
+                    term: last_included_term,
+                    data: vec![],
+                });
+            }
+            let snapshot = receiving.as_mut().unwrap();
+            if offset == snapshot.data.len() as u64 {
+                snapshot.data.extend_from_slice(&data);
+                if done {
+                    let data = receiving.take().unwrap().data;
+                    drop(receiving);
+                    if crc32fast::hash(&data) != checksum
+                        || crate::utils::serde::deserialize::<SnapshotDataItems>(&data).is_none()
+                    {
+                        error!("Received invalid snapshot at {}", last_included_index);
+                        return (meta.term, InstallSnapshotResult::Invalid);
+                    }
+                    let installed = self
+                        .install_snapshot_data(
+                            &mut meta,
+                            last_included_index,
+                            last_included_term,
+                            data,
+                        )
+                        .await;
+                    if let Err(e) = installed {
+                        error!("Cannot persist snapshot at {}, {}", last_included_index, e);
+                        return (meta.term, InstallSnapshotResult::StorageFailure);
+                    }
+                    return (meta.term, InstallSnapshotResult::Installed);
+                }
+            }
+            let received = snapshot.data.len() as u64;
+            (meta.term, InstallSnapshotResult::Received(received))
         }
         .boxed()
     }
@@ -1221,7 +1654,13 @@ impl Service for RaftService {
                     ClientCmdResponse::NotLeader(meta.leader_id)
                 };
             }
-            let (new_log_id, new_log_term) = self.leader_append_log(&meta, &mut entry).await;
+            let (new_log_id, new_log_term) = match self.leader_append_log(&meta, &mut entry).await {
+                Ok(appended) => appended,
+                Err(e) => {
+                    error!("Cannot persist command log, {}", e);
+                    return ClientCmdResponse::NotCommitted;
+                }
+            };
             let data = match entry.sm_id {
                 // special treats for membership changes
                 CONFIG_SM_ID => Some(
@@ -1322,11 +1761,339 @@ mod test {
             storage: Storage::default(),
             address: String::from("127.0.0.1:2000"),
             service_id: DEFAULT_SERVICE_ID,
+            ..Default::default()
         })
         .await;
         assert!(success);
     }
 
+    #[test]
+    fn snapshot_chunks() {
+        use crate::raft::state_machine::master::SnapshotDataItems;
+        use crate::raft::{InstallSnapshotResult, Service};
+        let service = RaftService::new(Options {
+            storage: Storage::default(),
+            address: String::from("127.0.0.1:2010"),
+            service_id: DEFAULT_SERVICE_ID,
+            ..Default::default()
+        });
+        let data = crate::utils::serde::serialize(&SnapshotDataItems::new());
+        let checksum = crc32fast::hash(&data);
+        let (head, tail) = data.split_at(1);
+        futures::executor::block_on(async {
+            let install = |offset: u64, chunk: &[u8], done: bool| {
+                service.install_snapshot(1, 42, 5, 1, offset, chunk.to_vec(), done, checksum)
+            };
+            // Corrupted in transfer, or not a snapshot at all
+            match install(0, &[data[0] ^ 1], false).await {
+                (1, InstallSnapshotResult::Received(1)) => {}
+                res => panic!("Unexpected {:?}", res),
+            }
+            match install(1, tail, true).await {
+                (1, InstallSnapshotResult::Invalid) => {}
+                res => panic!("Unexpected {:?}", res),
+            }
+            let garbage = vec![0xff, 0x00];
+            match service
+                .install_snapshot(
+                    1,
+                    42,
+                    5,
+                    1,
+                    0,
+                    garbage.clone(),
+                    true,
+                    crc32fast::hash(&garbage),
+                )
+                .await
+            {
+                (1, InstallSnapshotResult::Invalid) => {}
+                res => panic!("Unexpected {:?}", res),
+            }
+            assert_eq!(service.meta.read().await.last_applied, 0);
+            let received = |res: (u64, InstallSnapshotResult)| match res.1 {
+                InstallSnapshotResult::Received(received) => received,
+                res => panic!("Unexpected {:?}", res),
+            };
+            assert_eq!(received(install(0, head, false).await), 1);
+            // Resent or out of order chunks tell the leader where to go on
+            assert_eq!(received(install(0, head, false).await), 1);
+            assert_eq!(received(install(3, tail, true).await), 1);
+            match install(1, tail, true).await {
+                (1, InstallSnapshotResult::Installed) => {}
+                res => panic!("Unexpected {:?}", res),
+            }
+            let meta = service.meta.read().await;
+            assert_eq!((meta.last_applied, meta.commit_index), (5, 5));
+            let logs = meta.logs.read().await;
+            assert_eq!(logs.keys().cloned().collect::<Vec<_>>(), vec![5]);
+            drop(logs);
+            drop(meta);
+            match install(0, &data, true).await {
+                (_, InstallSnapshotResult::Installed) => {}
+                res => panic!("Unexpected {:?}", res),
+            }
+            match service
+                .install_snapshot(0, 42, 6, 1, 0, data.clone(), true, checksum)
+                .await
+            {
+                (1, InstallSnapshotResult::TermOut) => {}
+                res => panic!("Unexpected {:?}", res),
+            }
+        });
+    }
+
+    #[test]
+    fn failing_storage() {
+        use crate::raft::state_machine::master::SnapshotDataItems;
+        use crate::raft::storage::{HardState, RaftStorage, RecoveredState};
+        use crate::raft::{
+            AppendEntriesResult, InstallSnapshotResult, LogEntry, Service, SnapshotEntity,
+        };
+        use std::io;
+        use std::sync::Arc;
+        // Recovers two logs in term 1 and cannot save the hard state or snapshots. Appends and
+        // truncations fail when told to, and are recorded otherwise.
+        #[derive(Default)]
+        struct Recorded {
+            fail_append: bool,
+            fail_truncate: bool,
+            appended: Vec<u64>,
+            truncated: Vec<u64>,
+        }
+        struct TestStorage {
+            fail_recover: bool,
+            recorded: Arc<parking_lot::Mutex<Recorded>>,
+        }
+        fn failure() -> io::Error {
+            io::Error::new(io::ErrorKind::Other, "test storage")
+        }
+        fn log_entry(id: u64) -> LogEntry {
+            LogEntry {
+                id,
+                term: 1,
+                sm_id: 0,
+                fn_id: 0,
+                data: vec![],
+            }
+        }
+        impl RaftStorage for TestStorage {
+            fn recover(&mut self) -> io::Result<RecoveredState> {
+                if self.fail_recover {
+                    return Err(failure());
+                }
+                let mut state = RecoveredState::default();
+                state.hard_state.term = 1;
+                for id in 1..=2 {
+                    state.logs.insert(id, log_entry(id));
+                }
+                Ok(state)
+            }
+            fn append(&mut self, entries: &[LogEntry]) -> io::Result<u64> {
+                let mut recorded = self.recorded.lock();
+                if recorded.fail_append {
+                    return Err(failure());
+                }
+                recorded
+                    .appended
+                    .extend(entries.iter().map(|entry| entry.id));
+                Ok(0)
+            }
+            fn truncate_suffix(&mut self, id: u64) -> io::Result<()> {
+                let mut recorded = self.recorded.lock();
+                if recorded.fail_truncate {
+                    return Err(failure());
+                }
+                recorded.truncated.push(id);
+                Ok(())
+            }
+            fn compact_prefix(&mut self, _id: u64) -> io::Result<()> {
+                Ok(())
+            }
+            fn save_hard_state(&mut self, _state: &HardState) -> io::Result<()> {
+                Err(failure())
+            }
+            fn save_snapshot(&mut self, _snapshot: &SnapshotEntity) -> io::Result<()> {
+                Err(failure())
+            }
+            fn load_snapshot(&self) -> io::Result<Option<SnapshotEntity>> {
+                Ok(None)
+            }
+        }
+        let recorded = Arc::new(parking_lot::Mutex::new(Recorded::default()));
+        let options = |fail_recover| {
+            let recorded = recorded.clone();
+            Options {
+                storage: Storage::CUSTOM(Arc::new(move || {
+                    Box::new(TestStorage {
+                        fail_recover,
+                        recorded: recorded.clone(),
+                    })
+                })),
+                address: String::from("127.0.0.1:2011"),
+                service_id: DEFAULT_SERVICE_ID,
+                ..Default::default()
+            }
+        };
+        assert!(RaftService::try_new(options(true)).is_err());
+        let service = RaftService::try_new(options(false)).unwrap();
+        let log_ids = || {
+            futures::executor::block_on(async {
+                let meta = service.meta.read().await;
+                let logs = meta.logs.read().await;
+                logs.keys().cloned().collect::<Vec<_>>()
+            })
+        };
+        assert_eq!(log_ids(), vec![1, 2]);
+        futures::executor::block_on(async {
+            assert_eq!(service.meta.read().await.term, 1);
+            // A term that cannot be persisted is not taken
+            match service.append_entries(2, 42, 2, 2, None, 0).await {
+                (1, AppendEntriesResult::TermOut(_)) => {}
+                res => panic!("Unexpected {:?}", res),
+            }
+            // Entry 2 conflicts with the leader, but cannot be dropped from storage
+            recorded.lock().fail_truncate = true;
+            match service.append_entries(1, 42, 2, 2, None, 0).await {
+                (1, AppendEntriesResult::StorageFailure) => {}
+                res => panic!("Unexpected {:?}", res),
+            }
+        });
+        assert_eq!(log_ids(), vec![1, 2]);
+        assert!(recorded.lock().truncated.is_empty());
+        futures::executor::block_on(async {
+            recorded.lock().fail_truncate = false;
+            match service.append_entries(1, 42, 2, 2, None, 0).await {
+                (1, AppendEntriesResult::LogMismatch) => {}
+                res => panic!("Unexpected {:?}", res),
+            }
+        });
+        assert_eq!(log_ids(), vec![1]);
+        assert_eq!(recorded.lock().truncated, vec![2]);
+        // Entries that cannot be saved are not acknowledged, nor kept
+        let entries = Some(vec![log_entry(2)]);
+        futures::executor::block_on(async {
+            recorded.lock().fail_append = true;
+            match service
+                .append_entries(1, 42, 1, 1, entries.clone(), 0)
+                .await
+            {
+                (1, AppendEntriesResult::StorageFailure) => {}
+                res => panic!("Unexpected {:?}", res),
+            }
+        });
+        assert_eq!(log_ids(), vec![1]);
+        assert!(recorded.lock().appended.is_empty());
+        futures::executor::block_on(async {
+            recorded.lock().fail_append = false;
+            match service.append_entries(1, 42, 1, 1, entries, 0).await {
+                (1, AppendEntriesResult::Ok) => {}
+                res => panic!("Unexpected {:?}", res),
+            }
+        });
+        assert_eq!(log_ids(), vec![1, 2]);
+        assert_eq!(recorded.lock().appended, vec![2]);
+        // A snapshot that cannot be saved is not acknowledged, nor installed
+        let data = crate::utils::serde::serialize(&SnapshotDataItems::new());
+        let checksum = crc32fast::hash(&data);
+        futures::executor::block_on(async {
+            match service
+                .install_snapshot(1, 42, 5, 1, 0, data, true, checksum)
+                .await
+            {
+                (1, InstallSnapshotResult::StorageFailure) => {}
+                res => panic!("Unexpected {:?}", res),
+            }
+            let meta = service.meta.read().await;
+            assert_eq!((meta.last_applied, meta.commit_index), (0, 0));
+        });
+        assert_eq!(log_ids(), vec![1, 2]);
+        // Recovered entries were never committed, bootstrapping does not apply them
+        futures::executor::block_on(async {
+            service.bootstrap().await;
+            let meta = service.meta.read().await;
+            assert_eq!((meta.last_applied, meta.commit_index), (0, 0));
+            assert_eq!(meta.leader_id, service.id);
+        });
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn simulated_network() {
+        let _ = env_logger::try_init();
+        let network = crate::tcp::sim::SimNetwork::new(5);
+        let (s1_addr, s2_addr) = (String::from("raft-sim-1"), String::from("raft-sim-2"));
+        let options = |addr: &String| Options {
+            address: addr.clone(),
+            transport: Some(network.node(addr)),
+            ..Default::default()
+        };
+        let (started, service1, _server1) = RaftService::new_server(options(&s1_addr)).await;
+        assert!(started);
+        service1.bootstrap().await;
+        let (started, service2, _server2) = RaftService::new_server(options(&s2_addr)).await;
+        assert!(started);
+        // Simulated nodes never take the shortcut, all of it goes through the network
+        assert!(service2.join(&vec![s1_addr.clone()]).await.unwrap());
+        assert_eq!(service1.num_members().await, 2);
+        assert_eq!(service2.num_members().await, 2);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn simulated_partition() {
+        use crate::tcp::sim::{LinkRules, SimNetwork};
+        use std::time::Duration;
+        use tokio::time::sleep;
+        let _ = env_logger::try_init();
+        // Raft keeps its own runtime and timers, only the network is seeded here
+        let network = SimNetwork::new(6);
+        let addrs: Vec<_> = (1..=4).map(|i| format!("raft-partition-{}", i)).collect();
+        let mut services = vec![];
+        let mut servers = vec![];
+        for addr in &addrs {
+            let (started, service, server) = RaftService::new_server(Options {
+                address: addr.clone(),
+                transport: Some(network.node(addr)),
+                ..Default::default()
+            })
+            .await;
+            assert!(started);
+            services.push(service);
+            servers.push(server);
+        }
+        let seeds = vec![addrs[0].clone()];
+        services[0].bootstrap().await;
+        assert!(services[1].join(&seeds).await.unwrap());
+        assert!(services[2].join(&seeds).await.unwrap());
+        // Cut off from the others, the third member misses what the rest commits
+        network.isolate(&addrs[2]);
+        assert!(services[3].join(&seeds).await.unwrap());
+        assert_eq!(services[0].num_members().await, 4);
+        assert_eq!(services[1].num_members().await, 4);
+        assert_eq!(services[2].num_members().await, 3);
+        // and catches up once healed, even over a lossy link
+        network.heal_all();
+        network.set_link(
+            &addrs[0],
+            &addrs[2],
+            LinkRules {
+                drop_rate: 0.2,
+                ..LinkRules::default()
+            },
+        );
+        for _ in 0..50 {
+            if services[2].num_members().await == 4 {
+                break;
+            }
+            sleep(Duration::from_millis(200)).await;
+        }
+        assert_eq!(services[2].num_members().await, 4);
+        assert_eq!(services[2].leader_id().await, services[0].id);
+        assert_eq!(
+            services[2].last_log_id().await,
+            services[0].last_log_id().await
+        );
+    }
+
     #[tokio::test(flavor = "multi_thread")]
     async fn server_membership() {
         let _ = env_logger::try_init();
@@ -1337,6 +2104,7 @@ mod test {
             storage: Storage::default(),
             address: s1_addr.clone(),
             service_id: DEFAULT_SERVICE_ID,
+            ..Default::default()
         });
         info!("Starting server 1");
         let server1 = Server::new(&s1_addr);
@@ -1359,6 +2127,7 @@ mod test {
             storage: Storage::default(),
             address: s2_addr.clone(),
             service_id: DEFAULT_SERVICE_ID,
+            ..Default::default()
         });
         server2
             .register_service(DEFAULT_SERVICE_ID, &service2)
@@ -1384,6 +2153,7 @@ mod test {
             storage: Storage::default(),
             address: s3_addr.clone(),
             service_id: DEFAULT_SERVICE_ID,
+            ..Default::default()
         });
         let server3 = Server::new(&s3_addr);
         Server::listen_and_resume(&server3).await;
@@ -1438,26 +2208,31 @@ mod test {
             storage: Storage::default(),
             address: s1_addr.clone(),
             service_id: DEFAULT_SERVICE_ID,
+            ..Default::default()
         });
         let service2 = RaftService::new(Options {
             storage: Storage::default(),
             address: s2_addr.clone(),
             service_id: DEFAULT_SERVICE_ID,
+            ..Default::default()
         });
         let service3 = RaftService::new(Options {
             storage: Storage::default(),
             address: s3_addr.clone(),
             service_id: DEFAULT_SERVICE_ID,
+            ..Default::default()
         });
         let service4 = RaftService::new(Options {
             storage: Storage::default(),
             address: s4_addr.clone(),
             service_id: DEFAULT_SERVICE_ID,
+            ..Default::default()
         });
         let service5 = RaftService::new(Options {
             storage: Storage::default(),
             address: s5_addr.clone(),
             service_id: DEFAULT_SERVICE_ID,
+            ..Default::default()
         });
         let server_list = vec![
             s1_addr.clone(),
@@ -1591,13 +2366,32 @@ mod test {
                 15
             }
             fn snapshot(&self) -> Option<Vec<u8>> {
-                None
+                Some(crate::utils::serde::serialize(&self.shots))
             }
-            fn recover(&mut self, _data: Vec<u8>) -> BoxFuture<()> {
-                future::ready(()).boxed()
+            fn recover(&mut self, data: Vec<u8>) -> BoxFuture<()> {
+                async move {
+                    self.shots = crate::utils::serde::deserialize(&data).unwrap();
+                }
+                .boxed()
             }
         }
 
+        #[test]
+        fn recover_on_register() {
+            use crate::raft::state_machine::master::{MasterStateMachine, SnapshotDataItems};
+            let mut master = MasterStateMachine::new(DEFAULT_SERVICE_ID);
+            assert!(master.stash_snapshot(vec![0xff]).is_err());
+            let items: SnapshotDataItems = vec![(15, crate::utils::serde::serialize(&4))];
+            master
+                .stash_snapshot(crate::utils::serde::serialize(&items))
+                .unwrap();
+            futures::executor::block_on(master.register(Box::new(SM { shots: 10 })));
+            let snapshot: SnapshotDataItems =
+                crate::utils::serde::deserialize(&master.snapshot().unwrap()).unwrap();
+            let shots = snapshot.iter().find(|(id, _)| *id == 15).unwrap();
+            assert_eq!(crate::utils::serde::deserialize::<i32>(&shots.1), Some(4));
+        }
+
         #[tokio::test(flavor = "multi_thread")]
         async fn query_and_command() {
             let _ = env_logger::try_init();
@@ -1607,6 +2401,7 @@ mod test {
                 storage: Storage::default(),
                 address: addr.clone(),
                 service_id: DEFAULT_SERVICE_ID,
+                ..Default::default()
             });
             let sm = SM { shots: 10 };
             let server = Server::new(&addr);
@@ -1658,6 +2453,7 @@ mod test {
                             storage: Storage::default(),
                             address: addr.clone(),
                             service_id: DEFAULT_SERVICE_ID,
+                            ..Default::default()
                         });
                         let sm = SM { shots: 10 };
                         let server = Server::new(&addr);
@@ -68,6 +68,7 @@ mod test {
             storage: Storage::default(),
             address: addr.clone(),
             service_id: DEFAULT_SERVICE_ID,
+            ..Default::default()
         });
         let server = Server::new(&addr);
         let dummy_sm = Trigger {
@@ -23,6 +23,8 @@ pub struct Configures {
     // keep it in arc lock for reference in callback server.rs
     pub subscriptions: Arc<RwLock<Subscriptions>>,
     service_id: u64,
+    // Members are connected with clients from this pool
+    pool: Arc<rpc::ClientPool>,
 }
 
 pub type MemberConfigSnapshot = HashSet<String>;
@@ -48,7 +50,7 @@ impl StateMachineCmds for Configures {
             let addr = address.clone();
             let id = hash_str(&addr);
             if !self.members.contains_key(&id) {
-                match rpc::DEFAULT_CLIENT_POOL.get(&address).await {
+                match self.pool.get(&address).await {
                     Ok(client) => {
                         self.members.insert(
                             id,
@@ -118,10 +120,14 @@ impl StateMachineCtl for Configures {
 
 impl Configures {
     pub fn new(service_id: u64) -> Configures {
+        Self::new_with_pool(service_id, &rpc::DEFAULT_CLIENT_POOL)
+    }
+    pub fn new_with_pool(service_id: u64, pool: &Arc<rpc::ClientPool>) -> Configures {
         Configures {
             members: HashMap::new(),
             service_id,
             subscriptions: Arc::new(RwLock::new(Subscriptions::new())),
+            pool: pool.clone(),
         }
     }
     async fn recover_members(&mut self, snapshot: MemberConfigSnapshot) {
@@ -6,6 +6,7 @@ use std::error::Error;
 use std::fmt;
 use std::fmt::Display;
 use std::fmt::Formatter;
+use std::io;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum ExecError {
@@ -53,16 +54,30 @@ impl StateMachineCtl for MasterStateMachine {
                 sms.push((*sm_id, snapshot));
             }
         }
+        // Recovered for state machines not registered yet
+        for (sm_id, snapshot) in self.snapshots.iter() {
+            if *sm_id != self.configs.id() {
+                sms.push((*sm_id, snapshot.clone()));
+            }
+        }
         sms.push((self.configs.id(), self.configs.snapshot().unwrap()));
         let data = crate::utils::serde::serialize(&sms);
         Some(data)
     }
     fn recover(&mut self, data: Vec<u8>) -> BoxFuture<()> {
-        let sms: SnapshotDataItems = crate::utils::serde::deserialize(data.as_slice()).unwrap();
-        for (sm_id, snapshot) in sms {
-            self.snapshots.insert(sm_id, snapshot);
+        async move {
+            let sms: SnapshotDataItems = crate::utils::serde::deserialize(data.as_slice()).unwrap();
+            for (sm_id, snapshot) in sms {
+                if sm_id == self.configs.id() {
+                    self.configs.recover(snapshot).await;
+                } else if let Some(sm) = self.subs.get_mut(&sm_id) {
+                    sm.recover(snapshot).await;
+                } else {
+                    self.snapshots.insert(sm_id, snapshot);
+                }
+            }
         }
-        future::ready(()).boxed()
+        .boxed()
     }
 }
 
@@ -76,15 +91,44 @@ fn parse_output(r: Option<Vec<u8>>) -> ExecResult {
 
 impl MasterStateMachine {
     pub fn new(service_id: u64) -> MasterStateMachine {
-        let msm = MasterStateMachine {
+        Self::new_with_pool(service_id, &crate::rpc::DEFAULT_CLIENT_POOL)
+    }
+
+    // Raft members are connected with clients from `pool`
+    pub fn new_with_pool(
+        service_id: u64,
+        pool: &Arc<crate::rpc::ClientPool>,
+    ) -> MasterStateMachine {
+        MasterStateMachine {
             subs: HashMap::new(),
             snapshots: HashMap::new(),
-            configs: Configures::new(service_id),
-        };
-        msm
+            configs: Configures::new_with_pool(service_id, pool),
+        }
+    }
+
+    // Keeps the snapshot for state machines to recover from when registered, configurations
+    // recover in `recover_configs` once members can be connected
+    pub fn stash_snapshot(&mut self, data: Vec<u8>) -> io::Result<()> {
+        let sms: SnapshotDataItems =
+            crate::utils::serde::deserialize(data.as_slice()).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "cannot decode state machine snapshot",
+                )
+            })?;
+        for (sm_id, snapshot) in sms {
+            self.snapshots.insert(sm_id, snapshot);
+        }
+        Ok(())
+    }
+
+    pub async fn recover_configs(&mut self) {
+        if let Some(snapshot) = self.snapshots.remove(&self.configs.id()) {
+            self.configs.recover(snapshot).await;
+        }
     }
 
-    pub fn register(&mut self, mut smc: SubStateMachine) -> RegisterResult {
+    pub async fn register(&mut self, mut smc: SubStateMachine) -> RegisterResult {
         let id = smc.id();
         if id < 2 {
             return RegisterResult::RESERVED;
@@ -93,7 +137,7 @@ impl MasterStateMachine {
             return RegisterResult::EXISTED;
         };
         if let Some(snapshot) = self.snapshots.remove(&id) {
-            smc.recover(snapshot);
+            smc.recover(snapshot).await;
         }
         self.subs.insert(id, smc);
         RegisterResult::OK
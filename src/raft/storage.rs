@@ -0,0 +1,280 @@
+// What raft must not lose across restarts goes through `RaftStorage`, for other engines
+// than the built in ones to be plugged in with `Storage::CUSTOM`
+
+use crate::raft::disk::CompactionPolicy;
+use crate::raft::state_machine::StateMachineCtl;
+use crate::raft::{LogEntry, LogsMap, RaftMeta, SnapshotEntity};
+use async_std::sync::*;
+use parking_lot::Mutex as SyncMutex;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::ops::Bound::*;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// What a node must not forget across restarts to keep its votes, written before
+// it votes or moves to another term
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct HardState {
+    pub term: u64,
+    pub vote_for: Option<u64>,
+    pub commit_index: u64,
+}
+
+// Everything saved before the restart
+#[derive(Default)]
+pub struct RecoveredState {
+    pub hard_state: HardState,
+    pub snapshot: Option<SnapshotEntity>,
+    // Entries after the snapshot, the one it was taken at may lead them
+    pub logs: LogsMap,
+}
+
+pub trait RaftStorage: Send {
+    // Called once, before anything else
+    fn recover(&mut self) -> io::Result<RecoveredState>;
+    // Saves entries following the last saved one, durable once it returns. Returns the
+    // bytes they took.
+    fn append(&mut self, entries: &[LogEntry]) -> io::Result<u64>;
+    // Drops the entries from `id` on, they conflict with the leader
+    fn truncate_suffix(&mut self, id: u64) -> io::Result<()>;
+    // May drop the entries before `id`, they are in the saved snapshot
+    fn compact_prefix(&mut self, id: u64) -> io::Result<()>;
+    fn save_hard_state(&mut self, state: &HardState) -> io::Result<()>;
+    fn save_snapshot(&mut self, snapshot: &SnapshotEntity) -> io::Result<()>;
+    fn load_snapshot(&self) -> io::Result<Option<SnapshotEntity>>;
+    // Nodes that forget their logs probe for a cluster to join on conservative bootstrap
+    fn persistent(&self) -> bool {
+        true
+    }
+    // When to snapshot the state machines, never by default
+    fn compaction(&self) -> CompactionPolicy {
+        CompactionPolicy::default()
+    }
+    // Whether to drop the logs before a snapshot once it is taken
+    fn trim_logs(&self) -> bool {
+        false
+    }
+}
+
+// Keeps nothing across restarts, the logs in memory are all there is
+#[derive(Default)]
+pub struct MemoryStorage {
+    snapshot: Option<SnapshotEntity>,
+}
+
+impl RaftStorage for MemoryStorage {
+    fn recover(&mut self) -> io::Result<RecoveredState> {
+        Ok(RecoveredState::default())
+    }
+    fn append(&mut self, _entries: &[LogEntry]) -> io::Result<u64> {
+        Ok(0)
+    }
+    fn truncate_suffix(&mut self, _id: u64) -> io::Result<()> {
+        Ok(())
+    }
+    fn compact_prefix(&mut self, _id: u64) -> io::Result<()> {
+        Ok(())
+    }
+    fn save_hard_state(&mut self, _state: &HardState) -> io::Result<()> {
+        Ok(())
+    }
+    fn save_snapshot(&mut self, snapshot: &SnapshotEntity) -> io::Result<()> {
+        self.snapshot = Some(snapshot.clone());
+        Ok(())
+    }
+    fn load_snapshot(&self) -> io::Result<Option<SnapshotEntity>> {
+        Ok(self.snapshot.clone())
+    }
+    fn persistent(&self) -> bool {
+        false
+    }
+}
+
+// Keeps the storage in step with the logs in memory
+pub struct StorageEntity {
+    engine: Box<dyn RaftStorage>,
+    // Id of the last entry saved, shared with `RaftMeta` to be read without the lock
+    last_saved_id: Arc<AtomicU64>,
+    // Last entry in the snapshot, it stays in the logs for the entries after it to follow
+    pub snapshot_index: u64,
+    log_bytes: u64,
+    compaction: CompactionPolicy,
+    trim_logs: bool,
+}
+
+impl StorageEntity {
+    pub fn recover(mut engine: Box<dyn RaftStorage>) -> io::Result<(Self, RecoveredState)> {
+        let state = engine.recover()?;
+        let snapshot_index = state.snapshot.as_ref().map_or(0, |s| s.last_applied);
+        let entity = StorageEntity {
+            last_saved_id: Arc::new(AtomicU64::new(
+                state
+                    .logs
+                    .keys()
+                    .next_back()
+                    .cloned()
+                    .unwrap_or(snapshot_index),
+            )),
+            snapshot_index,
+            log_bytes: 0,
+            compaction: engine.compaction(),
+            trim_logs: engine.trim_logs(),
+            engine,
+        };
+        Ok((entity, state))
+    }
+
+    pub fn persistent(&self) -> bool {
+        self.engine.persistent()
+    }
+
+    pub fn last_saved_id(&self) -> u64 {
+        self.last_saved_id.load(Ordering::Relaxed)
+    }
+
+    // Follows `last_saved_id` without the lock, which fsyncs hold
+    pub fn shared_last_saved_id(&self) -> Arc<AtomicU64> {
+        self.last_saved_id.clone()
+    }
+
+    pub fn save_hard_state(&mut self, state: &HardState) -> io::Result<()> {
+        self.engine.save_hard_state(state)
+    }
+
+    pub fn load_snapshot(&self) -> io::Result<Option<SnapshotEntity>> {
+        self.engine.load_snapshot()
+    }
+
+    fn append_logs(&mut self, new_logs: Vec<LogEntry>) -> io::Result<()> {
+        if let Some(last) = new_logs.last() {
+            let last_id = last.id;
+            self.log_bytes += self.engine.append(&new_logs)?;
+            debug!(
+                "Appended and persisted {} logs, was {}, appended up to {}",
+                new_logs.len(),
+                self.last_saved_id(),
+                last_id
+            );
+            self.last_saved_id.store(last_id, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    pub fn truncate_logs(&mut self, id: u64) -> io::Result<()> {
+        self.engine.truncate_suffix(id)?;
+        let last_saved_id = self.last_saved_id().min(id.saturating_sub(1));
+        self.last_saved_id.store(last_saved_id, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn should_compact(&self, last_applied: u64) -> bool {
+        if last_applied <= self.snapshot_index {
+            return false;
+        }
+        let entries = self
+            .compaction
+            .log_entries
+            .map_or(false, |max| last_applied - self.snapshot_index >= max);
+        let bytes = self
+            .compaction
+            .log_bytes
+            .map_or(false, |max| self.log_bytes >= max);
+        entries || bytes
+    }
+
+    // Saves the snapshot and trims the saved logs before it, returns whether the logs in
+    // memory should be trimmed too
+    fn compact(&mut self, snapshot: &SnapshotEntity) -> io::Result<bool> {
+        let index = snapshot.last_applied;
+        self.engine.save_snapshot(snapshot)?;
+        self.snapshot_index = index;
+        self.log_bytes = 0;
+        if self.trim_logs {
+            self.engine.compact_prefix(index)?;
+        }
+        Ok(self.trim_logs)
+    }
+
+    // Saves a snapshot from the leader, `logs` are what is left of the logs with it
+    pub fn install_snapshot(
+        &mut self,
+        snapshot: &SnapshotEntity,
+        logs: Vec<LogEntry>,
+    ) -> io::Result<()> {
+        self.engine.save_snapshot(snapshot)?;
+        self.snapshot_index = snapshot.last_applied;
+        self.log_bytes = 0;
+        self.truncate_logs(0)?;
+        self.append_logs(logs)
+    }
+}
+
+// Runs storage IO on the blocking threads of the runtime when there is one, fsyncs would
+// stall every task on the worker otherwise
+pub async fn blocking<T, F>(storage: &Arc<SyncMutex<StorageEntity>>, f: F) -> io::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&mut StorageEntity) -> io::Result<T> + Send + 'static,
+{
+    let storage = storage.clone();
+    match tokio::runtime::Handle::try_current() {
+        Ok(rt) => rt
+            .spawn_blocking(move || f(&mut storage.lock()))
+            .await
+            .unwrap_or_else(|e| Err(io::Error::new(io::ErrorKind::Other, e))),
+        Err(_) => f(&mut storage.lock()),
+    }
+}
+
+// Logs in memory not saved yet
+fn unsaved_logs(logs: &LogsMap, last_saved_id: u64) -> Vec<LogEntry> {
+    logs.range((Excluded(last_saved_id), Unbounded))
+        .map(|(_, log)| log.clone())
+        .collect()
+}
+
+// Saves new logs and compacts them once the policy says so. Logs that could not be saved
+// are dropped from memory, they must not be acknowledged or committed. The lock of the
+// storage is only taken on blocking threads, it waits for fsyncs.
+pub async fn post_processing<'a>(
+    meta: &RaftMeta,
+    mut logs: RwLockWriteGuard<'a, LogsMap>,
+) -> io::Result<()> {
+    let index = meta.last_applied;
+    let new_logs = unsaved_logs(&logs, meta.last_saved_id.load(Ordering::Relaxed));
+    let appended = blocking(&meta.storage, move |storage| {
+        storage.append_logs(new_logs)?;
+        Ok(storage.should_compact(index))
+    })
+    .await;
+    let should_compact = match appended {
+        Ok(should_compact) => should_compact,
+        Err(e) => {
+            let saved = meta.last_saved_id.load(Ordering::Relaxed);
+            logs.split_off(&(saved + 1));
+            return Err(e);
+        }
+    };
+    if !should_compact {
+        return Ok(());
+    }
+    let term = match logs.get(&index) {
+        Some(entry) => entry.term,
+        None => return Ok(()),
+    };
+    let data = match meta.state_machine.read().await.snapshot() {
+        Some(data) => data,
+        None => return Ok(()),
+    };
+    let snapshot = SnapshotEntity {
+        term,
+        commit_index: meta.commit_index,
+        last_applied: index,
+        snapshot: data,
+    };
+    if blocking(&meta.storage, move |storage| storage.compact(&snapshot)).await? {
+        *logs = logs.split_off(&index);
+    }
+    debug!("Compacted raft logs at {}, {} logs left", index, logs.len());
+    Ok(())
+}
@@ -0,0 +1,193 @@
+// Shared-secret authentication. Clients sign every request with a key known to the whole
+// cluster, servers refuse requests to protected services without a valid signature.
+// Signatures cover the service, the function and arguments, and the metadata, so the
+// signer has to be the last client interceptor to add any.
+//
+// Calls through the shortcut never leave the process and are not signed. Servers do not
+// verify them either, anything in the process can call its services.
+
+use super::context::{ClientInterceptor, RequestContext, ServerInterceptor};
+use super::{RPCError, RPCRequestError};
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+use std::collections::HashSet;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const SIGNATURE_KEY: &'static str = "auth-hmac";
+pub const TIMESTAMP_KEY: &'static str = "auth-ts";
+pub const DEFAULT_MAX_CLOCK_SKEW: Duration = Duration::from_secs(30);
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+// Metadata entries but the signature go in with their lengths, for entries not to be
+// moved between keys and values. The timestamp is one of them, so captured requests cannot
+// be replayed for long.
+fn mac(key: &[u8], ctx: &RequestContext, payload: &[u8]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(&ctx.service_id.to_le_bytes());
+    mac.update(&ctx.func_id.to_le_bytes());
+    for (key, value) in ctx.metadata.iter() {
+        if key == SIGNATURE_KEY {
+            continue;
+        }
+        mac.update(&(key.len() as u64).to_le_bytes());
+        mac.update(key.as_bytes());
+        mac.update(&(value.len() as u64).to_le_bytes());
+        mac.update(value);
+    }
+    mac.update(payload);
+    mac
+}
+
+// Client side, add to `RPCClient` or `ClientPool` with `add_interceptor`
+pub struct HmacSigner {
+    key: Vec<u8>,
+}
+
+impl HmacSigner {
+    pub fn new(key: &[u8]) -> Self {
+        HmacSigner { key: key.to_vec() }
+    }
+}
+
+impl ClientInterceptor for HmacSigner {
+    fn before(&self, ctx: &mut RequestContext, payload: Option<&[u8]>) -> Result<(), RPCError> {
+        if let Some(payload) = payload {
+            ctx.metadata
+                .insert(TIMESTAMP_KEY, now_millis().to_le_bytes().to_vec());
+            let signature = mac(&self.key, ctx, payload).finalize().into_bytes();
+            ctx.metadata.insert(SIGNATURE_KEY, signature.to_vec());
+        }
+        Ok(())
+    }
+}
+
+// Server side, add to `rpc::Server` with `add_interceptor`. Every service requires a
+// signature unless made public.
+pub struct HmacAuth {
+    key: Vec<u8>,
+    public_services: HashSet<u64>,
+    max_clock_skew: Duration,
+}
+
+impl HmacAuth {
+    pub fn new(key: &[u8]) -> Self {
+        HmacAuth {
+            key: key.to_vec(),
+            public_services: HashSet::new(),
+            max_clock_skew: DEFAULT_MAX_CLOCK_SKEW,
+        }
+    }
+
+    // Anyone can call `service_id`, e.g. for read-only cluster information
+    pub fn public(mut self, service_id: u64) -> Self {
+        self.public_services.insert(service_id);
+        self
+    }
+
+    // Signed requests older or newer than this are refused
+    pub fn max_clock_skew(mut self, skew: Duration) -> Self {
+        self.max_clock_skew = skew;
+        self
+    }
+
+    fn verify(&self, ctx: &RequestContext, payload: &[u8]) -> bool {
+        let timestamp = match ctx.metadata.get(TIMESTAMP_KEY) {
+            Some(timestamp) if timestamp.len() == 8 => {
+                let mut bytes = [0u8; 8];
+                bytes.copy_from_slice(timestamp);
+                u64::from_le_bytes(bytes)
+            }
+            _ => return false,
+        };
+        let skew = (now_millis() as i128 - timestamp as i128).abs();
+        if skew > self.max_clock_skew.as_millis() as i128 {
+            return false;
+        }
+        match ctx.metadata.get(SIGNATURE_KEY) {
+            Some(signature) => mac(&self.key, ctx, payload).verify(signature).is_ok(),
+            None => false,
+        }
+    }
+}
+
+impl ServerInterceptor for HmacAuth {
+    fn before(&self, ctx: &RequestContext, payload: Option<&[u8]>) -> Result<(), RPCRequestError> {
+        if self.public_services.contains(&ctx.service_id) {
+            return Ok(());
+        }
+        match payload {
+            // Shortcut calls come from this process and are not verified
+            None => Ok(()),
+            Some(payload) if self.verify(ctx, payload) => Ok(()),
+            Some(_) => {
+                warn!("Refused unauthenticated call to service {}", ctx.service_id);
+                Err(RPCRequestError::Unauthenticated)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rpc::context::Metadata;
+
+    fn signed(key: &[u8], service_id: u64, payload: &[u8]) -> RequestContext {
+        let mut ctx = RequestContext::new(0, service_id, 1, Metadata::new(), false);
+        HmacSigner::new(key)
+            .before(&mut ctx, Some(payload))
+            .unwrap();
+        ctx
+    }
+
+    #[test]
+    fn signatures() {
+        let auth = HmacAuth::new(b"cluster key").public(2);
+        let payload = b"function and arguments";
+        let ctx = signed(b"cluster key", 1, payload);
+        assert!(auth.before(&ctx, Some(payload)).is_ok());
+        // Tampered payload, another service or another key
+        assert!(auth.before(&ctx, Some(b"other arguments")).is_err());
+        let mut moved = ctx.clone();
+        moved.service_id = 3;
+        assert!(auth.before(&moved, Some(payload)).is_err());
+        let mut moved = ctx.clone();
+        moved.func_id = 2;
+        assert!(auth.before(&moved, Some(payload)).is_err());
+        // Metadata changed or added after signing
+        let mut tampered = ctx.clone();
+        tampered.metadata.insert("user", "admin");
+        assert!(auth.before(&tampered, Some(payload)).is_err());
+        let ctx = signed(b"wrong key", 1, payload);
+        assert!(auth.before(&ctx, Some(payload)).is_err());
+        // Public services and shortcut calls need no signature
+        let unsigned = RequestContext::new(0, 2, 1, Metadata::new(), false);
+        assert!(auth.before(&unsigned, Some(payload)).is_ok());
+        let local = RequestContext::new(0, 1, 1, Metadata::new(), true);
+        assert!(auth.before(&local, None).is_ok());
+    }
+
+    #[test]
+    fn expired_signature() {
+        let auth = HmacAuth::new(b"cluster key").max_clock_skew(Duration::from_secs(1));
+        let payload = b"payload";
+        let mut ctx = signed(b"cluster key", 1, payload);
+        let timestamp = now_millis() - 60_000;
+        ctx.metadata
+            .insert(TIMESTAMP_KEY, timestamp.to_le_bytes().to_vec());
+        let signature = mac(b"cluster key", &ctx, payload).finalize().into_bytes();
+        ctx.metadata.insert(SIGNATURE_KEY, signature.to_vec());
+        assert!(matches!(
+            auth.before(&ctx, Some(payload)),
+            Err(RPCRequestError::Unauthenticated)
+        ));
+    }
+}
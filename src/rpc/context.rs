@@ -0,0 +1,287 @@
+// Metadata carried with every request and the interceptors that can read or change it.
+// Interceptors run for calls over the network and through the shortcut alike, only the
+// serialized request is missing for the latter.
+
+use super::{RPCError, RPCRequestError};
+use crate::tcp::shortcut::Namespace;
+use bytes::{Buf, BufMut, BytesMut};
+use parking_lot::RwLock;
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::sync::Arc;
+
+fn too_large(what: String) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("{} over the limit", what),
+    )
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Metadata {
+    entries: BTreeMap<String, Vec<u8>>,
+}
+
+impl Metadata {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, key: &str) -> Option<&[u8]> {
+        self.entries.get(key).map(|value| value.as_slice())
+    }
+
+    pub fn get_str(&self, key: &str) -> Option<&str> {
+        self.get(key)
+            .and_then(|value| std::str::from_utf8(value).ok())
+    }
+
+    pub fn insert<V: Into<Vec<u8>>>(&mut self, key: &str, value: V) {
+        self.entries.insert(key.to_string(), value.into());
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<Vec<u8>> {
+        self.entries.remove(key)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Vec<u8>)> {
+        self.entries.iter()
+    }
+
+    // Entry count, then length prefixed keys and values. Fails without writing anything
+    // when the count, a key or a value is too large for its prefix.
+    pub fn encode(&self, buf: &mut BytesMut) -> io::Result<()> {
+        if self.entries.len() > u16::MAX as usize {
+            return Err(too_large(format!(
+                "{} metadata entries",
+                self.entries.len()
+            )));
+        }
+        for (key, value) in &self.entries {
+            if key.len() > u16::MAX as usize {
+                return Err(too_large(format!("Metadata key of {} bytes", key.len())));
+            }
+            if value.len() > u32::MAX as usize {
+                return Err(too_large(format!(
+                    "Metadata value of {} bytes",
+                    value.len()
+                )));
+            }
+        }
+        buf.put_u16_le(self.entries.len() as u16);
+        for (key, value) in &self.entries {
+            buf.put_u16_le(key.len() as u16);
+            buf.extend_from_slice(key.as_bytes());
+            buf.put_u32_le(value.len() as u32);
+            buf.extend_from_slice(value);
+        }
+        Ok(())
+    }
+
+    pub fn decode(buf: &mut BytesMut) -> Option<Self> {
+        let mut entries = BTreeMap::new();
+        if buf.len() < 2 {
+            return None;
+        }
+        for _ in 0..buf.get_u16_le() {
+            if buf.len() < 2 {
+                return None;
+            }
+            let key_len = buf.get_u16_le() as usize;
+            if buf.len() < key_len + 4 {
+                return None;
+            }
+            let key = String::from_utf8(buf.split_to(key_len).to_vec()).ok()?;
+            let value_len = buf.get_u32_le() as usize;
+            if buf.len() < value_len {
+                return None;
+            }
+            entries.insert(key, buf.split_to(value_len).to_vec());
+        }
+        Some(Metadata { entries })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RequestContext {
+    // Server the request is for
+    pub server_id: u64,
+    pub service_id: u64,
+    pub func_id: u64,
+    pub metadata: Metadata,
+    // Called through the shortcut, without serializing the request
+    pub local: bool,
+}
+
+impl RequestContext {
+    pub fn new(
+        server_id: u64,
+        service_id: u64,
+        func_id: u64,
+        metadata: Metadata,
+        local: bool,
+    ) -> Self {
+        RequestContext {
+            server_id,
+            service_id,
+            func_id,
+            metadata,
+            local,
+        }
+    }
+}
+
+tokio::task_local! {
+    static CONTEXT: RequestContext;
+}
+
+// Context of the request being handled, for use inside service functions
+pub fn current() -> Option<RequestContext> {
+    CONTEXT.try_with(|ctx| ctx.clone()).ok()
+}
+
+pub(crate) async fn scope<F: std::future::Future>(ctx: RequestContext, f: F) -> F::Output {
+    CONTEXT.scope(ctx, f).await
+}
+
+// Runs on the server before and after service functions. `payload` is the serialized
+// function id and arguments, `None` for shortcut calls. Those come from the process itself,
+// interceptors checking the payload, like `auth::HmacAuth`, let them through unchecked.
+pub trait ServerInterceptor: Send + Sync {
+    // Errors are returned to the caller instead of calling the function
+    fn before(&self, ctx: &RequestContext, payload: Option<&[u8]>) -> Result<(), RPCRequestError>;
+    fn after(&self, _ctx: &RequestContext, _error: Option<&RPCRequestError>) {}
+}
+
+// Runs on the client around every call, can add metadata for the server. They run in the
+// order they were added, `auth::HmacSigner` only signs metadata added before it.
+pub trait ClientInterceptor: Send + Sync {
+    fn before(&self, ctx: &mut RequestContext, payload: Option<&[u8]>) -> Result<(), RPCError>;
+    fn after(&self, _ctx: &RequestContext, _error: Option<&RPCError>) {}
+}
+
+pub type ServerInterceptors = Arc<RwLock<Vec<Arc<dyn ServerInterceptor>>>>;
+pub type ClientInterceptors = RwLock<Vec<Arc<dyn ClientInterceptor>>>;
+
+lazy_static! {
+    // Interceptors of servers in this process, for shortcut calls to their services
+    static ref LOCAL_SERVERS: RwLock<HashMap<(Namespace, u64), ServerInterceptors>> =
+        RwLock::new(HashMap::new());
+}
+
+pub(crate) fn register_local_server(
+    namespace: Namespace,
+    server_id: u64,
+    interceptors: &ServerInterceptors,
+) {
+    LOCAL_SERVERS
+        .write()
+        .insert((namespace, server_id), interceptors.clone());
+}
+
+pub(crate) fn unregister_local_server(
+    namespace: Namespace,
+    server_id: u64,
+    interceptors: &ServerInterceptors,
+) {
+    let mut servers = LOCAL_SERVERS.write();
+    // Another server may have taken the address since
+    if let Some(registered) = servers.get(&(namespace, server_id)) {
+        if Arc::ptr_eq(registered, interceptors) {
+            servers.remove(&(namespace, server_id));
+        }
+    }
+}
+
+pub(crate) fn local_server_interceptors(
+    namespace: Namespace,
+    server_id: u64,
+) -> Vec<Arc<dyn ServerInterceptor>> {
+    match LOCAL_SERVERS.read().get(&(namespace, server_id)) {
+        Some(interceptors) => interceptors.read().clone(),
+        None => vec![],
+    }
+}
+
+pub(crate) fn server_before(
+    interceptors: &[Arc<dyn ServerInterceptor>],
+    ctx: &RequestContext,
+    payload: Option<&[u8]>,
+) -> Result<(), RPCRequestError> {
+    for interceptor in interceptors {
+        interceptor.before(ctx, payload)?;
+    }
+    Ok(())
+}
+
+pub(crate) fn server_after(
+    interceptors: &[Arc<dyn ServerInterceptor>],
+    ctx: &RequestContext,
+    error: Option<&RPCRequestError>,
+) {
+    for interceptor in interceptors.iter().rev() {
+        interceptor.after(ctx, error);
+    }
+}
+
+pub(crate) fn client_before(
+    interceptors: &[Arc<dyn ClientInterceptor>],
+    ctx: &mut RequestContext,
+    payload: Option<&[u8]>,
+) -> Result<(), RPCError> {
+    for interceptor in interceptors {
+        interceptor.before(ctx, payload)?;
+    }
+    Ok(())
+}
+
+pub(crate) fn client_after(
+    interceptors: &[Arc<dyn ClientInterceptor>],
+    ctx: &RequestContext,
+    error: Option<&RPCError>,
+) {
+    for interceptor in interceptors.iter().rev() {
+        interceptor.after(ctx, error);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn encode_decode_metadata() {
+        let mut metadata = Metadata::new();
+        metadata.insert("trace-id", "abc");
+        metadata.insert("token", vec![0u8, 1, 2]);
+        let mut buf = BytesMut::new();
+        metadata.encode(&mut buf).unwrap();
+        buf.extend_from_slice(b"body");
+        assert_eq!(Metadata::decode(&mut buf), Some(metadata));
+        assert_eq!(&buf[..], b"body");
+
+        let mut truncated = BytesMut::from(&[1u8, 0, 5, 0][..]);
+        assert_eq!(Metadata::decode(&mut truncated), None);
+    }
+
+    #[test]
+    fn oversized_metadata() {
+        let mut buf = BytesMut::new();
+        let mut metadata = Metadata::new();
+        metadata.insert(&"k".repeat(u16::MAX as usize + 1), "v");
+        let err = metadata.encode(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(buf.is_empty());
+
+        let mut metadata = Metadata::new();
+        for i in 0..=u16::MAX as usize {
+            metadata.insert(&i.to_string(), vec![]);
+        }
+        assert!(metadata.encode(&mut buf).is_err());
+        assert!(buf.is_empty());
+    }
+}
@@ -0,0 +1,423 @@
+// Limits on calls to the services of an `rpc::Server`. Calls beyond them are refused right
+// away with `RPCRequestError::Overloaded` for clients to back off. Services and functions
+// can reserve part of the server capacity set by `Server::set_max_in_flight`, so floods of
+// other calls cannot starve them. Without that capacity reservations do nothing. Only calls
+// over the network are limited.
+
+use super::RPCRequestError;
+use parking_lot::{Mutex, RwLock};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+#[derive(Clone, Debug, Default)]
+pub struct Limits {
+    pub max_in_flight: Option<usize>,
+    // Calls per second, up to `burst` at once
+    pub max_rate: Option<u32>,
+    pub burst: Option<u32>,
+    // Slots of the server capacity no one else can take, needs `Server::set_max_in_flight`
+    pub reserved: usize,
+}
+
+impl Limits {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn max_in_flight(mut self, max: usize) -> Self {
+        self.max_in_flight = Some(max);
+        self
+    }
+
+    // `burst` is the rate unless set
+    pub fn max_rate(mut self, per_second: u32) -> Self {
+        self.max_rate = Some(per_second);
+        self
+    }
+
+    pub fn burst(mut self, burst: u32) -> Self {
+        self.burst = Some(burst);
+        self
+    }
+
+    pub fn reserve(mut self, slots: usize) -> Self {
+        self.reserved = slots;
+        self
+    }
+}
+
+// Limits for a service and some of its functions, calls have to be within both
+#[derive(Clone, Debug, Default)]
+pub struct ServiceLimits {
+    pub service: Limits,
+    pub functions: HashMap<u64, Limits>,
+}
+
+impl ServiceLimits {
+    pub fn new(service: Limits) -> Self {
+        ServiceLimits {
+            service,
+            functions: HashMap::new(),
+        }
+    }
+
+    // `func_id` as in `hash_ident!(function) as u64`
+    pub fn function(mut self, func_id: u64, limits: Limits) -> Self {
+        self.functions.insert(func_id, limits);
+        self
+    }
+
+    fn reserved(&self) -> usize {
+        self.service.reserved + self.functions.values().map(|l| l.reserved).sum::<usize>()
+    }
+}
+
+struct Slots {
+    max: AtomicUsize,
+    used: AtomicUsize,
+}
+
+impl Slots {
+    fn new(max: usize) -> Arc<Self> {
+        Arc::new(Slots {
+            max: AtomicUsize::new(max),
+            used: AtomicUsize::new(0),
+        })
+    }
+
+    fn try_take(self: &Arc<Self>) -> Option<SlotGuard> {
+        let max = self.max.load(Ordering::Relaxed);
+        self.used
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+                if used < max {
+                    Some(used + 1)
+                } else {
+                    None
+                }
+            })
+            .ok()
+            .map(|_| SlotGuard(self.clone()))
+    }
+}
+
+struct SlotGuard(Arc<Slots>);
+
+impl Drop for SlotGuard {
+    fn drop(&mut self) {
+        self.0.used.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+struct RateBucket {
+    rate: f64,
+    burst: f64,
+    // Tokens and when they were counted
+    tokens: Mutex<(f64, Instant)>,
+}
+
+impl RateBucket {
+    fn new(rate: u32, burst: Option<u32>) -> Self {
+        let burst = burst.unwrap_or(rate).max(1) as f64;
+        RateBucket {
+            rate: rate as f64,
+            burst,
+            tokens: Mutex::new((burst, Instant::now())),
+        }
+    }
+
+    // Milliseconds until the next token otherwise
+    fn try_take(&self) -> Result<(), u64> {
+        let mut tokens = self.tokens.lock();
+        let now = Instant::now();
+        let refill = now.duration_since(tokens.1).as_secs_f64() * self.rate;
+        tokens.0 = (tokens.0 + refill).min(self.burst);
+        tokens.1 = now;
+        if tokens.0 >= 1.0 {
+            tokens.0 -= 1.0;
+            Ok(())
+        } else if self.rate > 0.0 {
+            Err(((1.0 - tokens.0) / self.rate * 1000.0).ceil() as u64)
+        } else {
+            Err(1000)
+        }
+    }
+
+    // Gives back a token taken for a call another limit refused
+    fn refund(&self) {
+        let mut tokens = self.tokens.lock();
+        tokens.0 = (tokens.0 + 1.0).min(self.burst);
+    }
+}
+
+struct Limiter {
+    in_flight: Option<Arc<Slots>>,
+    rate: Option<RateBucket>,
+    reserved: Option<Arc<Slots>>,
+}
+
+impl Limiter {
+    fn new(limits: &Limits) -> Self {
+        Limiter {
+            in_flight: limits.max_in_flight.map(Slots::new),
+            rate: limits
+                .max_rate
+                .map(|rate| RateBucket::new(rate, limits.burst)),
+            reserved: if limits.reserved > 0 {
+                Some(Slots::new(limits.reserved))
+            } else {
+                None
+            },
+        }
+    }
+
+    fn take_in_flight(&self, guards: &mut Vec<SlotGuard>) -> Result<(), RPCRequestError> {
+        if let Some(slots) = &self.in_flight {
+            guards.push(slots.try_take().ok_or(overloaded(None))?);
+        }
+        Ok(())
+    }
+
+    fn take_rate(&self) -> Result<(), RPCRequestError> {
+        match &self.rate {
+            Some(rate) => rate.try_take().map_err(|wait| overloaded(Some(wait))),
+            None => Ok(()),
+        }
+    }
+}
+
+struct ServiceLimiter {
+    service: Limiter,
+    functions: HashMap<u64, Limiter>,
+}
+
+fn overloaded(retry_after_ms: Option<u64>) -> RPCRequestError {
+    RPCRequestError::Overloaded { retry_after_ms }
+}
+
+// Held while the call runs
+pub(crate) struct Admission {
+    _slots: Vec<SlotGuard>,
+}
+
+#[derive(Default)]
+pub(crate) struct ServerLimits {
+    services: RwLock<HashMap<u64, (ServiceLimits, Arc<ServiceLimiter>)>>,
+    max_in_flight: RwLock<Option<usize>>,
+    // Capacity left after all reservations
+    shared: RwLock<Option<Arc<Slots>>>,
+}
+
+impl ServerLimits {
+    pub(crate) fn set(&self, service_id: u64, limits: ServiceLimits) {
+        let limiter = Arc::new(ServiceLimiter {
+            service: Limiter::new(&limits.service),
+            functions: limits
+                .functions
+                .iter()
+                .map(|(func_id, limits)| (*func_id, Limiter::new(limits)))
+                .collect(),
+        });
+        self.services.write().insert(service_id, (limits, limiter));
+        self.resize_shared();
+    }
+
+    pub(crate) fn remove(&self, service_id: u64) {
+        if self.services.write().remove(&service_id).is_some() {
+            self.resize_shared();
+        }
+    }
+
+    pub(crate) fn set_max_in_flight(&self, max: Option<usize>) {
+        *self.max_in_flight.write() = max;
+        self.resize_shared();
+    }
+
+    // Reservations beyond the capacity are cut down, in order of service and function ids
+    fn resize_shared(&self) {
+        let max = *self.max_in_flight.read();
+        let mut shared = self.shared.write();
+        let max = match max {
+            Some(max) => max,
+            None => {
+                *shared = None;
+                return;
+            }
+        };
+        let services = self.services.read();
+        let mut service_ids: Vec<_> = services.keys().cloned().collect();
+        service_ids.sort();
+        let mut available = max;
+        for service_id in service_ids {
+            let (limits, limiter) = &services[&service_id];
+            let mut func_ids: Vec<_> = limits.functions.keys().cloned().collect();
+            func_ids.sort();
+            let pools = func_ids
+                .iter()
+                .map(|func_id| (&limits.functions[func_id], &limiter.functions[func_id]))
+                .chain(std::iter::once((&limits.service, &limiter.service)));
+            for (limits, limiter) in pools {
+                if let Some(slots) = &limiter.reserved {
+                    let granted = limits.reserved.min(available);
+                    slots.max.store(granted, Ordering::Relaxed);
+                    available -= granted;
+                }
+            }
+        }
+        let reserved: usize = services.values().map(|(l, _)| l.reserved()).sum();
+        if reserved > max {
+            warn!(
+                "{} slots reserved but only {} calls in flight allowed, reservations cut down",
+                reserved, max
+            );
+        }
+        match &*shared {
+            Some(slots) => slots.max.store(available, Ordering::Relaxed),
+            None => *shared = Some(Slots::new(available)),
+        }
+    }
+
+    pub(crate) fn admit(
+        &self,
+        service_id: u64,
+        func_id: u64,
+    ) -> Result<Admission, RPCRequestError> {
+        let mut guards = vec![];
+        let limiter = self
+            .services
+            .read()
+            .get(&service_id)
+            .map(|(_, limiter)| limiter.clone());
+        let function = limiter
+            .as_ref()
+            .and_then(|limiter| limiter.functions.get(&func_id));
+        if let Some(function) = function {
+            function.take_in_flight(&mut guards)?;
+        }
+        if let Some(limiter) = &limiter {
+            limiter.service.take_in_flight(&mut guards)?;
+        }
+        if let Some(shared) = &*self.shared.read() {
+            // Reserved slots of the function, then of the service, then the rest
+            let slot = function
+                .and_then(|f| f.reserved.as_ref())
+                .and_then(|slots| slots.try_take())
+                .or_else(|| {
+                    limiter
+                        .as_ref()
+                        .and_then(|l| l.service.reserved.as_ref())
+                        .and_then(|slots| slots.try_take())
+                })
+                .or_else(|| shared.try_take())
+                .ok_or(overloaded(None))?;
+            guards.push(slot);
+        }
+        // Rates last, calls refused for their slots do not spend tokens
+        if let Some(function) = function {
+            function.take_rate()?;
+        }
+        if let Some(limiter) = &limiter {
+            if let Err(e) = limiter.service.take_rate() {
+                if let Some(rate) = function.and_then(|f| f.rate.as_ref()) {
+                    rate.refund();
+                }
+                return Err(e);
+            }
+        }
+        Ok(Admission { _slots: guards })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn is_overloaded(res: &Result<Admission, RPCRequestError>) -> bool {
+        matches!(res, Err(RPCRequestError::Overloaded { .. }))
+    }
+
+    #[test]
+    fn in_flight_and_rate() {
+        let limits = ServerLimits::default();
+        limits.set(
+            1,
+            ServiceLimits::new(Limits::new().max_in_flight(2))
+                .function(10, Limits::new().max_rate(1)),
+        );
+        let first = limits.admit(1, 11).unwrap();
+        let second = limits.admit(1, 11).unwrap();
+        assert!(is_overloaded(&limits.admit(1, 11)));
+        drop(first);
+        let _third = limits.admit(1, 11).unwrap();
+        drop(second);
+        // One call per second for function 10
+        let _ = limits.admit(1, 10).unwrap();
+        match limits.admit(1, 10) {
+            Err(RPCRequestError::Overloaded {
+                retry_after_ms: Some(wait),
+            }) => assert!(wait > 0 && wait <= 1000),
+            _ => panic!("Expected rate limit"),
+        }
+        // Other services are not limited
+        let _others: Vec<_> = (0..10).map(|_| limits.admit(2, 10).unwrap()).collect();
+    }
+
+    #[test]
+    fn refused_calls_keep_tokens() {
+        let limits = ServerLimits::default();
+        limits.set(
+            1,
+            ServiceLimits::new(Limits::new().max_rate(10).burst(1))
+                .function(10, Limits::new().max_rate(1)),
+        );
+        let _ = limits.admit(1, 11).unwrap();
+        // Refused by the service rate, the token of function 10 is given back
+        assert!(is_overloaded(&limits.admit(1, 10)));
+        std::thread::sleep(std::time::Duration::from_millis(150));
+        let _ = limits.admit(1, 10).unwrap();
+    }
+
+    #[test]
+    fn reserved_capacity() {
+        let limits = ServerLimits::default();
+        limits.set_max_in_flight(Some(3));
+        limits.set(
+            1,
+            ServiceLimits::new(Limits::new()).function(10, Limits::new().reserve(1)),
+        );
+        // Others only get what is not reserved
+        let flood: Vec<_> = (0..2).map(|_| limits.admit(2, 0).unwrap()).collect();
+        assert!(is_overloaded(&limits.admit(2, 0)));
+        assert!(is_overloaded(&limits.admit(1, 11)));
+        let reserved = limits.admit(1, 10).unwrap();
+        assert!(is_overloaded(&limits.admit(1, 10)));
+        drop(reserved);
+        drop(flood);
+        limits.remove(1);
+        let _all: Vec<_> = (0..3).map(|_| limits.admit(2, 0).unwrap()).collect();
+    }
+
+    #[test]
+    fn reservations_beyond_capacity() {
+        let limits = ServerLimits::default();
+        limits.set(
+            1,
+            ServiceLimits::new(Limits::new().reserve(2)).function(10, Limits::new().reserve(2)),
+        );
+        limits.set_max_in_flight(Some(3));
+        // Function 10 gets its 2 slots, the service only the one left
+        let function: Vec<_> = (0..2).map(|_| limits.admit(1, 10).unwrap()).collect();
+        let service = limits.admit(1, 11).unwrap();
+        assert!(is_overloaded(&limits.admit(1, 11)));
+        assert!(is_overloaded(&limits.admit(2, 0)));
+        drop(function);
+        drop(service);
+        // Back in full once there is room
+        limits.set_max_in_flight(Some(5));
+        let _function: Vec<_> = (0..2).map(|_| limits.admit(1, 10).unwrap()).collect();
+        let _service: Vec<_> = (0..2).map(|_| limits.admit(1, 11).unwrap()).collect();
+        let _other = limits.admit(2, 0).unwrap();
+        assert!(is_overloaded(&limits.admit(2, 0)));
+    }
+}
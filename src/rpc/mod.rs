@@ -1,32 +1,62 @@
 #[macro_use]
 pub mod proto;
+pub mod auth;
+pub mod context;
+pub mod limits;
+pub mod reflection;
+pub mod stream;
 
 use crate::{tcp, DISABLE_SHORTCUT};
 use bifrost_hasher::hash_str;
 use bytes::{Buf, BufMut, BytesMut};
+use context::{ClientInterceptor, Metadata, RequestContext, ServerInterceptor};
 use futures::future::BoxFuture;
 use futures::prelude::*;
 use futures::Future;
-use serde::{Deserialize, Serialize};
 use lightning::map::*;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::any::Any;
+use std::cmp::max;
+use std::collections::HashMap;
 use std::error::Error;
 use std::io;
+use std::panic::AssertUnwindSafe;
 use std::pin::Pin;
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::atomic::{AtomicBool, AtomicUsize};
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::time::sleep;
 use tokio::time::*;
 
 lazy_static! {
-    pub static ref DEFAULT_CLIENT_POOL: ClientPool = ClientPool::new();
+    pub static ref DEFAULT_CLIENT_POOL: Arc<ClientPool> = Arc::new(ClientPool::new());
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+// Sent back to callers as the kind of error with its details
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum RPCRequestError {
     FunctionIdNotFound,
     ServiceIdNotFound,
     BadRequest,
     Other,
+    // Refused by the server authentication, see `rpc::auth`
+    Unauthenticated,
+    // The service function panicked, with the panic message
+    Panicked(String),
+    // Raised by services or interceptors, `payload` is up to the service to define
+    Service {
+        message: String,
+        payload: Option<Vec<u8>>,
+    },
+    // Pulled a stream the server does not know, it expired or was cancelled
+    StreamNotFound,
+    // Refused by the limits of the server, see `rpc::limits`. Rate limits tell when the
+    // next call can be made.
+    Overloaded {
+        retry_after_ms: Option<u64>,
+    },
 }
 
 #[derive(Debug)]
@@ -34,6 +64,8 @@ pub enum RPCError {
     IOError(io::Error),
     RequestError(RPCRequestError),
     ClientCannotDecodeResponse,
+    // Frame size, pending request or connection limits, see `tcp::LimitExceeded`
+    LimitExceeded(tcp::LimitExceeded),
 }
 
 pub trait RPCService: Sync + Send {
@@ -41,37 +73,84 @@ pub trait RPCService: Sync + Send {
     fn register_shortcut_service(
         &self,
         service_ptr: usize,
+        namespace: tcp::shortcut::Namespace,
+        server_id: u64,
+        service_id: u64,
+    ) -> ::std::pin::Pin<Box<dyn Future<Output = ()> + Send>>;
+    fn unregister_shortcut_service(
+        &self,
+        namespace: tcp::shortcut::Namespace,
         server_id: u64,
         service_id: u64,
     ) -> ::std::pin::Pin<Box<dyn Future<Output = ()> + Send>>;
+    // Made by `service!`, listed by the reflection service of the server
+    fn descriptor(&self) -> Option<reflection::ServiceDescriptor> {
+        None
+    }
 }
 
 pub struct Server {
-    services: ObjectMap<Arc<dyn RPCService>>,
+    services: Arc<ObjectMap<Arc<dyn RPCService>>>,
     pub address: String,
     pub server_id: u64,
+    pub options: tcp::server::ServerOptions,
+    shutdown: Arc<tcp::server::Shutdown>,
+    interceptors: context::ServerInterceptors,
+    limits: limits::ServerLimits,
+    // Drops streams of this server no longer pulled, stopped on shutdown
+    stream_sweeper: parking_lot::Mutex<Option<tokio::task::JoinHandle<()>>>,
 }
 
 unsafe impl Sync for Server {}
 
+pub const DEFAULT_CONNECTIONS_PER_PEER: usize = 1;
+// How often pools run `check_health` once they handed out a client
+pub const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+// Clients are kept per namespace, servers of independent clusters may share ids
+type PooledClients = HashMap<(tcp::shortcut::Namespace, u64), Arc<RPCClient>>;
+
 pub struct ClientPool {
-    clients: ObjectMap<Arc<RPCClient>>,
+    clients: Arc<parking_lot::RwLock<PooledClients>>,
+    options: parking_lot::RwLock<tcp::client::ClientOptions>,
+    connections_per_peer: AtomicUsize,
+    interceptors: context::ClientInterceptors,
+    // Started on the runtime of the first lookup, again if that runtime went away
+    health_checker: parking_lot::Mutex<Option<tokio::task::JoinHandle<()>>>,
 }
 
 fn encode_res(res: Result<BytesMut, RPCRequestError>) -> BytesMut {
     match res {
         Ok(buffer) => [0u8; 1].iter().cloned().chain(buffer.into_iter()).collect(),
         Err(e) => {
-            let err_id = match e {
-                RPCRequestError::FunctionIdNotFound => 1u8,
-                RPCRequestError::ServiceIdNotFound => 2u8,
-                _ => 255u8,
-            };
-            BytesMut::from(&[err_id][..])
+            let envelope = crate::utils::serde::serialize(&e);
+            let mut res = BytesMut::with_capacity(1 + envelope.len());
+            res.put_u8(255u8);
+            res.extend_from_slice(&envelope);
+            res
         }
     }
 }
 
+fn panic_message(panic: Box<dyn Any + Send>) -> String {
+    match panic.downcast::<String>() {
+        Ok(message) => *message,
+        Err(panic) => match panic.downcast::<&'static str>() {
+            Ok(message) => message.to_string(),
+            Err(_) => String::from("unknown panic"),
+        },
+    }
+}
+
+// Panics in service functions become errors for the caller instead of taking down the
+// task serving the request
+async fn catch_panic<F: Future>(f: F) -> Result<F::Output, RPCRequestError> {
+    AssertUnwindSafe(f)
+        .catch_unwind()
+        .await
+        .map_err(|panic| RPCRequestError::Panicked(panic_message(panic)))
+}
+
 fn decode_res(res: io::Result<BytesMut>) -> Result<BytesMut, RPCError> {
     match res {
         Ok(mut res) => {
@@ -80,13 +159,159 @@ fn decode_res(res: io::Result<BytesMut>) -> Result<BytesMut, RPCError> {
                 Ok(res.split())
             } else {
                 match res[0] {
-                    1u8 => Err(RPCError::RequestError(RPCRequestError::FunctionIdNotFound)),
-                    2u8 => Err(RPCError::RequestError(RPCRequestError::ServiceIdNotFound)),
-                    _ => Err(RPCError::RequestError(RPCRequestError::Other)),
+                    3u8 if res.len() == 17 => {
+                        res.advance(1);
+                        let size = res.get_u64_le() as usize;
+                        let max = res.get_u64_le() as usize;
+                        Err(RPCError::LimitExceeded(tcp::LimitExceeded::FrameSize {
+                            size,
+                            max,
+                        }))
+                    }
+                    255u8 => match crate::utils::serde::deserialize(&res[1..]) {
+                        Some(e) => Err(RPCError::RequestError(e)),
+                        None => Err(RPCError::ClientCannotDecodeResponse),
+                    },
+                    _ => Err(RPCError::ClientCannotDecodeResponse),
                 }
             }
         }
-        Err(e) => Err(RPCError::IOError(e)),
+        Err(e) => match tcp::LimitExceeded::from_io_error(&e) {
+            Some(limit) => Err(RPCError::LimitExceeded(limit.clone())),
+            None => Err(RPCError::IOError(e)),
+        },
+    }
+}
+
+// Service calls through the shortcut, bounded by `timeout` if any. `call` must not do
+// anything before it is polled, interceptors may reject the call.
+pub async fn call_local<F: Future>(
+    client: &Arc<RPCClient>,
+    service_id: u64,
+    func_id: u64,
+    metadata: &Metadata,
+    timeout: Option<Duration>,
+    call: F,
+) -> Result<F::Output, RPCError> {
+    let mut ctx = RequestContext::new(
+        client.server_id,
+        service_id,
+        func_id,
+        metadata.clone(),
+        true,
+    );
+    let client_interceptors = client.interceptors.read().clone();
+    let server_interceptors =
+        context::local_server_interceptors(client.namespace, client.server_id);
+    // Nothing to run around the call, the context only has to reach the service
+    if client_interceptors.is_empty() && server_interceptors.is_empty() {
+        return match run_local(ctx, timeout, call).await {
+            Ok(Ok(output)) => Ok(output),
+            Ok(Err(e)) => Err(RPCError::RequestError(e)),
+            Err(e) => Err(e),
+        };
+    }
+    context::client_before(&client_interceptors, &mut ctx, None)?;
+    let res = match context::server_before(&server_interceptors, &ctx, None) {
+        Ok(()) => match run_local(ctx.clone(), timeout, call).await {
+            Ok(Ok(output)) => {
+                context::server_after(&server_interceptors, &ctx, None);
+                Ok(output)
+            }
+            Ok(Err(e)) => {
+                context::server_after(&server_interceptors, &ctx, Some(&e));
+                Err(RPCError::RequestError(e))
+            }
+            Err(e) => {
+                context::server_after(&server_interceptors, &ctx, None);
+                Err(e)
+            }
+        },
+        Err(e) => {
+            context::server_after(&server_interceptors, &ctx, Some(&e));
+            Err(RPCError::RequestError(e))
+        }
+    };
+    context::client_after(&client_interceptors, &ctx, res.as_ref().err());
+    res
+}
+
+async fn run_local<F: Future>(
+    ctx: RequestContext,
+    timeout: Option<Duration>,
+    call: F,
+) -> Result<Result<F::Output, RPCRequestError>, RPCError> {
+    let call = catch_panic(context::scope(ctx, call));
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, call)
+            .await
+            .map_err(|e| RPCError::IOError(e.into())),
+        None => Ok(call.await),
+    }
+}
+
+// Service calls over the network, `service!` clients use this when the service is not local
+pub async fn call_remote<Req, Res>(
+    client: &Arc<RPCClient>,
+    service_id: u64,
+    func_id: u64,
+    req: &Req,
+    metadata: &Metadata,
+    timeout: Option<Duration>,
+) -> Result<Res, RPCError>
+where
+    Req: Serialize,
+    Res: DeserializeOwned,
+{
+    let req_data_bytes = BytesMut::from(crate::utils::serde::serialize(req).as_slice());
+    let req_bytes = prepend_u64(func_id, req_data_bytes);
+    let ctx = RequestContext::new(
+        client.server_id,
+        service_id,
+        func_id,
+        metadata.clone(),
+        false,
+    );
+    let res_bytes = client.send_with_context(ctx, req_bytes, timeout).await?;
+    match crate::utils::serde::deserialize(&res_bytes) {
+        Some(data) => Ok(data),
+        None => Err(RPCError::ClientCannotDecodeResponse),
+    }
+}
+
+// Calls the service in the context of the request, with panics as errors
+async fn dispatch(
+    service: Arc<dyn RPCService>,
+    ctx: &RequestContext,
+    data: BytesMut,
+) -> Result<BytesMut, RPCRequestError> {
+    let call = {
+        let service = service.clone();
+        async move { service.dispatch(data).await }
+    };
+    match catch_panic(context::scope(ctx.clone(), call)).await {
+        Ok(Err(RPCRequestError::FunctionIdNotFound)) => {
+            let name = service.descriptor().map(|d| d.name);
+            warn!(
+                "No function {} in service {} {:?}",
+                ctx.func_id, ctx.service_id, name
+            );
+            Err(RPCRequestError::FunctionIdNotFound)
+        }
+        Ok(res) => res,
+        Err(e) => {
+            error!("Service {} panicked, {:?}", ctx.service_id, e);
+            Err(e)
+        }
+    }
+}
+
+// Function id at the head of a service request, without consuming it
+fn peek_func_id(data: &BytesMut) -> u64 {
+    if data.len() >= 8 {
+        (&data[..8]).get_u64_le()
+    } else {
+        0
     }
 }
 
@@ -97,37 +322,131 @@ pub fn read_u64_head(mut data: BytesMut) -> (u64, BytesMut) {
 
 impl Server {
     pub fn new(address: &String) -> Arc<Server> {
+        Self::new_with_options(address, tcp::server::ServerOptions::default())
+    }
+    pub fn new_with_options(address: &String, options: tcp::server::ServerOptions) -> Arc<Server> {
+        let server_id = hash_str(address);
+        let interceptors = Arc::new(parking_lot::RwLock::new(vec![]));
+        context::register_local_server(options.namespace, server_id, &interceptors);
+        let services: Arc<ObjectMap<Arc<dyn RPCService>>> = Arc::new(ObjectMap::with_capacity(16));
+        services.insert(
+            &(stream::STREAM_SERVICE_ID as usize),
+            Arc::new(stream::service::StreamService),
+        );
+        services.insert(
+            &(reflection::REFLECTION_SERVICE_ID as usize),
+            Arc::new(reflection::service::ReflectionService {
+                services: Arc::downgrade(&services),
+            }),
+        );
         Arc::new(Server {
-            services: ObjectMap::with_capacity(16),
+            services,
             address: address.clone(),
-            server_id: hash_str(address),
+            server_id,
+            options,
+            shutdown: tcp::server::Shutdown::new(),
+            interceptors,
+            limits: Default::default(),
+            stream_sweeper: parking_lot::Mutex::new(None),
         })
     }
+    // Interceptors run in the order they were added, for all services of this server
+    pub fn add_interceptor(&self, interceptor: Arc<dyn ServerInterceptor>) {
+        self.interceptors.write().push(interceptor);
+    }
+    // Replaces the limits of calls to `service_id` over the network
+    pub fn set_limits(&self, service_id: u64, limits: limits::ServiceLimits) {
+        self.limits.set(service_id, limits);
+    }
+    // Calls running at once for all services, reservations are taken out of it
+    pub fn set_max_in_flight(&self, max: Option<usize>) {
+        self.limits.set_max_in_flight(max);
+    }
     pub async fn listen(server: &Arc<Server>) -> Result<(), Box<dyn Error>> {
         let address = &server.address;
+        let options = server.options.clone();
+        let max_frame_size = options.max_frame_size;
+        let shutdown = server.shutdown.clone();
+        if !shutdown.is_shutdown() {
+            let sweeper = tokio::spawn(stream::sweep_idle(server.server_id));
+            if let Some(previous) = server.stream_sweeper.lock().replace(sweeper) {
+                previous.abort();
+            }
+        }
         let server = server.clone();
-        tcp::server::Server::new(
+        tcp::server::Server::new_with_shutdown(
             address,
             Arc::new(move |data| {
                 let server = server.clone();
                 async move {
-                    let (svr_id, data) = read_u64_head(data);
-                    let service = server.services.get(&(svr_id as usize));
+                    if data.len() < 8 {
+                        return encode_res(Err(RPCRequestError::BadRequest));
+                    }
+                    let (svr_id, mut data) = read_u64_head(data);
+                    let metadata = match Metadata::decode(&mut data) {
+                        Some(metadata) => metadata,
+                        None => return encode_res(Err(RPCRequestError::BadRequest)),
+                    };
+                    let func_id = peek_func_id(&data);
+                    let ctx =
+                        RequestContext::new(server.server_id, svr_id, func_id, metadata, false);
+                    let interceptors = server.interceptors.read().clone();
                     trace!("Processing request for service {}", svr_id);
-                    match service {
-                        Some(service) => {
-                            let svr_res = service.dispatch(data).await;
-                            encode_res(svr_res)
-                        }
-                        None => encode_res(Err(RPCRequestError::ServiceIdNotFound)),
+                    let svr_res = match context::server_before(&interceptors, &ctx, Some(&data)) {
+                        Ok(()) => match server.services.get(&(svr_id as usize)) {
+                            Some(service) => match server.limits.admit(svr_id, ctx.func_id) {
+                                // Holds the limits until the call is done
+                                Ok(_admission) => dispatch(service, &ctx, data).await,
+                                Err(e) => Err(e),
+                            },
+                            None => {
+                                warn!("No service {} on server {}", svr_id, server.address);
+                                Err(RPCRequestError::ServiceIdNotFound)
+                            }
+                        },
+                        Err(e) => Err(e),
+                    };
+                    context::server_after(&interceptors, &ctx, svr_res.as_ref().err());
+                    let res = encode_res(svr_res);
+                    // 8 bytes for the message id added by the tcp server
+                    if res.len() + 8 > max_frame_size {
+                        tcp::server::encode_response_too_large(res.len() + 8, max_frame_size)
+                    } else {
+                        res
                     }
                 }
                 .boxed()
             }),
+            options,
+            &shutdown,
         )
         .await
     }
 
+    // Stops listening, unregisters shortcuts and waits for in-flight requests until `grace`
+    // has passed. The server cannot be started again.
+    pub async fn shutdown(&self, grace: Duration) {
+        for (service_id, service) in self.services.entries() {
+            service
+                .unregister_shortcut_service(
+                    self.options.namespace,
+                    self.server_id,
+                    service_id as u64,
+                )
+                .await;
+        }
+        context::unregister_local_server(
+            self.options.namespace,
+            self.server_id,
+            &self.interceptors,
+        );
+        self.shutdown.shutdown(grace).await;
+        if let Some(sweeper) = self.stream_sweeper.lock().take() {
+            sweeper.abort();
+        }
+        stream::close_server(self.server_id);
+    }
+
     pub async fn listen_and_resume(server: &Arc<Server>) {
         let server = server.clone();
         tokio::spawn(async move {
@@ -141,10 +460,15 @@ impl Server {
         T: RPCService + Sized + 'static,
     {
         let service = service.clone();
-        if !DISABLE_SHORTCUT {
+        if !DISABLE_SHORTCUT && self.options.transport.allows_shortcut() {
             let service_ptr = Arc::into_raw(service.clone()) as usize;
             service
-                .register_shortcut_service(service_ptr, self.server_id, service_id)
+                .register_shortcut_service(
+                    service_ptr,
+                    self.options.namespace,
+                    self.server_id,
+                    service_id,
+                )
                 .await;
         } else {
             debug!("SERVICE SHORTCUT DISABLED");
@@ -152,17 +476,41 @@ impl Server {
         self.services.insert(&(service_id as usize), service);
     }
 
+    pub async fn register_service_with_limits<T>(
+        &self,
+        service_id: u64,
+        service: &Arc<T>,
+        limits: limits::ServiceLimits,
+    ) where
+        T: RPCService + Sized + 'static,
+    {
+        self.set_limits(service_id, limits);
+        self.register_service(service_id, service).await;
+    }
+
     pub async fn remove_service(&self, service_id: u64) {
-        self.services.remove(&(service_id as usize));
+        self.limits.remove(service_id);
+        if let Some(service) = self.services.remove(&(service_id as usize)) {
+            service
+                .unregister_shortcut_service(self.options.namespace, self.server_id, service_id)
+                .await;
+        }
     }
     pub fn address(&self) -> &String {
         &self.address
     }
 }
 
+// Requests are striped across all connections to the server. Connections that have given up
+// reconnecting are replaced by `replace_broken`.
 pub struct RPCClient {
-    client: tcp::client::Client,
+    connections: parking_lot::RwLock<Vec<Arc<tcp::client::Client>>>,
+    next: AtomicUsize,
+    replacing: AtomicBool,
+    options: tcp::client::ClientOptions,
+    interceptors: context::ClientInterceptors,
     pub server_id: u64,
+    pub namespace: tcp::shortcut::Namespace,
     pub address: String,
 }
 
@@ -179,26 +527,212 @@ impl RPCClient {
         svr_id: u64,
         data: BytesMut,
     ) -> Result<BytesMut, RPCError> {
-        let client = &self.client;
-        let payload = prepend_u64(svr_id, data);
-        let res = client.send_msg(payload).await;
-        decode_res(res)
+        self.send_async_with_timeout(svr_id, data, None).await
+    }
+    // Without a timeout the one from the client options applies
+    pub async fn send_async_with_timeout(
+        self: Pin<&Self>,
+        svr_id: u64,
+        data: BytesMut,
+        timeout: Option<Duration>,
+    ) -> Result<BytesMut, RPCError> {
+        let func_id = peek_func_id(&data);
+        let ctx = RequestContext::new(self.server_id, svr_id, func_id, Metadata::default(), false);
+        self.send_with_context(ctx, data, timeout).await
+    }
+    // Sends `data` for the service in `ctx` after the interceptors had their turn
+    pub async fn send_with_context(
+        &self,
+        mut ctx: RequestContext,
+        data: BytesMut,
+        timeout: Option<Duration>,
+    ) -> Result<BytesMut, RPCError> {
+        let interceptors = self.interceptors.read().clone();
+        context::client_before(&interceptors, &mut ctx, Some(&data))?;
+        let mut payload = BytesMut::with_capacity(8 + 2 + data.len());
+        payload.put_u64_le(ctx.service_id);
+        let res = match ctx.metadata.encode(&mut payload) {
+            Ok(()) => {
+                payload.extend_from_slice(&data);
+                let client = self.pick();
+                decode_res(match timeout {
+                    Some(timeout) => client.send_msg_with_timeout(payload, timeout).await,
+                    None => client.send_msg(payload).await,
+                })
+            }
+            Err(e) => Err(RPCError::IOError(e)),
+        };
+        context::client_after(&interceptors, &ctx, res.as_ref().err());
+        res
+    }
+    // Interceptors run in the order they were added
+    pub fn add_interceptor(&self, interceptor: Arc<dyn ClientInterceptor>) {
+        self.interceptors.write().push(interceptor);
     }
     pub async fn new_async(addr: &String) -> io::Result<Arc<RPCClient>> {
-        let client = tcp::client::Client::connect(addr).await?;
+        Self::new_async_with_options(addr, tcp::client::ClientOptions::default()).await
+    }
+    pub async fn new_async_with_options(
+        addr: &String,
+        options: tcp::client::ClientOptions,
+    ) -> io::Result<Arc<RPCClient>> {
+        Self::new_async_with_connections(addr, options, 1).await
+    }
+    // Opens `connections` connections to the server, or just one when it is reached
+    // through the shortcut
+    pub async fn new_async_with_connections(
+        addr: &String,
+        options: tcp::client::ClientOptions,
+        connections: usize,
+    ) -> io::Result<Arc<RPCClient>> {
+        let first = tcp::client::Client::connect_with_options(addr, options.clone()).await?;
+        let (server_id, namespace) = (first.server_id, first.namespace);
+        let mut clients = vec![Arc::new(first)];
+        if !clients[0].is_local() {
+            let rest = future::try_join_all(
+                (1..connections)
+                    .map(|_| tcp::client::Client::connect_with_options(addr, options.clone())),
+            )
+            .await?;
+            clients.extend(rest.into_iter().map(Arc::new));
+        }
         Ok(Arc::new(RPCClient {
-            server_id: client.server_id,
-            client,
+            connections: parking_lot::RwLock::new(clients),
+            next: AtomicUsize::new(0),
+            replacing: AtomicBool::new(false),
+            options,
+            interceptors: parking_lot::RwLock::new(vec![]),
+            server_id,
+            namespace,
             address: addr.clone(),
         }))
     }
+    // Next connection in turn, skipping those that are reconnecting if possible
+    fn pick(&self) -> Arc<tcp::client::Client> {
+        let connections = self.connections.read();
+        let start = self.next.fetch_add(1, Relaxed);
+        (0..connections.len())
+            .map(|i| &connections[(start + i) % connections.len()])
+            .find(|client| client.is_connected())
+            .unwrap_or(&connections[start % connections.len()])
+            .clone()
+    }
+    pub fn connections(&self) -> usize {
+        self.connections.read().len()
+    }
+    // All connections have given up reconnecting
+    pub fn is_broken(&self) -> bool {
+        self.connections
+            .read()
+            .iter()
+            .all(|client| client.is_broken())
+    }
+    pub fn has_broken(&self) -> bool {
+        self.connections
+            .read()
+            .iter()
+            .any(|client| client.is_broken())
+    }
+    // Reconnects in place of broken connections, returns how many were replaced
+    pub async fn replace_broken(&self) -> usize {
+        if self
+            .replacing
+            .compare_exchange(false, true, Relaxed, Relaxed)
+            .is_err()
+        {
+            return 0;
+        }
+        let broken: Vec<_> = self
+            .connections
+            .read()
+            .iter()
+            .filter(|client| client.is_broken())
+            .cloned()
+            .collect();
+        let mut replaced = 0;
+        for client in broken {
+            match tcp::client::Client::connect_with_options(&self.address, self.options.clone())
+                .await
+            {
+                Ok(new_client) => {
+                    let mut connections = self.connections.write();
+                    if let Some(slot) = connections.iter_mut().find(|c| Arc::ptr_eq(c, &client)) {
+                        *slot = Arc::new(new_client);
+                        replaced += 1;
+                    }
+                }
+                Err(e) => {
+                    debug!("Cannot replace connection to {}, {}", self.address, e);
+                    break;
+                }
+            }
+        }
+        self.replacing.store(false, Relaxed);
+        replaced
+    }
 }
 
 impl ClientPool {
     pub fn new() -> ClientPool {
+        Self::new_with_options(tcp::client::ClientOptions::default())
+    }
+
+    pub fn new_with_options(options: tcp::client::ClientOptions) -> ClientPool {
         ClientPool {
-            clients: ObjectMap::with_capacity(16),
+            clients: Arc::new(parking_lot::RwLock::new(HashMap::new())),
+            options: parking_lot::RwLock::new(options),
+            connections_per_peer: AtomicUsize::new(DEFAULT_CONNECTIONS_PER_PEER),
+            interceptors: parking_lot::RwLock::new(vec![]),
+            health_checker: parking_lot::Mutex::new(None),
+        }
+    }
+
+    // Added to every client of the pool, including those created later
+    pub fn add_interceptor(&self, interceptor: Arc<dyn ClientInterceptor>) {
+        self.interceptors.write().push(interceptor.clone());
+        for client in self.clients.read().values() {
+            client.add_interceptor(interceptor.clone());
+        }
+    }
+
+    // Like `set_options`, only clients created afterwards get this many connections
+    pub fn set_connections_per_peer(&self, connections: usize) {
+        self.connections_per_peer
+            .store(max(connections, 1), Relaxed);
+    }
+
+    // Drops clients that lost all connections and replaces broken connections of the others
+    pub async fn check_health(&self) {
+        check_clients(&self.clients).await
+    }
+
+    fn start_health_checks(&self) {
+        let mut checker = self.health_checker.lock();
+        if checker.as_ref().map_or(false, |task| !task.is_finished()) {
+            return;
         }
+        let clients = Arc::downgrade(&self.clients);
+        *checker = Some(tokio::spawn(async move {
+            let mut interval = tokio::time::interval(HEALTH_CHECK_INTERVAL);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                match clients.upgrade() {
+                    Some(clients) => check_clients(&clients).await,
+                    None => break,
+                }
+            }
+        }));
+    }
+
+    // Only applies to clients created afterwards, e.g. to enable TLS on `DEFAULT_CLIENT_POOL`
+    // before any raft or membership traffic starts
+    pub fn set_options(&self, options: tcp::client::ClientOptions) {
+        *self.options.write() = options;
+    }
+
+    pub fn options(&self) -> tcp::client::ClientOptions {
+        self.options.read().clone()
     }
 
     pub async fn get(&self, addr: &String) -> io::Result<Arc<RPCClient>> {
@@ -211,22 +745,79 @@ impl ClientPool {
     where
         F: FnOnce(u64) -> String,
     {
-        let clients = &self.clients;
-        if clients.contains_key(&(server_id as usize)) {
-            let client = clients.get(&(server_id as usize)).unwrap().clone();
-            Ok(client)
-        } else {
-            let client = timeout(
-                Duration::from_secs(5),
-                RPCClient::new_async(&addr_fn(server_id)),
-            )
-            .await??;
-            clients.insert(&(server_id as usize), client.clone());
-            Ok(client)
+        self.start_health_checks();
+        let options = self.options.read().clone();
+        let key = (options.namespace, server_id);
+        let cached = self.clients.read().get(&key).cloned();
+        match cached {
+            Some(client) if !client.is_broken() => {
+                if client.has_broken() {
+                    let client = client.clone();
+                    tokio::spawn(async move { client.replace_broken().await });
+                }
+                Ok(client)
+            }
+            _ => {
+                if let Some(client) = cached {
+                    debug!("Replacing broken client for server {}", server_id);
+                    remove_client(&self.clients, key, &client);
+                }
+                let connections = self.connections_per_peer.load(Relaxed);
+                let client = timeout(
+                    Duration::from_secs(5),
+                    RPCClient::new_async_with_connections(
+                        &addr_fn(server_id),
+                        options,
+                        connections,
+                    ),
+                )
+                .await??;
+                for interceptor in self.interceptors.read().iter() {
+                    client.add_interceptor(interceptor.clone());
+                }
+                self.clients.write().insert(key, client.clone());
+                Ok(client)
+            }
+        }
+    }
+}
+
+impl Drop for ClientPool {
+    fn drop(&mut self) {
+        if let Some(checker) = self.health_checker.lock().take() {
+            checker.abort();
+        }
+    }
+}
+
+async fn check_clients(pooled: &parking_lot::RwLock<PooledClients>) {
+    let clients: Vec<_> = pooled
+        .read()
+        .iter()
+        .map(|(key, client)| (*key, client.clone()))
+        .collect();
+    for (key, client) in clients {
+        if client.is_broken() {
+            debug!("Removing broken client for server {}", key.1);
+            remove_client(pooled, key, &client);
+        } else if client.has_broken() {
+            client.replace_broken().await;
         }
     }
 }
 
+// Unless it was replaced in the meantime
+fn remove_client(
+    pooled: &parking_lot::RwLock<PooledClients>,
+    key: (tcp::shortcut::Namespace, u64),
+    client: &Arc<RPCClient>,
+) {
+    let mut clients = pooled.write();
+    if clients.get(&key).map_or(false, |c| Arc::ptr_eq(c, client)) {
+        clients.remove(&key);
+    }
+}
+
 #[cfg(test)]
 mod test {
     use futures::future::BoxFuture;
@@ -278,6 +869,179 @@ mod test {
             let error_msg = response.await.unwrap().err().unwrap();
             assert_eq!(error_msg, expected_err_msg);
         }
+
+        #[tokio::test(flavor = "multi_thread")]
+        pub async fn shutdown() {
+            let _ = env_logger::try_init();
+            let addr = String::from("127.0.0.1:1301");
+            let server = Server::new(&addr);
+            server.register_service(0, &Arc::new(HelloServer)).await;
+            Server::listen_and_resume(&server).await;
+            let client = RPCClient::new_async(&addr).await.unwrap();
+            let service_client = AsyncServiceClient::new(0, &client);
+            let response = service_client.hello(String::from("Jack")).await;
+            assert_eq!(response.unwrap(), String::from("Hello, Jack!"));
+            assert!(get_local(Default::default(), server.server_id, 0)
+                .await
+                .is_some());
+
+            server.shutdown(Duration::from_secs(1)).await;
+            assert!(get_local(Default::default(), server.server_id, 0)
+                .await
+                .is_none());
+            assert!(service_client.hello(String::from("Jack")).await.is_err());
+            assert!(RPCClient::new_async(&addr).await.is_err());
+        }
+
+        #[tokio::test(flavor = "multi_thread")]
+        pub async fn striped_pool() {
+            let _ = env_logger::try_init();
+            let addr = String::from("127.0.0.1:1302");
+            let start_server = || async {
+                let server = Server::new(&addr);
+                server.register_service(0, &Arc::new(HelloServer)).await;
+                Server::listen_and_resume(&server).await;
+                server
+            };
+            let server = start_server().await;
+            let pool = ClientPool::new_with_options(crate::tcp::client::ClientOptions {
+                reconnect_backoff: Duration::from_millis(50),
+                max_reconnect_attempts: 1,
+                ..Default::default()
+            });
+            pool.set_connections_per_peer(4);
+            // Another name for the server address, so the shortcut is not taken
+            let client = pool.get(&String::from("localhost:1302")).await.unwrap();
+            assert_eq!(client.connections(), 4);
+            let service_client = Arc::new(AsyncServiceClient::new(0, &client));
+            let calls = (0..32).map(|i| {
+                let service_client = service_client.clone();
+                async move { service_client.hello(format!("{}", i)).await.unwrap() }
+            });
+            let greetings = future::join_all(calls).await;
+            assert_eq!(greetings[31], String::from("Hello, 31!"));
+
+            server.shutdown(Duration::from_secs(1)).await;
+            for _ in 0..50 {
+                if client.is_broken() {
+                    break;
+                }
+                sleep(Duration::from_millis(100)).await;
+            }
+            assert!(client.is_broken());
+            let _server = start_server().await;
+            assert_eq!(client.replace_broken().await, 4);
+            let response = service_client.hello(String::from("Jack")).await;
+            assert_eq!(response.unwrap(), String::from("Hello, Jack!"));
+        }
+
+        #[tokio::test(flavor = "multi_thread")]
+        pub async fn health_checks() {
+            let _ = env_logger::try_init();
+            let addr = String::from("127.0.0.1:1306");
+            let server = Server::new(&addr);
+            server.register_service(0, &Arc::new(HelloServer)).await;
+            Server::listen_and_resume(&server).await;
+            let pool = ClientPool::new_with_options(crate::tcp::client::ClientOptions {
+                reconnect_backoff: Duration::from_millis(50),
+                max_reconnect_attempts: 1,
+                ..Default::default()
+            });
+            let client = pool.get(&String::from("localhost:1306")).await.unwrap();
+            server.shutdown(Duration::from_secs(1)).await;
+            // Dropped by the pool without anyone asking for it again
+            for _ in 0..(HEALTH_CHECK_INTERVAL.as_millis() / 100 * 3) {
+                if pool.clients.read().is_empty() {
+                    break;
+                }
+                sleep(Duration::from_millis(100)).await;
+            }
+            assert!(client.is_broken());
+            assert!(pool.clients.read().is_empty());
+        }
+
+        #[tokio::test(flavor = "multi_thread")]
+        pub async fn call_deadline() {
+            let _ = env_logger::try_init();
+            let network = crate::tcp::sim::SimNetwork::new(0);
+            let addr = String::from("deadline-server");
+            let server = Server::new_with_options(
+                &addr,
+                crate::tcp::server::ServerOptions {
+                    transport: network.node(&addr),
+                    ..Default::default()
+                },
+            );
+            server.register_service(0, &Arc::new(HelloServer)).await;
+            Server::listen_and_resume(&server).await;
+            let client = RPCClient::new_async_with_options(
+                &addr,
+                crate::tcp::client::ClientOptions {
+                    transport: network.node("deadline-client"),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+            network.set_link(
+                "deadline-client",
+                &addr,
+                crate::tcp::sim::LinkRules {
+                    delay: Duration::from_millis(300),
+                    ..Default::default()
+                },
+            );
+            let service_client = AsyncServiceClient::new(0, &client);
+            match service_client
+                .with_timeout(Duration::from_millis(100))
+                .hello(String::from("Jack"))
+                .await
+            {
+                Err(RPCError::IOError(e)) => assert_eq!(e.kind(), io::ErrorKind::TimedOut),
+                other => panic!("Expected timeout, got {:?}", other),
+            }
+            let response = service_client
+                .with_timeout(Duration::from_secs(1))
+                .hello(String::from("Jack"))
+                .await;
+            assert_eq!(response.unwrap(), String::from("Hello, Jack!"));
+        }
+
+        #[tokio::test(flavor = "multi_thread")]
+        pub async fn simulated_network_rpc() {
+            let _ = env_logger::try_init();
+            let network = crate::tcp::sim::SimNetwork::new(0);
+            let addr = String::from("sim-server");
+            {
+                let server = Server::new_with_options(
+                    &addr,
+                    crate::tcp::server::ServerOptions {
+                        transport: network.node(&addr),
+                        ..Default::default()
+                    },
+                );
+                server.register_service(0, &Arc::new(HelloServer)).await;
+                Server::listen_and_resume(&server).await;
+            }
+            let client = RPCClient::new_async_with_options(
+                &addr,
+                crate::tcp::client::ClientOptions {
+                    timeout: Duration::from_millis(500),
+                    transport: network.node("sim-client"),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+            let service_client = AsyncServiceClient::new(0, &client);
+            let response = service_client.hello(String::from("Jack")).await;
+            assert_eq!(response.unwrap(), String::from("Hello, Jack!"));
+            network.partition("sim-client", &addr);
+            assert!(service_client.hello(String::from("Jack")).await.is_err());
+            network.heal_all();
+            let response = service_client.hello(String::from("Jack")).await;
+            assert_eq!(response.unwrap(), String::from("Hello, Jack!"));
+        }
     }
 
     pub mod struct_service {
@@ -443,6 +1207,112 @@ mod test {
                 id += 1;
             }
         }
+
+        #[tokio::test(flavor = "multi_thread")]
+        async fn limits() {
+            use crate::tcp::LimitExceeded;
+            let _ = env_logger::try_init();
+            let network = crate::tcp::sim::SimNetwork::new(0);
+            let addr = String::from("limited-server");
+            let server = Server::new_with_options(
+                &addr,
+                crate::tcp::server::ServerOptions {
+                    transport: network.node(&addr),
+                    max_frame_size: 16 * 1024,
+                    max_connections: 1,
+                    ..Default::default()
+                },
+            );
+            server
+                .register_service(0, &Arc::new(IdServer { id: 0 }))
+                .await;
+            Server::listen_and_resume(&server).await;
+            let client_options = crate::tcp::client::ClientOptions {
+                transport: network.node("limited-client"),
+                max_frame_size: 16 * 1024,
+                ..Default::default()
+            };
+            let client = RPCClient::new_async_with_options(&addr, client_options.clone())
+                .await
+                .unwrap();
+            let service_client = AsyncServiceClient::new(0, &client);
+            assert_eq!(service_client.query_server_id().await.unwrap(), 0);
+            match service_client.large_query(None).await {
+                Err(RPCError::LimitExceeded(LimitExceeded::FrameSize { max, .. })) => {
+                    assert_eq!(max, 16 * 1024)
+                }
+                _ => panic!("Response should exceed frame size"),
+            }
+            let large: Vec<_> = (0..1024)
+                .map(|id| ComplexAnswer {
+                    name: String::from("large"),
+                    id,
+                    req: None,
+                })
+                .collect();
+            match service_client.large_req(large.clone(), large).await {
+                Err(RPCError::LimitExceeded(LimitExceeded::FrameSize { .. })) => {}
+                _ => panic!("Request should exceed frame size"),
+            }
+            // Connection is still usable after rejected frames
+            assert_eq!(service_client.query_server_id().await.unwrap(), 0);
+
+            let err = RPCClient::new_async_with_options(&addr, client_options)
+                .await
+                .err()
+                .unwrap();
+            assert_eq!(
+                LimitExceeded::from_io_error(&err),
+                Some(&LimitExceeded::Connections(1))
+            );
+        }
+
+        #[tokio::test(flavor = "multi_thread")]
+        async fn isolated_namespaces() {
+            use crate::tcp::shortcut::Namespace;
+            use crate::tcp::STANDALONE_ADDRESS_STRING;
+            let addr = STANDALONE_ADDRESS_STRING.clone();
+            let namespaces = vec![Namespace::isolated(), Namespace::isolated()];
+            for (id, namespace) in namespaces.iter().enumerate() {
+                let server = Server::new_with_options(
+                    &addr,
+                    crate::tcp::server::ServerOptions {
+                        namespace: *namespace,
+                        ..Default::default()
+                    },
+                );
+                server
+                    .register_service(0, &Arc::new(IdServer { id: id as u64 }))
+                    .await;
+                Server::listen(&server).await.unwrap();
+            }
+            // Both clusters use the same address and only see their own server
+            for (id, namespace) in namespaces.iter().enumerate() {
+                let client = RPCClient::new_async_with_options(
+                    &addr,
+                    crate::tcp::client::ClientOptions {
+                        namespace: *namespace,
+                        ..Default::default()
+                    },
+                )
+                .await
+                .unwrap();
+                let service_client = AsyncServiceClient::new(0, &client);
+                assert_eq!(service_client.query_server_id().await.unwrap(), id as u64);
+            }
+            // One pool switching namespaces must not hand out the other cluster's client
+            let pool = ClientPool::new();
+            for (id, namespace) in namespaces.iter().enumerate() {
+                pool.set_options(crate::tcp::client::ClientOptions {
+                    namespace: *namespace,
+                    ..Default::default()
+                });
+                let client = pool.get(&addr).await.unwrap();
+                let service_client = AsyncServiceClient::new(0, &client);
+                assert_eq!(service_client.query_server_id().await.unwrap(), id as u64);
+            }
+            assert!(RPCClient::new_async(&addr).await.is_err());
+        }
     }
 
     mod parallel {
@@ -515,4 +1385,623 @@ mod test {
             while futs.next().await.is_some() {}
         }
     }
+
+    mod interceptors {
+        use super::*;
+        use crate::rpc::context::{self, *};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        service! {
+            rpc trace_id() -> Option<String>;
+        }
+
+        struct TracedServer;
+
+        impl Service for TracedServer {
+            fn trace_id(&self) -> BoxFuture<Option<String>> {
+                let trace_id = context::current()
+                    .and_then(|ctx| ctx.metadata.get_str("trace-id").map(String::from));
+                future::ready(trace_id).boxed()
+            }
+        }
+        dispatch_rpc_service_functions!(TracedServer);
+
+        // Adds a trace id to calls that do not have one
+        struct Tracer;
+
+        impl ClientInterceptor for Tracer {
+            fn before(&self, ctx: &mut RequestContext, _: Option<&[u8]>) -> Result<(), RPCError> {
+                if ctx.metadata.get("trace-id").is_none() {
+                    ctx.metadata.insert("trace-id", "generated");
+                }
+                Ok(())
+            }
+        }
+
+        // Turns away calls marked with `deny`, counts the others
+        #[derive(Default)]
+        struct Guard {
+            denied: AtomicUsize,
+            finished: AtomicUsize,
+        }
+
+        impl ServerInterceptor for Guard {
+            fn before(
+                &self,
+                ctx: &RequestContext,
+                _: Option<&[u8]>,
+            ) -> Result<(), RPCRequestError> {
+                if ctx.metadata.get("deny").is_some() {
+                    Err(RPCRequestError::Other)
+                } else {
+                    Ok(())
+                }
+            }
+            fn after(&self, _: &RequestContext, error: Option<&RPCRequestError>) {
+                match error {
+                    Some(_) => self.denied.fetch_add(1, Ordering::Relaxed),
+                    None => self.finished.fetch_add(1, Ordering::Relaxed),
+                };
+            }
+        }
+
+        async fn check_calls(server: &Arc<Server>, client: &Arc<RPCClient>) {
+            let guard = Arc::new(Guard::default());
+            server.add_interceptor(guard.clone());
+            client.add_interceptor(Arc::new(Tracer));
+            let service_client = AsyncServiceClient::new(0, client);
+            let trace_id = service_client.trace_id().await.unwrap();
+            assert_eq!(trace_id, Some(String::from("generated")));
+
+            let mut metadata = Metadata::new();
+            metadata.insert("trace-id", "abc");
+            let trace_id = service_client
+                .with_metadata(metadata.clone())
+                .trace_id()
+                .await
+                .unwrap();
+            assert_eq!(trace_id, Some(String::from("abc")));
+
+            metadata.insert("deny", "");
+            match service_client.with_metadata(metadata).trace_id().await {
+                Err(RPCError::RequestError(RPCRequestError::Other)) => {}
+                other => panic!("Expected rejection, got {:?}", other),
+            }
+            assert_eq!(guard.finished.load(Ordering::Relaxed), 2);
+            assert_eq!(guard.denied.load(Ordering::Relaxed), 1);
+        }
+
+        #[tokio::test(flavor = "multi_thread")]
+        async fn remote_calls() {
+            let _ = env_logger::try_init();
+            let network = crate::tcp::sim::SimNetwork::new(0);
+            let addr = String::from("traced-server");
+            let server = Server::new_with_options(
+                &addr,
+                crate::tcp::server::ServerOptions {
+                    transport: network.node(&addr),
+                    ..Default::default()
+                },
+            );
+            server.register_service(0, &Arc::new(TracedServer)).await;
+            Server::listen_and_resume(&server).await;
+            let client = RPCClient::new_async_with_options(
+                &addr,
+                crate::tcp::client::ClientOptions {
+                    transport: network.node("traced-client"),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+            check_calls(&server, &client).await;
+        }
+
+        #[tokio::test(flavor = "multi_thread")]
+        async fn shortcut_calls() {
+            let _ = env_logger::try_init();
+            let addr = String::from("127.0.0.1:1303");
+            let server = Server::new(&addr);
+            server.register_service(0, &Arc::new(TracedServer)).await;
+            Server::listen_and_resume(&server).await;
+            let client = RPCClient::new_async(&addr).await.unwrap();
+            assert!(get_local(Default::default(), client.server_id, 0)
+                .await
+                .is_some());
+            check_calls(&server, &client).await;
+        }
+
+        #[tokio::test(flavor = "multi_thread")]
+        async fn authenticated_calls() {
+            let _ = env_logger::try_init();
+            let network = crate::tcp::sim::SimNetwork::new(0);
+            let addr = String::from("auth-server");
+            let server = Server::new_with_options(
+                &addr,
+                crate::tcp::server::ServerOptions {
+                    transport: network.node(&addr),
+                    ..Default::default()
+                },
+            );
+            server.register_service(0, &Arc::new(TracedServer)).await;
+            server.register_service(1, &Arc::new(TracedServer)).await;
+            server.add_interceptor(Arc::new(auth::HmacAuth::new(b"cluster key").public(1)));
+            Server::listen_and_resume(&server).await;
+            let connect = |node: &'static str| {
+                RPCClient::new_async_with_options(
+                    &addr,
+                    crate::tcp::client::ClientOptions {
+                        transport: network.node(node),
+                        ..Default::default()
+                    },
+                )
+            };
+            let stranger = connect("stranger").await.unwrap();
+            match AsyncServiceClient::new(0, &stranger).trace_id().await {
+                Err(RPCError::RequestError(RPCRequestError::Unauthenticated)) => {}
+                other => panic!("Expected unauthenticated error, got {:?}", other),
+            }
+            assert!(AsyncServiceClient::new(1, &stranger)
+                .trace_id()
+                .await
+                .is_ok());
+
+            let member = connect("member").await.unwrap();
+            member.add_interceptor(Arc::new(auth::HmacSigner::new(b"cluster key")));
+            assert!(AsyncServiceClient::new(0, &member).trace_id().await.is_ok());
+        }
+    }
+
+    mod errors {
+        use super::*;
+        use crate::rpc::context::*;
+
+        service! {
+            rpc divide(a: u32, b: u32) -> u32;
+        }
+
+        struct Calculator;
+
+        impl Service for Calculator {
+            fn divide(&self, a: u32, b: u32) -> BoxFuture<u32> {
+                future::ready(a / b).boxed()
+            }
+        }
+        dispatch_rpc_service_functions!(Calculator);
+
+        // Refuses calls asking for it, with details for the caller
+        struct Refuser;
+
+        impl ServerInterceptor for Refuser {
+            fn before(
+                &self,
+                ctx: &RequestContext,
+                _: Option<&[u8]>,
+            ) -> Result<(), RPCRequestError> {
+                match ctx.metadata.get("refuse") {
+                    Some(reason) => Err(RPCRequestError::Service {
+                        message: String::from("refused"),
+                        payload: Some(reason.to_vec()),
+                    }),
+                    None => Ok(()),
+                }
+            }
+        }
+
+        async fn check_panic(client: &Arc<RPCClient>) {
+            let service_client = AsyncServiceClient::new(0, client);
+            match service_client.divide(1, 0).await {
+                Err(RPCError::RequestError(RPCRequestError::Panicked(message))) => {
+                    assert!(message.contains("divide by zero"))
+                }
+                other => panic!("Expected panic error, got {:?}", other),
+            }
+            assert_eq!(service_client.divide(4, 2).await.unwrap(), 2);
+        }
+
+        #[tokio::test(flavor = "multi_thread")]
+        async fn handler_panics() {
+            let _ = env_logger::try_init();
+            let network = crate::tcp::sim::SimNetwork::new(0);
+            let addr = String::from("calculator");
+            let server = Server::new_with_options(
+                &addr,
+                crate::tcp::server::ServerOptions {
+                    transport: network.node(&addr),
+                    ..Default::default()
+                },
+            );
+            server.register_service(0, &Arc::new(Calculator)).await;
+            Server::listen_and_resume(&server).await;
+            let remote = RPCClient::new_async_with_options(
+                &addr,
+                crate::tcp::client::ClientOptions {
+                    timeout: Duration::from_secs(10),
+                    transport: network.node("calculator-client"),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+            // Answered right away instead of after the client timeout
+            let start = std::time::Instant::now();
+            check_panic(&remote).await;
+            assert!(start.elapsed() < Duration::from_secs(5));
+
+            server.add_interceptor(Arc::new(Refuser));
+            let mut metadata = Metadata::new();
+            metadata.insert("refuse", vec![42u8]);
+            let service_client = AsyncServiceClient::new(0, &remote).with_metadata(metadata);
+            match service_client.divide(4, 2).await {
+                Err(RPCError::RequestError(RPCRequestError::Service { message, payload })) => {
+                    assert_eq!(message, "refused");
+                    assert_eq!(payload, Some(vec![42]));
+                }
+                other => panic!("Expected service error, got {:?}", other),
+            }
+
+            let addr = String::from("127.0.0.1:1304");
+            let server = Server::new(&addr);
+            server.register_service(0, &Arc::new(Calculator)).await;
+            Server::listen_and_resume(&server).await;
+            check_panic(&RPCClient::new_async(&addr).await.unwrap()).await;
+        }
+    }
+
+    mod streaming {
+        use super::*;
+        use futures::stream::BoxStream;
+        use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+        service! {
+            rpc count(n: u64) -> stream u64;
+            rpc naturals() -> stream u64;
+            rpc sums(offset: u64; numbers: stream u64) -> stream u64;
+        }
+
+        #[derive(Default)]
+        struct Numbers {
+            produced: Arc<AtomicUsize>,
+            dropped: Arc<AtomicBool>,
+            sums_dropped: Arc<AtomicBool>,
+        }
+
+        // Tells when the server let go of the stream
+        struct DropFlag(Arc<AtomicBool>);
+
+        impl Drop for DropFlag {
+            fn drop(&mut self) {
+                self.0.store(true, Ordering::SeqCst);
+            }
+        }
+
+        impl Service for Numbers {
+            fn count(&self, n: u64) -> BoxStream<'static, u64> {
+                futures::stream::iter(0..n).boxed()
+            }
+            fn naturals(&self) -> BoxStream<'static, u64> {
+                let produced = self.produced.clone();
+                let flag = DropFlag(self.dropped.clone());
+                futures::stream::iter(0..)
+                    .map(move |i| {
+                        let _ = &flag;
+                        produced.fetch_add(1, Ordering::SeqCst);
+                        i
+                    })
+                    .boxed()
+            }
+            fn sums(
+                &self,
+                offset: u64,
+                numbers: BoxStream<'static, u64>,
+            ) -> BoxStream<'static, u64> {
+                let flag = DropFlag(self.sums_dropped.clone());
+                numbers
+                    .scan(offset, move |sum, n| {
+                        let _ = &flag;
+                        *sum += n;
+                        future::ready(Some(*sum))
+                    })
+                    .boxed()
+            }
+        }
+        dispatch_rpc_service_functions!(Numbers);
+
+        async fn check_streams(client: &Arc<RPCClient>, numbers: &Numbers) {
+            let service_client = AsyncServiceClient::new(0, client);
+            let items: Vec<u64> = service_client
+                .count(200)
+                .await
+                .unwrap()
+                .map(|item| item.unwrap())
+                .collect()
+                .await;
+            assert_eq!(items, (0..200).collect::<Vec<_>>());
+
+            // Only produced as far as consumed, and given up when dropped
+            let naturals = service_client.naturals().await.unwrap();
+            let items: Vec<u64> = naturals.take(10).map(|item| item.unwrap()).collect().await;
+            assert_eq!(items, (0..10).collect::<Vec<_>>());
+            sleep(Duration::from_millis(500)).await;
+            assert!(
+                numbers.produced.load(Ordering::SeqCst)
+                    <= 2 * crate::rpc::stream::STREAM_BATCH_SIZE as usize
+            );
+            assert!(numbers.dropped.load(Ordering::SeqCst));
+
+            // Input only read as far as answers are consumed, and given up with them
+            let input = futures::stream::iter(1..=5u64).boxed();
+            let items: Vec<u64> = service_client
+                .sums(100, input)
+                .await
+                .unwrap()
+                .map(|item| item.unwrap())
+                .collect()
+                .await;
+            assert_eq!(items, vec![101, 103, 106, 110, 115]);
+            let sent = Arc::new(AtomicUsize::new(0));
+            let counted = sent.clone();
+            let input = futures::stream::iter(0..)
+                .map(move |n| {
+                    counted.fetch_add(1, Ordering::SeqCst);
+                    n
+                })
+                .boxed();
+            let sums = service_client.sums(0, input).await.unwrap();
+            let items: Vec<u64> = sums.take(10).map(|item| item.unwrap()).collect().await;
+            assert_eq!(
+                items,
+                (0..10)
+                    .scan(0, |sum, n| {
+                        *sum += n;
+                        Some(*sum)
+                    })
+                    .collect::<Vec<_>>()
+            );
+            sleep(Duration::from_millis(500)).await;
+            assert!(
+                sent.load(Ordering::SeqCst) <= 4 * crate::rpc::stream::STREAM_BATCH_SIZE as usize
+            );
+            assert!(numbers.sums_dropped.load(Ordering::SeqCst));
+        }
+
+        #[tokio::test(flavor = "multi_thread")]
+        async fn server_streams() {
+            let _ = env_logger::try_init();
+            let network = crate::tcp::sim::SimNetwork::new(0);
+            let addr = String::from("numbers");
+            let server = Server::new_with_options(
+                &addr,
+                crate::tcp::server::ServerOptions {
+                    transport: network.node(&addr),
+                    ..Default::default()
+                },
+            );
+            let numbers = Arc::new(Numbers::default());
+            server.register_service(0, &numbers).await;
+            Server::listen_and_resume(&server).await;
+            let remote = RPCClient::new_async_with_options(
+                &addr,
+                crate::tcp::client::ClientOptions {
+                    transport: network.node("numbers-client"),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+            check_streams(&remote, &numbers).await;
+
+            // Through the shortcut
+            let addr = String::from("127.0.0.1:1305");
+            let server = Server::new(&addr);
+            let numbers = Arc::new(Numbers::default());
+            server.register_service(0, &numbers).await;
+            Server::listen_and_resume(&server).await;
+            let local = RPCClient::new_async(&addr).await.unwrap();
+            assert!(get_local(Default::default(), local.server_id, 0)
+                .await
+                .is_some());
+            check_streams(&local, &numbers).await;
+        }
+
+        #[tokio::test(flavor = "multi_thread")]
+        async fn unknown_stream() {
+            let _ = env_logger::try_init();
+            let network = crate::tcp::sim::SimNetwork::new(0);
+            let addr = String::from("no-streams");
+            let server = Server::new_with_options(
+                &addr,
+                crate::tcp::server::ServerOptions {
+                    transport: network.node(&addr),
+                    ..Default::default()
+                },
+            );
+            Server::listen_and_resume(&server).await;
+            let remote = RPCClient::new_async_with_options(
+                &addr,
+                crate::tcp::client::ClientOptions {
+                    transport: network.node("no-streams-client"),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+            let handle = crate::rpc::stream::StreamHandle {
+                stream_id: 42,
+                token: 0,
+            };
+            let mut items =
+                crate::rpc::stream::remote::<u64>(&remote, 0, handle, &Default::default(), None);
+            assert!(matches!(
+                items.next().await,
+                Some(Err(RPCError::RequestError(RPCRequestError::StreamNotFound)))
+            ));
+            assert!(items.next().await.is_none());
+        }
+    }
+
+    mod reflection {
+        use super::*;
+        use crate::rpc::reflection::*;
+        use futures::stream::BoxStream;
+
+        service! {
+            rpc greet(name: String, times: u32) -> Vec<String>;
+            rpc ticks(from: u64) -> stream u64;
+        }
+
+        struct Greeter;
+
+        impl Service for Greeter {
+            fn greet(&self, name: String, times: u32) -> BoxFuture<Vec<String>> {
+                future::ready(vec![name; times as usize]).boxed()
+            }
+            fn ticks(&self, from: u64) -> BoxStream<'static, u64> {
+                futures::stream::iter(from..).boxed()
+            }
+        }
+        dispatch_rpc_service_functions!(Greeter);
+
+        #[tokio::test(flavor = "multi_thread")]
+        async fn list_services() {
+            let _ = env_logger::try_init();
+            let greet = descriptor().function_by_name("greet").unwrap().clone();
+            assert_eq!(
+                greet.args,
+                vec![
+                    (String::from("name"), String::from("String")),
+                    (String::from("times"), String::from("u32"))
+                ]
+            );
+            assert_eq!(greet.output, "Vec<String>");
+            assert!(descriptor().function_by_name("ticks").unwrap().stream);
+
+            let network = crate::tcp::sim::SimNetwork::new(0);
+            let addr = String::from("greeter");
+            let server = Server::new_with_options(
+                &addr,
+                crate::tcp::server::ServerOptions {
+                    transport: network.node(&addr),
+                    ..Default::default()
+                },
+            );
+            server.register_service(7, &Arc::new(Greeter)).await;
+            Server::listen_and_resume(&server).await;
+            let client = RPCClient::new_async_with_options(
+                &addr,
+                crate::tcp::client::ClientOptions {
+                    transport: network.node("greeter-client"),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+            let services = services(&client).await.unwrap();
+            let ids: Vec<u64> = services.iter().map(|info| info.id).collect();
+            assert!(ids.contains(&REFLECTION_SERVICE_ID));
+            assert!(ids.contains(&crate::rpc::stream::STREAM_SERVICE_ID));
+            let greeter = services.iter().find(|info| info.id == 7).unwrap();
+            assert_eq!(greeter.descriptor, Some(descriptor()));
+            assert_eq!(
+                describe_call(&services, 7, greet.id),
+                format!("{}::greet", module_path!())
+            );
+            assert!(describe_call(&services, 8, greet.id).contains("unknown service 8"));
+
+            // Without the types, as debugging tools would call them
+            let args = vec![serde_json::json!("Jack"), serde_json::json!(2)];
+            let res = call_json(&client, 7, &greet, args.clone(), None).await;
+            assert_eq!(res.unwrap(), serde_json::json!(["Jack", "Jack"]));
+            assert!(call_json(&client, 7, &greet, vec![], None).await.is_err());
+            let ticks = descriptor().function_by_name("ticks").unwrap().clone();
+            let items = stream_json(&client, 7, &ticks, vec![serde_json::json!(5)], None)
+                .await
+                .unwrap();
+            let items: Vec<_> = items.take(3).map(|item| item.unwrap()).collect().await;
+            assert_eq!(items, vec![5, 6, 7]);
+        }
+    }
+
+    mod limits {
+        use super::*;
+        use crate::rpc::limits::*;
+
+        service! {
+            rpc slow(millis: u64);
+            rpc fast();
+        }
+
+        struct Sleeper;
+
+        impl Service for Sleeper {
+            fn slow(&self, millis: u64) -> BoxFuture<()> {
+                sleep(Duration::from_millis(millis)).boxed()
+            }
+            fn fast(&self) -> BoxFuture<()> {
+                future::ready(()).boxed()
+            }
+        }
+        dispatch_rpc_service_functions!(Sleeper);
+
+        fn is_overloaded<T: std::fmt::Debug>(res: &Result<T, RPCError>) -> bool {
+            matches!(
+                res,
+                Err(RPCError::RequestError(RPCRequestError::Overloaded { .. }))
+            )
+        }
+
+        #[tokio::test(flavor = "multi_thread")]
+        async fn overloaded_calls() {
+            let _ = env_logger::try_init();
+            let network = crate::tcp::sim::SimNetwork::new(0);
+            let addr = String::from("sleeper");
+            let server = Server::new_with_options(
+                &addr,
+                crate::tcp::server::ServerOptions {
+                    transport: network.node(&addr),
+                    ..Default::default()
+                },
+            );
+            let limits = ServiceLimits::new(Limits::new().max_in_flight(1)).function(
+                ::bifrost_plugins::hash_ident!(fast) as u64,
+                Limits::new().max_rate(1),
+            );
+            server
+                .register_service_with_limits(0, &Arc::new(Sleeper), limits)
+                .await;
+            Server::listen_and_resume(&server).await;
+            let client = RPCClient::new_async_with_options(
+                &addr,
+                crate::tcp::client::ClientOptions {
+                    transport: network.node("sleeper-client"),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+            let service_client = AsyncServiceClient::new(0, &client);
+            let (first, second) = future::join(service_client.slow(500), async {
+                sleep(Duration::from_millis(100)).await;
+                service_client.slow(0).await
+            })
+            .await;
+            assert!(first.is_ok());
+            assert!(is_overloaded(&second));
+            assert!(service_client.slow(0).await.is_ok());
+
+            assert!(service_client.fast().await.is_ok());
+            match service_client.fast().await {
+                Err(RPCError::RequestError(RPCRequestError::Overloaded {
+                    retry_after_ms: Some(wait),
+                })) => assert!(wait <= 1000),
+                other => panic!("Expected rate limit, got {:?}", other),
+            }
+
+            // Limits are gone with the service
+            server.remove_service(0).await;
+            server.register_service(0, &Arc::new(Sleeper)).await;
+            assert!(service_client.fast().await.is_ok());
+            assert!(service_client.fast().await.is_ok());
+        }
+    }
 }
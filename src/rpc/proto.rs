@@ -18,16 +18,32 @@ macro_rules! dispatch_rpc_service_functions {
             {
                 self.inner_dispatch(data)
             }
+            fn descriptor(&self) -> Option<$crate::rpc::reflection::ServiceDescriptor> {
+                Some(descriptor())
+            }
             fn register_shortcut_service(
                 &self,
                 service_ptr: usize,
+                namespace: $crate::tcp::shortcut::Namespace,
                 server_id: u64,
                 service_id: u64,
             ) -> ::std::pin::Pin<Box<dyn Future<Output = ()> + Send>> {
                 async move {
                     let mut cbs = RPC_SVRS.write().await;
                     let service = unsafe { Arc::from_raw(service_ptr as *const $s) };
-                    cbs.insert((server_id, service_id), service);
+                    cbs.insert((namespace, server_id, service_id), service);
+                }
+                .boxed()
+            }
+            fn unregister_shortcut_service(
+                &self,
+                namespace: $crate::tcp::shortcut::Namespace,
+                server_id: u64,
+                service_id: u64,
+            ) -> ::std::pin::Pin<Box<dyn Future<Output = ()> + Send>> {
+                async move {
+                    let mut cbs = RPC_SVRS.write().await;
+                    cbs.remove(&(namespace, server_id, service_id));
                 }
                 .boxed()
             }
@@ -36,20 +52,32 @@ macro_rules! dispatch_rpc_service_functions {
 }
 
 // this macro expansion design took credits from tarpc by Google Inc.
+// Functions are sorted into unary ones, streaming ones, declared as `-> stream T`, and
+// ones also reading a stream of the caller, declared as `(args; input: stream I) -> stream T`.
 #[macro_export]
 macro_rules! service {
     (
-        $(
+        {
             $(#[$attr:meta])*
-            rpc $fn_name:ident( $( $arg:ident : $in_:ty ),* ) $(-> $out:ty)*;
-        )*
+            rpc $fn_name:ident( $( $arg:ident : $in_:ty ),* ; $input:ident : stream $input_ty:ty ) -> stream $out:ty;
+
+            $( $unexpanded:tt )*
+        }
+        [ $( $unary:tt )* ]
+        [ $( $streams:tt )* ]
+        [ $( $bidis:tt )* ]
     ) => {
-        service! {{
-            $(
+        service! {
+            { $( $unexpanded )* }
+            [ $( $unary )* ]
+            [ $( $streams )* ]
+            [
+                $( $bidis )*
+
                 $(#[$attr])*
-                rpc $fn_name( $( $arg : $in_ ),* ) $(-> $out)*;
-            )*
-        }}
+                rpc $fn_name( $( $arg : $in_ ),* ; $input : $input_ty ) -> $out;
+            ]
+        }
     };
     (
         {
@@ -58,15 +86,43 @@ macro_rules! service {
 
             $( $unexpanded:tt )*
         }
-        $( $expanded:tt )*
+        [ $( $unary:tt )* ]
+        [ $( $streams:tt )* ]
+        [ $( $bidis:tt )* ]
     ) => {
         service! {
             { $( $unexpanded )* }
+            [
+                $( $unary )*
+
+                $(#[$attr])*
+                rpc $fn_name( $( $arg : $in_ ),* ) -> ();
+            ]
+            [ $( $streams )* ]
+            [ $( $bidis )* ]
+        }
+    };
+    (
+        {
+            $(#[$attr:meta])*
+            rpc $fn_name:ident( $( $arg:ident : $in_:ty ),* ) -> stream $out:ty;
 
-            $( $expanded )*
+            $( $unexpanded:tt )*
+        }
+        [ $( $unary:tt )* ]
+        [ $( $streams:tt )* ]
+        [ $( $bidis:tt )* ]
+    ) => {
+        service! {
+            { $( $unexpanded )* }
+            [ $( $unary )* ]
+            [
+                $( $streams )*
 
-            $(#[$attr])*
-            rpc $fn_name( $( $arg : $in_ ),* ) -> ();
+                $(#[$attr])*
+                rpc $fn_name( $( $arg : $in_ ),* ) -> $out;
+            ]
+            [ $( $bidis )* ]
         }
     };
     (
@@ -76,23 +132,42 @@ macro_rules! service {
 
             $( $unexpanded:tt )*
         }
-        $( $expanded:tt )*
+        [ $( $unary:tt )* ]
+        [ $( $streams:tt )* ]
+        [ $( $bidis:tt )* ]
     ) => {
         service! {
             { $( $unexpanded )* }
+            [
+                $( $unary )*
 
-            $( $expanded )*
-
-            $(#[$attr])*
-            rpc $fn_name( $( $arg : $in_ ),* ) -> $out;
+                $(#[$attr])*
+                rpc $fn_name( $( $arg : $in_ ),* ) -> $out;
+            ]
+            [ $( $streams )* ]
+            [ $( $bidis )* ]
         }
     };
     (
         {} // all expanded
-        $(
-            $(#[$attr:meta])*
-            rpc $fn_name:ident ( $( $arg:ident : $in_:ty ),* ) -> $out:ty;
-        )*
+        [
+            $(
+                $(#[$attr:meta])*
+                rpc $fn_name:ident ( $( $arg:ident : $in_:ty ),* ) -> $out:ty;
+            )*
+        ]
+        [
+            $(
+                $(#[$sattr:meta])*
+                rpc $sfn_name:ident ( $( $sarg:ident : $sin_:ty ),* ) -> $sout:ty;
+            )*
+        ]
+        [
+            $(
+                $(#[$battr:meta])*
+                rpc $bfn_name:ident ( $( $barg:ident : $bin_:ty ),* ; $binput:ident : $binput_ty:ty ) -> $bout:ty;
+            )*
+        ]
     ) => {
 
         use std::sync::Arc;
@@ -103,7 +178,7 @@ macro_rules! service {
 
         lazy_static! {
             pub static ref RPC_SVRS:
-            async_std::sync::RwLock<::std::collections::BTreeMap<(u64, u64), Arc<dyn Service>>>
+            async_std::sync::RwLock<::std::collections::BTreeMap<($crate::tcp::shortcut::Namespace, u64, u64), Arc<dyn Service>>>
             = async_std::sync::RwLock::new(::std::collections::BTreeMap::new());
         }
 
@@ -112,6 +187,14 @@ macro_rules! service {
                 $(#[$attr])*
                 fn $fn_name<'a>(&'a self, $($arg:$in_),*) -> ::futures::future::BoxFuture<$out>;
            )*
+           $(
+                $(#[$sattr])*
+                fn $sfn_name(&self, $($sarg:$sin_),*) -> ::futures::stream::BoxStream<'static, $sout>;
+           )*
+           $(
+                $(#[$battr])*
+                fn $bfn_name(&self, $($barg:$bin_,)* $binput: ::futures::stream::BoxStream<'static, $binput_ty>) -> ::futures::stream::BoxStream<'static, $bout>;
+           )*
            fn inner_dispatch<'a>(&'a self, data: $crate::bytes::BytesMut) -> Pin<Box<dyn core::future::Future<Output = Result<$crate::bytes::BytesMut, RPCRequestError>> + Send + 'a>> {
                let (func_id, body) = read_u64_head(data);
                async move {
@@ -125,7 +208,34 @@ macro_rules! service {
                         } else {
                             Err(RPCRequestError::BadRequest)
                         }
-                    }),*
+                    })*
+                    $(::bifrost_plugins::hash_ident!($sfn_name) => {
+                        if let Some(data) = $crate::utils::serde::deserialize(body.as_ref()) {
+                            let ($($sarg,)*) : ($($sin_,)*) = data;
+                            // Items are pulled by the caller, see `rpc::stream`
+                            let items = self.$sfn_name($($sarg,)*)
+                                .map(|item| $crate::utils::serde::serialize(&item))
+                                .boxed();
+                            let handle = $crate::rpc::stream::open(items);
+                            Ok($crate::bytes::BytesMut::from($crate::utils::serde::serialize(&handle).as_slice()))
+                        } else {
+                            Err(RPCRequestError::BadRequest)
+                        }
+                    })*
+                    $(::bifrost_plugins::hash_ident!($bfn_name) => {
+                        if let Some(data) = $crate::utils::serde::deserialize(body.as_ref()) {
+                            let ($($barg,)*) : ($($bin_,)*) = data;
+                            // Input is pushed and items are pulled by the caller, see `rpc::stream`
+                            let (input, $binput) = $crate::rpc::stream::input::<$binput_ty>();
+                            let items = self.$bfn_name($($barg,)* $binput)
+                                .map(|item| $crate::utils::serde::serialize(&item))
+                                .boxed();
+                            let handle = $crate::rpc::stream::open_with_input(items, input);
+                            Ok($crate::bytes::BytesMut::from($crate::utils::serde::serialize(&handle).as_slice()))
+                        } else {
+                            Err(RPCRequestError::BadRequest)
+                        }
+                    })*
                     _ => {
                         Err(RPCRequestError::FunctionIdNotFound)
                     }
@@ -134,10 +244,44 @@ macro_rules! service {
            }
         }
 
+        // Names, ids and signatures of the functions, see `rpc::reflection`
         #[allow(dead_code)]
-        pub async fn get_local(server_id: u64, service_id: u64) -> Option<Arc<dyn Service>> {
+        pub fn descriptor() -> $crate::rpc::reflection::ServiceDescriptor {
+            $crate::rpc::reflection::ServiceDescriptor {
+                name: String::from(module_path!()),
+                functions: vec![
+                    $($crate::rpc::reflection::FunctionDescriptor {
+                        name: String::from(stringify!($fn_name)),
+                        id: ::bifrost_plugins::hash_ident!($fn_name) as u64,
+                        args: vec![$((String::from(stringify!($arg)), String::from(stringify!($in_)))),*],
+                        output: String::from(stringify!($out)),
+                        stream: false,
+                        input: None,
+                    },)*
+                    $($crate::rpc::reflection::FunctionDescriptor {
+                        name: String::from(stringify!($sfn_name)),
+                        id: ::bifrost_plugins::hash_ident!($sfn_name) as u64,
+                        args: vec![$((String::from(stringify!($sarg)), String::from(stringify!($sin_)))),*],
+                        output: String::from(stringify!($sout)),
+                        stream: true,
+                        input: None,
+                    },)*
+                    $($crate::rpc::reflection::FunctionDescriptor {
+                        name: String::from(stringify!($bfn_name)),
+                        id: ::bifrost_plugins::hash_ident!($bfn_name) as u64,
+                        args: vec![$((String::from(stringify!($barg)), String::from(stringify!($bin_)))),*],
+                        output: String::from(stringify!($bout)),
+                        stream: true,
+                        input: Some((String::from(stringify!($binput)), String::from(stringify!($binput_ty)))),
+                    },)*
+                ],
+            }
+        }
+
+        #[allow(dead_code)]
+        pub async fn get_local(namespace: $crate::tcp::shortcut::Namespace, server_id: u64, service_id: u64) -> Option<Arc<dyn Service>> {
             let svrs = RPC_SVRS.read().await;
-            match svrs.get(&(server_id, service_id)) {
+            match svrs.get(&(namespace, server_id, service_id)) {
                 Some(s) => Some(s.clone()),
                 _ => None
             }
@@ -147,6 +291,10 @@ macro_rules! service {
         pub struct AsyncServiceClient {
             pub service_id: u64,
             pub client: Arc<RPCClient>,
+            // Deadline for every call, carried to the server so it can give up too
+            pub timeout: Option<::std::time::Duration>,
+            // Sent with every call, see `rpc::context`
+            pub metadata: $crate::rpc::context::Metadata,
         }
 
         #[allow(dead_code)]
@@ -155,20 +303,79 @@ macro_rules! service {
                 #[allow(non_camel_case_types)]
                 $(#[$attr])*
                 pub async fn $fn_name(&self, $($arg:$in_),*) -> Result<$out, RPCError> {
-                    ImmeServiceClient::$fn_name(self.service_id, &self.client, $($arg),*).await
+                    let func_id = ::bifrost_plugins::hash_ident!($fn_name) as u64;
+                    if let Some(local) = get_local(self.client.namespace, self.client.server_id, self.service_id).await {
+                        call_local(&self.client, self.service_id, func_id, &self.metadata, self.timeout, async move {
+                            local.$fn_name($($arg),*).await
+                        }).await
+                    } else {
+                        call_remote(&self.client, self.service_id, func_id, &($($arg,)*), &self.metadata, self.timeout).await
+                    }
+                }
+           )*
+           $(
+                #[allow(non_camel_case_types)]
+                $(#[$sattr])*
+                pub async fn $sfn_name(&self, $($sarg:$sin_),*) -> Result<::futures::stream::BoxStream<'static, Result<$sout, RPCError>>, RPCError> {
+                    let func_id = ::bifrost_plugins::hash_ident!($sfn_name) as u64;
+                    if let Some(local) = get_local(self.client.namespace, self.client.server_id, self.service_id).await {
+                        call_local(&self.client, self.service_id, func_id, &self.metadata, self.timeout, async move {
+                            local.$sfn_name($($sarg),*).map(Ok).boxed()
+                        }).await
+                    } else {
+                        let handle = call_remote(&self.client, self.service_id, func_id, &($($sarg,)*), &self.metadata, self.timeout).await?;
+                        Ok($crate::rpc::stream::remote(&self.client, self.service_id, handle, &self.metadata, self.timeout))
+                    }
+                }
+           )*
+           $(
+                #[allow(non_camel_case_types)]
+                $(#[$battr])*
+                pub async fn $bfn_name(&self, $($barg:$bin_,)* $binput: ::futures::stream::BoxStream<'static, $binput_ty>) -> Result<::futures::stream::BoxStream<'static, Result<$bout, RPCError>>, RPCError> {
+                    let func_id = ::bifrost_plugins::hash_ident!($bfn_name) as u64;
+                    if let Some(local) = get_local(self.client.namespace, self.client.server_id, self.service_id).await {
+                        call_local(&self.client, self.service_id, func_id, &self.metadata, self.timeout, async move {
+                            local.$bfn_name($($barg,)* $binput).map(Ok).boxed()
+                        }).await
+                    } else {
+                        let handle = call_remote(&self.client, self.service_id, func_id, &($($barg,)*), &self.metadata, self.timeout).await?;
+                        Ok($crate::rpc::stream::remote_with_input(&self.client, self.service_id, handle, $binput, &self.metadata, self.timeout))
+                    }
                 }
            )*
            pub fn new(service_id: u64, client: &Arc<RPCClient>) -> Arc<AsyncServiceClient> {
                 Arc::new(AsyncServiceClient{
                     service_id: service_id,
-                    client: client.clone()
+                    client: client.clone(),
+                    timeout: None,
+                    metadata: Default::default()
+                })
+           }
+           // Same service and client, calls give up after `timeout`
+           pub fn with_timeout(&self, timeout: ::std::time::Duration) -> Arc<AsyncServiceClient> {
+                Arc::new(AsyncServiceClient{
+                    service_id: self.service_id,
+                    client: self.client.clone(),
+                    timeout: Some(timeout),
+                    metadata: self.metadata.clone()
+                })
+           }
+           // Same service and client, calls carry `metadata`
+           pub fn with_metadata(&self, metadata: $crate::rpc::context::Metadata) -> Arc<AsyncServiceClient> {
+                Arc::new(AsyncServiceClient{
+                    service_id: self.service_id,
+                    client: self.client.clone(),
+                    timeout: self.timeout,
+                    metadata
                 })
            }
            pub fn server_id(&self) -> u64 {
                self.client.server_id
            }
         }
+        #[allow(dead_code)]
         pub struct ImmeServiceClient;
+        #[allow(dead_code)]
         impl ImmeServiceClient {
             $(
                 $(#[$attr])*
@@ -176,27 +383,59 @@ macro_rules! service {
                 /// Some applications highly depend on RPC shortcut to achieve performance advantages.
                 /// Cloning for shortcut will significantly increase overhead. Eg. Hivemind immutable queue
                 pub async fn $fn_name(service_id: u64, client: &Arc<RPCClient>, $($arg:$in_),*) -> Result<$out, RPCError> {
-                    if let Some(ref local) = get_local(client.server_id, service_id).await {
-                        Ok(local.$fn_name($($arg),*).await)
+                    let func_id = ::bifrost_plugins::hash_ident!($fn_name) as u64;
+                    let metadata = Default::default();
+                    if let Some(local) = get_local(client.namespace, client.server_id, service_id).await {
+                        call_local(client, service_id, func_id, &metadata, None, async move {
+                            local.$fn_name($($arg),*).await
+                        }).await
                     } else {
-                        let req_data = ($($arg,)*);
-                        let req_data_bytes = $crate::bytes::BytesMut::from($crate::utils::serde::serialize(&req_data).as_slice());
-                        let req_bytes = prepend_u64(::bifrost_plugins::hash_ident!($fn_name) as u64, req_data_bytes);
-                        let res_bytes = RPCClient::send_async(Pin::new(&*client), service_id, req_bytes).await;
-                        if let Ok(res_bytes) = res_bytes {
-                            if let Some(data) = $crate::utils::serde::deserialize(&res_bytes) {
-                                Ok(data)
-                            } else {
-                                Err(RPCError::ClientCannotDecodeResponse)
-                            }
-                        } else {
-                            Err(res_bytes.err().unwrap())
-                        }
+                        call_remote(client, service_id, func_id, &($($arg,)*), &metadata, None).await
+                    }
+                }
+           )*
+           $(
+                $(#[$sattr])*
+                pub async fn $sfn_name(service_id: u64, client: &Arc<RPCClient>, $($sarg:$sin_),*) -> Result<::futures::stream::BoxStream<'static, Result<$sout, RPCError>>, RPCError> {
+                    let func_id = ::bifrost_plugins::hash_ident!($sfn_name) as u64;
+                    let metadata = Default::default();
+                    if let Some(local) = get_local(client.namespace, client.server_id, service_id).await {
+                        call_local(client, service_id, func_id, &metadata, None, async move {
+                            local.$sfn_name($($sarg),*).map(Ok).boxed()
+                        }).await
+                    } else {
+                        let handle = call_remote(client, service_id, func_id, &($($sarg,)*), &metadata, None).await?;
+                        Ok($crate::rpc::stream::remote(client, service_id, handle, &metadata, None))
+                    }
+                }
+           )*
+           $(
+                $(#[$battr])*
+                pub async fn $bfn_name(service_id: u64, client: &Arc<RPCClient>, $($barg:$bin_,)* $binput: ::futures::stream::BoxStream<'static, $binput_ty>) -> Result<::futures::stream::BoxStream<'static, Result<$bout, RPCError>>, RPCError> {
+                    let func_id = ::bifrost_plugins::hash_ident!($bfn_name) as u64;
+                    let metadata = Default::default();
+                    if let Some(local) = get_local(client.namespace, client.server_id, service_id).await {
+                        call_local(client, service_id, func_id, &metadata, None, async move {
+                            local.$bfn_name($($barg,)* $binput).map(Ok).boxed()
+                        }).await
+                    } else {
+                        let handle = call_remote(client, service_id, func_id, &($($barg,)*), &metadata, None).await?;
+                        Ok($crate::rpc::stream::remote_with_input(client, service_id, handle, $binput, &metadata, None))
                     }
                 }
            )*
         }
-    }
+    };
+    (
+        $( $tokens:tt )*
+    ) => {
+        service! {
+            { $( $tokens )* }
+            []
+            []
+            []
+        }
+    };
 }
 
 mod syntax_test {
@@ -204,6 +443,9 @@ mod syntax_test {
         rpc test(a: u32, b: u32) -> bool;
         rpc test2(a: u32);
         rpc test3(a: u32, b: u32, c: u32, d: u32);
+        rpc test4(a: u32) -> stream u64;
+        rpc test5(a: u32, b: u32) -> Vec<u8>;
+        rpc test6(a: u32; b: stream String) -> stream u64;
     }
 }
 
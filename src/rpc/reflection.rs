@@ -0,0 +1,169 @@
+// Names behind the service and function ids. Every `service!` describes its functions and
+// every `rpc::Server` lists its services through a built-in reflection service, so tools
+// can tell what is being called.
+
+use super::context::Metadata;
+use super::{call_remote, RPCClient, RPCError, RPCRequestError, RPCService};
+use bifrost_plugins::hash_ident;
+use futures::prelude::*;
+use futures::stream::BoxStream;
+use lightning::map::*;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+pub static REFLECTION_SERVICE_ID: u64 = hash_ident!(BIFROST_RPC_REFLECTION) as u64;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FunctionDescriptor {
+    pub name: String,
+    pub id: u64,
+    // Argument names and types, types as written in `service!`
+    pub args: Vec<(String, String)>,
+    pub output: String,
+    // Declared as `-> stream T`, `output` is the item type
+    pub stream: bool,
+    // Name and item type of the stream read from the caller, if declared
+    pub input: Option<(String, String)>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ServiceDescriptor {
+    // Module path of the `service!`
+    pub name: String,
+    pub functions: Vec<FunctionDescriptor>,
+}
+
+impl ServiceDescriptor {
+    pub fn function(&self, id: u64) -> Option<&FunctionDescriptor> {
+        self.functions.iter().find(|f| f.id == id)
+    }
+
+    pub fn function_by_name(&self, name: &str) -> Option<&FunctionDescriptor> {
+        self.functions.iter().find(|f| f.name == name)
+    }
+}
+
+// A service registered on a server, services not made by `service!` have no descriptor
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ServiceInfo {
+    pub id: u64,
+    pub descriptor: Option<ServiceDescriptor>,
+}
+
+pub mod service {
+    use super::*;
+
+    service! {
+        rpc services() -> Vec<ServiceInfo>;
+        rpc service(service_id: u64) -> Option<ServiceInfo>;
+    }
+
+    // Refers to the services of its server, which own it
+    pub struct ReflectionService {
+        pub(crate) services: Weak<ObjectMap<Arc<dyn RPCService>>>,
+    }
+
+    impl ReflectionService {
+        fn info(service_id: u64, service: &Arc<dyn RPCService>) -> ServiceInfo {
+            ServiceInfo {
+                id: service_id,
+                descriptor: service.descriptor(),
+            }
+        }
+    }
+
+    impl Service for ReflectionService {
+        fn services(&self) -> BoxFuture<Vec<ServiceInfo>> {
+            let mut services = match self.services.upgrade() {
+                Some(services) => services
+                    .entries()
+                    .iter()
+                    .map(|(id, service)| Self::info(*id as u64, service))
+                    .collect(),
+                None => vec![],
+            };
+            services.sort_by_key(|info| info.id);
+            future::ready(services).boxed()
+        }
+        fn service(&self, service_id: u64) -> BoxFuture<Option<ServiceInfo>> {
+            let info = self
+                .services
+                .upgrade()
+                .and_then(|services| services.get(&(service_id as usize)))
+                .map(|service| Self::info(service_id, &service));
+            future::ready(info).boxed()
+        }
+    }
+    dispatch_rpc_service_functions!(ReflectionService);
+}
+
+// Services of the server `client` is connected to
+pub async fn services(client: &Arc<RPCClient>) -> Result<Vec<ServiceInfo>, RPCError> {
+    service::AsyncServiceClient::new(REFLECTION_SERVICE_ID, client)
+        .services()
+        .await
+}
+
+fn json_args(
+    function: &FunctionDescriptor,
+    args: Vec<Value>,
+    stream: bool,
+) -> Result<Value, RPCError> {
+    // Streams of the caller cannot be given as JSON
+    if args.len() != function.args.len() || function.stream != stream || function.input.is_some() {
+        return Err(RPCError::RequestError(RPCRequestError::BadRequest));
+    }
+    if args.is_empty() {
+        Ok(Value::Null)
+    } else {
+        Ok(Value::Array(args))
+    }
+}
+
+// Calls `function` with arguments and result as JSON, for tools that only know the
+// descriptor. Arguments are serialized as the tuple `service!` clients would send, which
+// is unit without arguments. Only works with codecs that are `Codec::self_describing`.
+pub async fn call_json(
+    client: &Arc<RPCClient>,
+    service_id: u64,
+    function: &FunctionDescriptor,
+    args: Vec<Value>,
+    timeout: Option<Duration>,
+) -> Result<Value, RPCError> {
+    let args = json_args(function, args, false)?;
+    let metadata = Metadata::new();
+    call_remote(client, service_id, function.id, &args, &metadata, timeout).await
+}
+
+// Same as `call_json` for functions declared with `-> stream T`
+pub async fn stream_json(
+    client: &Arc<RPCClient>,
+    service_id: u64,
+    function: &FunctionDescriptor,
+    args: Vec<Value>,
+    timeout: Option<Duration>,
+) -> Result<BoxStream<'static, Result<Value, RPCError>>, RPCError> {
+    let args = json_args(function, args, true)?;
+    let metadata = Metadata::new();
+    let handle = call_remote(client, service_id, function.id, &args, &metadata, timeout).await?;
+    Ok(super::stream::remote(
+        client, service_id, handle, &metadata, timeout,
+    ))
+}
+
+// Service and function names for the ids, for error messages and debugging
+pub fn describe_call(services: &[ServiceInfo], service_id: u64, func_id: u64) -> String {
+    let descriptor = services
+        .iter()
+        .find(|info| info.id == service_id)
+        .and_then(|info| info.descriptor.as_ref());
+    match descriptor {
+        Some(descriptor) => match descriptor.function(func_id) {
+            Some(function) => format!("{}::{}", descriptor.name, function.name),
+            None => format!("{}::<unknown function {}>", descriptor.name, func_id),
+        },
+        None => format!("<unknown service {}>::<function {}>", service_id, func_id),
+    }
+}
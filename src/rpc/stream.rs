@@ -0,0 +1,666 @@
+// Server streams of `service!` functions declared with `-> stream T`. The server keeps the
+// stream and callers pull batches of items from it, so items are only produced as fast as
+// they are consumed. Dropping the client side cancels the stream on the server, streams
+// left idle are swept by the server periodically and all are dropped on its shutdown.
+// Calls through the shortcut hand out the stream itself and never come here.
+//
+// Functions declared with an input stream, `rpc f(args; input: stream I) -> stream T`, also
+// read items of the caller. Callers push them in batches, pushes only go through as far as
+// the function reads them. Streams only going from callers to the server are functions of
+// this kind answering with a single item.
+//
+// Streams are kept for the server and service that opened them. Callers get a token along
+// with the stream id, pulls and cancels without it are answered as for unknown streams.
+
+use super::context::{self, Metadata};
+use super::{RPCClient, RPCError, RPCRequestError};
+use bifrost_plugins::hash_ident;
+use futures::prelude::*;
+use futures::stream::BoxStream;
+use parking_lot::Mutex;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+pub static STREAM_SERVICE_ID: u64 = hash_ident!(BIFROST_RPC_STREAMS) as u64;
+
+// Streams not pulled for this long are assumed to be abandoned by their callers
+pub const STREAM_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+// How often servers look for idle streams
+pub const STREAM_SWEEP_INTERVAL: Duration = Duration::from_secs(15);
+// Items asked for in one pull, and sent in one push
+pub const STREAM_BATCH_SIZE: u32 = 64;
+
+struct Pulling {
+    items: BoxStream<'static, Vec<u8>>,
+    // Too large for the last batch, goes first in the next one
+    pending: Option<Vec<u8>>,
+}
+
+// Answer of the server to calls of stream functions
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct StreamHandle {
+    pub stream_id: u64,
+    // Only known to the caller, proves the stream is theirs
+    pub token: u64,
+}
+
+// Server and service the stream was opened on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct StreamKey {
+    server_id: u64,
+    service_id: u64,
+    stream_id: u64,
+}
+
+struct OpenStream {
+    token: u64,
+    pulling: tokio::sync::Mutex<Pulling>,
+    // Items of the caller for the function, gone once the caller has no more
+    input: tokio::sync::Mutex<Option<mpsc::Sender<Vec<u8>>>>,
+    last_pulled: Mutex<Instant>,
+}
+
+// Where items pushed by the caller go, see `input`
+pub struct Input(mpsc::Sender<Vec<u8>>);
+
+// Input stream for a function reading items of the caller, and the end to open its
+// stream with. The stream ends at the first item that cannot be decoded.
+pub fn input<T>() -> (Input, BoxStream<'static, T>)
+where
+    T: DeserializeOwned + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel::<Vec<u8>>(STREAM_BATCH_SIZE as usize);
+    let items = stream::unfold(rx, |mut rx| async move {
+        let item = rx.recv().await?;
+        Some((crate::utils::serde::deserialize::<T>(&item), rx))
+    })
+    .take_while(|item| future::ready(item.is_some()))
+    .filter_map(future::ready)
+    .boxed();
+    (Input(tx), items)
+}
+
+lazy_static! {
+    static ref STREAMS: Mutex<HashMap<StreamKey, Arc<OpenStream>>> = Mutex::new(HashMap::new());
+}
+
+// The stream if the caller holds its token
+fn find(key: &StreamKey, token: u64) -> Option<Arc<OpenStream>> {
+    STREAMS
+        .lock()
+        .get(key)
+        .filter(|stream| stream.token == token)
+        .cloned()
+}
+
+fn remove(key: &StreamKey, token: u64) {
+    let mut streams = STREAMS.lock();
+    if streams
+        .get(key)
+        .map_or(false, |stream| stream.token == token)
+    {
+        streams.remove(key);
+    }
+}
+
+// Key of `stream_id` of `service_id` on the server handling the request
+fn request_key(service_id: u64, stream_id: u64) -> StreamKey {
+    StreamKey {
+        server_id: context::current().map_or(0, |ctx| ctx.server_id),
+        service_id,
+        stream_id,
+    }
+}
+
+fn sweep(server_id: u64, idle_timeout: Duration) {
+    STREAMS.lock().retain(|key, stream| {
+        key.server_id != server_id || stream.last_pulled.lock().elapsed() < idle_timeout
+    });
+}
+
+// Run by every listening server until it shuts down
+pub(crate) async fn sweep_idle(server_id: u64) {
+    let mut interval = tokio::time::interval(STREAM_SWEEP_INTERVAL);
+    loop {
+        interval.tick().await;
+        sweep(server_id, STREAM_IDLE_TIMEOUT);
+    }
+}
+
+// Drops all streams of the server, their callers get `StreamNotFound`
+pub(crate) fn close_server(server_id: u64) {
+    STREAMS.lock().retain(|key, _| key.server_id != server_id);
+}
+
+// Keeps serialized `items` for callers to pull, returns the handle they pull with. The
+// stream belongs to the server and service handling the request.
+pub fn open(items: BoxStream<'static, Vec<u8>>) -> StreamHandle {
+    open_stream(items, None)
+}
+
+// Same as `open`, callers also push items into `input`
+pub fn open_with_input(items: BoxStream<'static, Vec<u8>>, input: Input) -> StreamHandle {
+    open_stream(items, Some(input.0))
+}
+
+fn open_stream(
+    items: BoxStream<'static, Vec<u8>>,
+    input: Option<mpsc::Sender<Vec<u8>>>,
+) -> StreamHandle {
+    let ctx = context::current();
+    let token = rand::random::<u64>();
+    let stream = Arc::new(OpenStream {
+        token,
+        pulling: tokio::sync::Mutex::new(Pulling {
+            items,
+            pending: None,
+        }),
+        input: tokio::sync::Mutex::new(input),
+        last_pulled: Mutex::new(Instant::now()),
+    });
+    let mut streams = STREAMS.lock();
+    loop {
+        let key = StreamKey {
+            server_id: ctx.as_ref().map_or(0, |ctx| ctx.server_id),
+            service_id: ctx.as_ref().map_or(0, |ctx| ctx.service_id),
+            stream_id: rand::random::<u64>(),
+        };
+        if !streams.contains_key(&key) {
+            streams.insert(key, stream);
+            return StreamHandle {
+                stream_id: key.stream_id,
+                token,
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) fn is_open(stream_id: u64) -> bool {
+    STREAMS.lock().keys().any(|key| key.stream_id == stream_id)
+}
+
+// Streams panicking while pulled are not pulled again
+struct PanicGuard(StreamKey, u64);
+
+impl Drop for PanicGuard {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            remove(&self.0, self.1);
+        }
+    }
+}
+
+// Waits up to `wait` for the first item, then takes whatever else is ready within the
+// limits. `None` if the stream is unknown, otherwise the items and whether the stream ended.
+async fn pull_items(
+    key: StreamKey,
+    token: u64,
+    max_items: u32,
+    max_bytes: u64,
+    wait: Duration,
+) -> Option<(Vec<Vec<u8>>, bool)> {
+    let stream = find(&key, token)?;
+    let _guard = PanicGuard(key, token);
+    let mut pulling = stream.pulling.lock().await;
+    let mut items = vec![];
+    let mut bytes = 0;
+    let mut ended = false;
+    match pulling.pending.take() {
+        Some(item) => {
+            bytes += item.len() as u64;
+            items.push(item);
+        }
+        None => match tokio::time::timeout(wait, pulling.items.next()).await {
+            Ok(Some(item)) => {
+                bytes += item.len() as u64;
+                items.push(item);
+            }
+            Ok(None) => ended = true,
+            Err(_) => {}
+        },
+    }
+    while !ended && !items.is_empty() && (items.len() as u32) < max_items {
+        match pulling.items.next().now_or_never() {
+            Some(Some(item)) => {
+                if bytes + item.len() as u64 > max_bytes {
+                    pulling.pending = Some(item);
+                    break;
+                }
+                bytes += item.len() as u64;
+                items.push(item);
+            }
+            Some(None) => ended = true,
+            None => break,
+        }
+    }
+    *stream.last_pulled.lock() = Instant::now();
+    if ended {
+        remove(&key, token);
+    }
+    Some((items, ended))
+}
+
+// Feeds `items` to the function, waiting up to `wait` for it to read them. `None` if the
+// stream is unknown or takes no input, otherwise how many of the items went in. Once the
+// function stops reading, items are taken and thrown away.
+async fn push_items(
+    key: StreamKey,
+    token: u64,
+    items: Vec<Vec<u8>>,
+    ended: bool,
+    wait: Duration,
+) -> Option<u32> {
+    let stream = find(&key, token)?;
+    let mut input = stream.input.lock().await;
+    let sender = input.as_ref()?;
+    let deadline = tokio::time::Instant::now() + wait;
+    let count = items.len() as u32;
+    let mut taken = 0;
+    for item in items {
+        match tokio::time::timeout_at(deadline, sender.reserve()).await {
+            Ok(Ok(permit)) => permit.send(item),
+            Ok(Err(_)) => {}
+            Err(_) => break,
+        }
+        taken += 1;
+    }
+    if ended && taken == count {
+        *input = None;
+    }
+    *stream.last_pulled.lock() = Instant::now();
+    Some(taken)
+}
+
+pub mod service {
+    use super::*;
+
+    service! {
+        rpc pull(service_id: u64, handle: StreamHandle, max_items: u32, max_bytes: u64, wait_ms: u64) -> Option<(Vec<Vec<u8>>, bool)>;
+        rpc push(service_id: u64, handle: StreamHandle, items: Vec<Vec<u8>>, ended: bool, wait_ms: u64) -> Option<u32>;
+        rpc cancel(service_id: u64, handle: StreamHandle);
+    }
+
+    pub struct StreamService;
+
+    impl Service for StreamService {
+        fn pull(
+            &self,
+            service_id: u64,
+            handle: StreamHandle,
+            max_items: u32,
+            max_bytes: u64,
+            wait_ms: u64,
+        ) -> BoxFuture<Option<(Vec<Vec<u8>>, bool)>> {
+            pull_items(
+                request_key(service_id, handle.stream_id),
+                handle.token,
+                max_items,
+                max_bytes,
+                Duration::from_millis(wait_ms),
+            )
+            .boxed()
+        }
+        fn push(
+            &self,
+            service_id: u64,
+            handle: StreamHandle,
+            items: Vec<Vec<u8>>,
+            ended: bool,
+            wait_ms: u64,
+        ) -> BoxFuture<Option<u32>> {
+            push_items(
+                request_key(service_id, handle.stream_id),
+                handle.token,
+                items,
+                ended,
+                Duration::from_millis(wait_ms),
+            )
+            .boxed()
+        }
+        fn cancel(&self, service_id: u64, handle: StreamHandle) -> BoxFuture<()> {
+            remove(&request_key(service_id, handle.stream_id), handle.token);
+            future::ready(()).boxed()
+        }
+    }
+    dispatch_rpc_service_functions!(StreamService);
+}
+
+struct RemoteStream {
+    service: Arc<service::AsyncServiceClient>,
+    // Of the function that opened the stream
+    service_id: u64,
+    handle: StreamHandle,
+    max_bytes: u64,
+    wait_ms: u64,
+    buffer: VecDeque<Vec<u8>>,
+    // The server has no more items or does not know the stream
+    finished: bool,
+    // Pulling failed, the stream ends after the error
+    failed: bool,
+    // Pushes the input of the caller, if the function takes one
+    pusher: Option<JoinHandle<()>>,
+}
+
+impl Drop for RemoteStream {
+    fn drop(&mut self) {
+        if let Some(pusher) = self.pusher.take() {
+            pusher.abort();
+        }
+        if self.finished {
+            return;
+        }
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            let service = self.service.clone();
+            let (service_id, handle) = (self.service_id, self.handle);
+            runtime.spawn(async move {
+                let _ = service.cancel(service_id, handle).await;
+            });
+        }
+    }
+}
+
+impl RemoteStream {
+    fn new(
+        client: &Arc<RPCClient>,
+        service_id: u64,
+        handle: StreamHandle,
+        metadata: &Metadata,
+        timeout: Option<Duration>,
+    ) -> Self {
+        let mut service = service::AsyncServiceClient::new(STREAM_SERVICE_ID, client)
+            .with_metadata(metadata.clone());
+        if let Some(timeout) = timeout {
+            service = service.with_timeout(timeout);
+        }
+        // Pulls and pushes wait no longer than half the time they are given
+        let wait = timeout.unwrap_or(client.options.timeout) / 2;
+        RemoteStream {
+            service,
+            service_id,
+            handle,
+            max_bytes: (client.options.max_frame_size / 2) as u64,
+            wait_ms: wait.as_millis() as u64,
+            buffer: VecDeque::new(),
+            finished: false,
+            failed: false,
+            pusher: None,
+        }
+    }
+
+    fn items<T>(self) -> BoxStream<'static, Result<T, RPCError>>
+    where
+        T: DeserializeOwned + Send + 'static,
+    {
+        stream::unfold(self, |mut state| async move {
+            loop {
+                if let Some(item) = state.buffer.pop_front() {
+                    let item = crate::utils::serde::deserialize(&item)
+                        .ok_or(RPCError::ClientCannotDecodeResponse);
+                    return Some((item, state));
+                }
+                if state.finished || state.failed {
+                    return None;
+                }
+                let pulled = state
+                    .service
+                    .pull(
+                        state.service_id,
+                        state.handle,
+                        STREAM_BATCH_SIZE,
+                        state.max_bytes,
+                        state.wait_ms,
+                    )
+                    .await;
+                match pulled {
+                    Ok(Some((items, ended))) => {
+                        state.buffer.extend(items);
+                        state.finished = ended;
+                    }
+                    Ok(None) => {
+                        state.finished = true;
+                        let e = RPCError::RequestError(RPCRequestError::StreamNotFound);
+                        return Some((Err(e), state));
+                    }
+                    Err(e) => {
+                        state.failed = true;
+                        return Some((Err(e), state));
+                    }
+                }
+            }
+        })
+        .boxed()
+    }
+}
+
+// Sends `input` in batches until it ends or the server stops taking it. Failed pushes
+// cancel the stream, so pulling it fails too.
+async fn push_input<I>(
+    service: Arc<service::AsyncServiceClient>,
+    service_id: u64,
+    handle: StreamHandle,
+    mut input: BoxStream<'static, I>,
+    max_bytes: u64,
+    wait_ms: u64,
+) where
+    I: Serialize,
+{
+    // Too large for the last batch, goes first in the next one
+    let mut pending = None;
+    let mut ended = false;
+    while !ended {
+        let mut items = vec![];
+        let mut bytes = 0;
+        let first = match pending.take() {
+            Some(item) => Some(item),
+            None => input
+                .next()
+                .await
+                .map(|item| crate::utils::serde::serialize(&item)),
+        };
+        match first {
+            Some(item) => {
+                bytes += item.len() as u64;
+                items.push(item);
+            }
+            None => ended = true,
+        }
+        while !ended && (items.len() as u32) < STREAM_BATCH_SIZE {
+            match input.next().now_or_never() {
+                Some(Some(item)) => {
+                    let item = crate::utils::serde::serialize(&item);
+                    if bytes + item.len() as u64 > max_bytes {
+                        pending = Some(item);
+                        break;
+                    }
+                    bytes += item.len() as u64;
+                    items.push(item);
+                }
+                Some(None) => ended = true,
+                None => break,
+            }
+        }
+        // Pushed again until the function took all of them
+        loop {
+            match service
+                .push(service_id, handle, items.clone(), ended, wait_ms)
+                .await
+            {
+                Ok(Some(taken)) => {
+                    items.drain(..taken as usize);
+                }
+                Ok(None) => return,
+                Err(_) => {
+                    let _ = service.cancel(service_id, handle).await;
+                    return;
+                }
+            }
+            if items.is_empty() {
+                break;
+            }
+        }
+    }
+}
+
+// Items of the stream `handle` opened by `service_id` on the server `client` is connected to
+pub fn remote<T>(
+    client: &Arc<RPCClient>,
+    service_id: u64,
+    handle: StreamHandle,
+    metadata: &Metadata,
+    timeout: Option<Duration>,
+) -> BoxStream<'static, Result<T, RPCError>>
+where
+    T: DeserializeOwned + Send + 'static,
+{
+    RemoteStream::new(client, service_id, handle, metadata, timeout).items()
+}
+
+// Same as `remote` for functions reading items of the caller, `input` is pushed to the
+// server as long as the returned stream is kept
+pub fn remote_with_input<I, T>(
+    client: &Arc<RPCClient>,
+    service_id: u64,
+    handle: StreamHandle,
+    input: BoxStream<'static, I>,
+    metadata: &Metadata,
+    timeout: Option<Duration>,
+) -> BoxStream<'static, Result<T, RPCError>>
+where
+    I: Serialize + Send + 'static,
+    T: DeserializeOwned + Send + 'static,
+{
+    let mut state = RemoteStream::new(client, service_id, handle, metadata, timeout);
+    state.pusher = Some(tokio::spawn(push_input(
+        state.service.clone(),
+        service_id,
+        handle,
+        input,
+        state.max_bytes,
+        state.wait_ms,
+    )));
+    state.items()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn pull_batches() {
+        let items = stream::iter(0..10u8).map(|i| vec![i; 10]).boxed();
+        let handle = open(items);
+        let (key, token) = (request_key(0, handle.stream_id), handle.token);
+        let wait = Duration::from_millis(100);
+        // Limited by item count, then by size
+        let (items, ended) = pull_items(key, token, 3, 1024, wait).await.unwrap();
+        assert_eq!((items.len(), ended), (3, false));
+        let (items, ended) = pull_items(key, token, 10, 25, wait).await.unwrap();
+        assert_eq!((items.len(), ended), (2, false));
+        let (items, ended) = pull_items(key, token, 10, 1024, wait).await.unwrap();
+        assert_eq!(items[0], vec![5u8; 10]);
+        assert_eq!((items.len(), ended), (5, true));
+        assert!(!is_open(handle.stream_id));
+        assert!(pull_items(key, token, 10, 1024, wait).await.is_none());
+
+        // Nothing ready within the wait
+        let handle = open(stream::pending().boxed());
+        let key = request_key(0, handle.stream_id);
+        let (items, ended) = pull_items(key, handle.token, 10, 1024, wait).await.unwrap();
+        assert!(items.is_empty() && !ended);
+        assert!(is_open(handle.stream_id));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn push_batches() {
+        let (input, numbers) = input::<u64>();
+        let items = numbers.map(|n| crate::utils::serde::serialize(&n)).boxed();
+        let handle = open_with_input(items, input);
+        let (key, token) = (request_key(0, handle.stream_id), handle.token);
+        let wait = Duration::from_millis(100);
+        let pushed: Vec<_> = (0..100u64)
+            .map(|n| crate::utils::serde::serialize(&n))
+            .collect();
+        // Only as many as the function has room for until it reads them
+        let taken = push_items(key, token, pushed.clone(), true, wait)
+            .await
+            .unwrap();
+        assert_eq!(taken, STREAM_BATCH_SIZE);
+        let (items, ended) = pull_items(key, token, 100, 1024, wait).await.unwrap();
+        assert_eq!((items.len(), ended), (STREAM_BATCH_SIZE as usize, false));
+        let rest = pushed[taken as usize..].to_vec();
+        let taken = push_items(key, token, rest, true, wait).await.unwrap();
+        assert_eq!(taken, 100 - STREAM_BATCH_SIZE);
+        let mut items = vec![];
+        loop {
+            let (pulled, ended) = pull_items(key, token, 100, 1024, wait).await.unwrap();
+            items.extend(pulled);
+            if ended {
+                break;
+            }
+        }
+        assert_eq!(items, pushed[STREAM_BATCH_SIZE as usize..]);
+
+        // Streams without input take nothing
+        let handle = open(stream::pending().boxed());
+        let key = request_key(0, handle.stream_id);
+        assert!(push_items(key, handle.token, vec![], true, wait)
+            .await
+            .is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn ownership() {
+        let ctx = context::RequestContext::new(1, 2, 0, Metadata::new(), false);
+        let items = stream::iter(0..10u8).map(|i| vec![i]).boxed();
+        let handle = context::scope(ctx, async { open(items) }).await;
+        let key = |server_id, service_id| StreamKey {
+            server_id,
+            service_id,
+            stream_id: handle.stream_id,
+        };
+        let wait = Duration::from_millis(100);
+        // Unknown on other servers and services, or without the token
+        assert!(pull_items(key(3, 2), handle.token, 10, 1024, wait)
+            .await
+            .is_none());
+        assert!(pull_items(key(1, 3), handle.token, 10, 1024, wait)
+            .await
+            .is_none());
+        assert!(pull_items(key(1, 2), handle.token ^ 1, 10, 1024, wait)
+            .await
+            .is_none());
+        remove(&key(1, 2), handle.token ^ 1);
+        assert!(is_open(handle.stream_id));
+        let (items, _) = pull_items(key(1, 2), handle.token, 3, 1024, wait)
+            .await
+            .unwrap();
+        assert_eq!(items.len(), 3);
+        remove(&key(1, 2), handle.token);
+        assert!(!is_open(handle.stream_id));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn sweep_servers() {
+        let open_on = |server_id| {
+            let ctx = context::RequestContext::new(server_id, 0, 0, Metadata::new(), false);
+            context::scope(ctx, async { open(stream::pending().boxed()).stream_id })
+        };
+        let (first, second) = (rand::random::<u64>(), rand::random::<u64>());
+        let idle = open_on(first).await;
+        let other = open_on(second).await;
+        // Only streams of the server go, and only those idle for long enough
+        sweep(first, Duration::from_secs(60));
+        assert!(is_open(idle));
+        sweep(first, Duration::from_secs(0));
+        assert!(!is_open(idle));
+        assert!(is_open(other));
+        close_server(second);
+        assert!(!is_open(other));
+    }
+}
@@ -1,7 +1,14 @@
-use std::sync::Arc;
+use std::sync::{Arc, Weak};
 use std::time::Duration;
 
-use crate::tcp::{shortcut, STANDALONE_ADDRESS};
+use crate::tcp::frame::{Compression, FrameCodec, DEFAULT_COMPRESSION_THRESHOLD};
+use crate::tcp::handshake::{self, Handshake};
+use crate::tcp::shortcut::{self, Namespace};
+use crate::tcp::tls::{self, TlsClientOptions};
+use crate::tcp::transport::{SharedTransport, DEFAULT_TRANSPORT};
+use crate::tcp::{
+    FramedStream, LimitExceeded, DEFAULT_MAX_FRAME_SIZE, FRAME_HEADER_SIZE, STANDALONE_ADDRESS,
+};
 use crate::DISABLE_SHORTCUT;
 use bifrost_hasher::hash_str;
 
@@ -9,39 +16,221 @@ use crate::tcp::server::TcpReq;
 use async_std::sync::Mutex;
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 use futures::prelude::*;
-use futures::stream::SplitSink;
+use futures::stream::{SplitSink, SplitStream};
 use futures::SinkExt;
 use parking_lot::Mutex as SyncMutex;
+use std::cmp::{max, min};
 use std::collections::HashMap;
-use std::sync::atomic::AtomicU64;
 use std::sync::atomic::Ordering::Relaxed;
+use std::sync::atomic::{AtomicU64, AtomicU8};
 use tokio::io;
-use tokio::net::TcpStream;
 use tokio::sync::oneshot;
 use tokio::time;
-use tokio_util::codec::{Framed, LengthDelimitedCodec};
+use tokio_util::codec::Framed;
+
+type Transport = FramedStream;
+type FrameWriter = SplitSink<Transport, Bytes>;
+type FrameReader = SplitStream<Transport>;
+type ResSender = oneshot::Sender<io::Result<BytesMut>>;
+
+const CONNECTED: u8 = 0;
+const RECONNECTING: u8 = 1;
+const BROKEN: u8 = 2;
+
+pub const DEFAULT_MAX_PENDING_REQUESTS: usize = 4096;
+
+#[derive(Clone)]
+pub struct ClientOptions {
+    pub timeout: Duration,
+    // Delay before the first reconnection attempt, doubled on every failure
+    pub reconnect_backoff: Duration,
+    pub max_reconnect_backoff: Duration,
+    // The client is considered broken after this many failed reconnection attempts in a row
+    pub max_reconnect_attempts: u32,
+    // Connect over TLS, see `tls::client_options`
+    pub tls: Option<TlsClientOptions>,
+    pub transport: SharedTransport,
+    // Must match the cluster id of the server, checked during handshake
+    pub cluster_id: u64,
+    // Servers in this namespace are called through the shortcut
+    pub namespace: Namespace,
+    pub max_frame_size: usize,
+    // Requests waiting for responses beyond this limit fail immediately
+    pub max_pending_requests: usize,
+    // Asked for during handshake, used only if the server allows it too
+    pub compression: Compression,
+    // Smaller frames are sent uncompressed
+    pub compression_threshold: usize,
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        ClientOptions {
+            timeout: Duration::from_secs(2),
+            reconnect_backoff: Duration::from_millis(100),
+            max_reconnect_backoff: Duration::from_secs(5),
+            max_reconnect_attempts: 10,
+            tls: None,
+            transport: DEFAULT_TRANSPORT.clone(),
+            cluster_id: 0,
+            namespace: Namespace::default(),
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            max_pending_requests: DEFAULT_MAX_PENDING_REQUESTS,
+            compression: Compression::None,
+            compression_threshold: DEFAULT_COMPRESSION_THRESHOLD,
+        }
+    }
+}
+
+struct Connection {
+    address: String,
+    options: ClientOptions,
+    writer: Mutex<Option<FrameWriter>>,
+    senders: SyncMutex<HashMap<u64, ResSender>>,
+    state: AtomicU8,
+}
 
 pub struct Client {
-    //client: Option<SplitSink<Bytes>>,
-    client: Option<Mutex<SplitSink<Framed<TcpStream, LengthDelimitedCodec>, Bytes>>>,
+    conn: Option<Arc<Connection>>,
     msg_counter: AtomicU64,
-    senders: Arc<SyncMutex<HashMap<u64, oneshot::Sender<BytesMut>>>>,
     timeout: Duration,
     pub server_id: u64,
+    pub namespace: Namespace,
+}
+
+impl Connection {
+    async fn open(address: &String, options: &ClientOptions) -> io::Result<Transport> {
+        debug!("Create socket on {}", address);
+        let socket = time::timeout(options.timeout, options.transport.connect(address)).await??;
+        let stream = match options.tls {
+            Some(ref tls) => time::timeout(options.timeout, tls::connect(socket, tls)).await??,
+            None => socket,
+        };
+        let codec =
+            FrameCodec::new(options.max_frame_size).with_threshold(options.compression_threshold);
+        let mut transport = Framed::new(stream, codec);
+        let local = Handshake::new(options.cluster_id, hash_str(address))
+            .with_compression(options.compression);
+        handshake::initiate(&mut transport, &local, options.timeout).await?;
+        Ok(transport)
+    }
+
+    async fn attach(self: &Arc<Self>, transport: Transport) {
+        let (writer, reader) = transport.split();
+        *self.writer.lock().await = Some(writer);
+        self.state.store(CONNECTED, Relaxed);
+        spawn_reader(Arc::downgrade(self), reader);
+    }
+
+    async fn disconnected(self: &Arc<Self>) {
+        // Drop the writer before failing pending calls, so no new request can slip
+        // into the senders map against the dead connection
+        self.writer.lock().await.take();
+        let pending: Vec<_> = self.senders.lock().drain().collect();
+        debug!(
+            "Connection to {} lost, failing {} pending requests",
+            self.address,
+            pending.len()
+        );
+        for (_, sender) in pending {
+            let _ = sender.send(Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                format!("Connection to {} lost", self.address),
+            )));
+        }
+        self.state.store(RECONNECTING, Relaxed);
+        spawn_reconnect(Arc::downgrade(self));
+    }
+}
+
+fn spawn_reader(conn: Weak<Connection>, mut reader: FrameReader) {
+    tokio::spawn(async move {
+        while let Some(res) = reader.next().await {
+            if let Ok(mut data) = res {
+                let conn = match conn.upgrade() {
+                    Some(conn) => conn,
+                    None => return,
+                };
+                let res_msg_id = data.get_u64_le();
+                trace!("Received msg for {}, size {}", res_msg_id, data.len());
+                let sender = conn.senders.lock().remove(&res_msg_id);
+                if let Some(sender) = sender {
+                    let _ = sender.send(Ok(data));
+                } else {
+                    debug!("No pending request for msg {}", res_msg_id);
+                }
+            }
+        }
+        if let Some(conn) = conn.upgrade() {
+            debug!("Stream from TCP server {} broken", conn.address);
+            conn.disconnected().await;
+        }
+    });
+}
+
+fn spawn_reconnect(conn: Weak<Connection>) {
+    tokio::spawn(async move {
+        let mut attempts = 0;
+        let mut backoff = match conn.upgrade() {
+            Some(conn) => conn.options.reconnect_backoff,
+            None => return,
+        };
+        loop {
+            time::sleep(backoff).await;
+            let conn = match conn.upgrade() {
+                Some(conn) => conn,
+                None => return,
+            };
+            match Connection::open(&conn.address, &conn.options).await {
+                Ok(transport) => {
+                    info!(
+                        "Reconnected to {} after {} attempts",
+                        conn.address,
+                        attempts + 1
+                    );
+                    conn.attach(transport).await;
+                    return;
+                }
+                Err(e) => {
+                    attempts += 1;
+                    if attempts >= conn.options.max_reconnect_attempts {
+                        warn!(
+                            "Giving up reconnecting to {} after {} attempts, {}",
+                            conn.address, attempts, e
+                        );
+                        conn.state.store(BROKEN, Relaxed);
+                        return;
+                    }
+                    debug!(
+                        "Cannot reconnect to {}, attempt {}, {}",
+                        conn.address, attempts, e
+                    );
+                    backoff = min(backoff * 2, conn.options.max_reconnect_backoff);
+                }
+            }
+        }
+    });
 }
 
 impl Client {
-    pub async fn connect_with_timeout(address: &String, timeout: Duration) -> io::Result<Self> {
+    pub async fn connect_with_options(
+        address: &String,
+        options: ClientOptions,
+    ) -> io::Result<Self> {
         let server_id = hash_str(address);
-        let senders = Arc::new(SyncMutex::new(HashMap::new()));
+        let timeout = options.timeout;
+        let namespace = options.namespace;
         debug!(
             "TCP connect to {}, server id {}, timeout {}ms",
             address,
             server_id,
             timeout.as_millis()
         );
-        let client = {
-            if !DISABLE_SHORTCUT && shortcut::is_local(server_id).await {
+        let conn = {
+            if !DISABLE_SHORTCUT
+                && options.transport.allows_shortcut()
+                && shortcut::is_local(options.namespace, server_id).await
+            {
                 debug!("Local connection, using shortcut");
                 None
             } else {
@@ -51,59 +240,259 @@ impl Client {
                         "STANDALONE server is not found",
                     ));
                 }
-                debug!("Create socket on {}", address);
-                let socket = time::timeout(timeout, TcpStream::connect(address)).await??;
-                let transport = Framed::new(socket, LengthDelimitedCodec::new());
-                let (writer, mut reader) = transport.split();
-                let cloned_senders = senders.clone();
-                debug!("Streaming messages for {}", address);
-                let address = address.clone();
-                tokio::spawn(async move {
-                    while let Some(res) = reader.next().await {
-                        if let Ok(mut data) = res {
-                            let res_msg_id = data.get_u64_le();
-                            trace!("Received msg for {}, size {}", res_msg_id, data.len());
-                            let sender: oneshot::Sender<BytesMut> =
-                                cloned_senders.lock().remove(&res_msg_id).unwrap();
-                            sender.send(data).unwrap();
-                        }
-                    }
-                    debug!("Stream from TCP server {} broken", address);
+                let transport = Connection::open(address, &options).await?;
+                let conn = Arc::new(Connection {
+                    address: address.clone(),
+                    options,
+                    writer: Mutex::new(None),
+                    senders: SyncMutex::new(HashMap::new()),
+                    state: AtomicU8::new(CONNECTED),
                 });
-                Some(Mutex::new(writer))
+                debug!("Streaming messages for {}", address);
+                conn.attach(transport).await;
+                Some(conn)
             }
         };
         Ok(Client {
-            client,
+            conn,
             server_id,
-            senders,
+            namespace,
             timeout,
             msg_counter: AtomicU64::new(0),
         })
     }
+    pub async fn connect_with_timeout(address: &String, timeout: Duration) -> io::Result<Self> {
+        Client::connect_with_options(
+            address,
+            ClientOptions {
+                timeout,
+                ..ClientOptions::default()
+            },
+        )
+        .await
+    }
     pub async fn connect(address: &String) -> io::Result<Self> {
-        Client::connect_with_timeout(address, Duration::from_secs(2)).await
+        Client::connect_with_options(address, ClientOptions::default()).await
     }
     pub async fn send_msg(&self, msg: TcpReq) -> io::Result<BytesMut> {
-        if let Some(ref transport) = self.client {
+        if self.conn.is_none() {
+            // Local calls are not bound by the connection timeout
+            return shortcut::call(self.namespace, self.server_id, msg).await;
+        }
+        self.send_msg_with_timeout(msg, self.timeout).await
+    }
+    // The server is told how long we wait, so it can skip or cancel the request once
+    // nobody is waiting for the response
+    pub async fn send_msg_with_timeout(
+        &self,
+        msg: TcpReq,
+        timeout: Duration,
+    ) -> io::Result<BytesMut> {
+        if let Some(ref conn) = self.conn {
+            let max_frame_size = conn.options.max_frame_size;
+            if FRAME_HEADER_SIZE + msg.len() > max_frame_size {
+                return Err(LimitExceeded::FrameSize {
+                    size: FRAME_HEADER_SIZE + msg.len(),
+                    max: max_frame_size,
+                }
+                .into_io_error());
+            }
+            let deadline = time::Instant::now() + timeout;
             let msg_id = self.msg_counter.fetch_add(1, Relaxed);
-            let mut frame = BytesMut::with_capacity(8 + msg.len());
+            let mut frame = BytesMut::with_capacity(FRAME_HEADER_SIZE + msg.len());
             let rx = {
                 frame.put_u64_le(msg_id);
+                frame.put_u64_le(max(timeout.as_millis() as u64, 1));
                 frame.extend_from_slice(msg.as_ref());
                 let (tx, rx) = oneshot::channel();
-                let mut senders = self.senders.lock();
+                let mut senders = conn.senders.lock();
+                if senders.len() >= conn.options.max_pending_requests {
+                    return Err(
+                        LimitExceeded::PendingRequests(conn.options.max_pending_requests)
+                            .into_io_error(),
+                    );
+                }
                 senders.insert(msg_id, tx);
                 rx
             };
             trace!("Sending msg {}, size {}", msg_id, frame.len());
-            time::timeout(self.timeout, transport.lock().await.send(frame.freeze())).await??;
-            trace!("Sent msg {}", msg_id);
-            Ok(time::timeout(self.timeout, rx).await?.unwrap())
+            let res = async {
+                {
+                    let mut writer = conn.writer.lock().await;
+                    match writer.as_mut() {
+                        Some(writer) => writer.send(frame.freeze()).await?,
+                        None => {
+                            return Err(io::Error::new(
+                                io::ErrorKind::NotConnected,
+                                format!("Reconnecting to {}", conn.address),
+                            ))
+                        }
+                    }
+                }
+                trace!("Sent msg {}", msg_id);
+                match rx.await {
+                    Ok(res) => res,
+                    Err(_) => Err(io::Error::new(
+                        io::ErrorKind::ConnectionAborted,
+                        format!("Connection to {} lost", conn.address),
+                    )),
+                }
+            };
+            let res = time::timeout_at(deadline, res)
+                .await
+                .map_err(io::Error::from)
+                .and_then(|r| r);
+            if res.is_err() {
+                // Nothing will answer a timed out or failed request
+                conn.senders.lock().remove(&msg_id);
+            }
+            res
         } else {
-            Ok(shortcut::call(self.server_id, msg).await?)
+            time::timeout(timeout, shortcut::call(self.namespace, self.server_id, msg)).await?
+        }
+    }
+    // Broken clients have given up reconnecting and should be replaced
+    pub fn is_broken(&self) -> bool {
+        match self.conn {
+            Some(ref conn) => conn.state.load(Relaxed) == BROKEN,
+            None => false,
+        }
+    }
+    pub fn is_connected(&self) -> bool {
+        match self.conn {
+            Some(ref conn) => conn.state.load(Relaxed) == CONNECTED,
+            None => true,
         }
     }
+    // Calls go through the shortcut, there is no connection to the server
+    pub fn is_local(&self) -> bool {
+        self.conn.is_none()
+    }
 }
 
 unsafe impl Send for Client {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Instant;
+    use tokio::net::TcpListener;
+
+    // Raw server end of a connection, after answering the handshake
+    async fn accept(listener: &TcpListener, addr: &String) -> Transport {
+        let (socket, _) = listener.accept().await.unwrap();
+        let mut transport: Transport =
+            Framed::new(Box::new(socket), FrameCodec::new(DEFAULT_MAX_FRAME_SIZE));
+        let local = Handshake::new(0, hash_str(addr));
+        handshake::respond(&mut transport, &local, Duration::from_secs(5))
+            .await
+            .unwrap();
+        transport
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn fail_pending_and_reconnect() {
+        let _ = env_logger::try_init();
+        let addr = String::from("127.0.0.1:1360");
+        let listener = TcpListener::bind(&addr).await.unwrap();
+        let (client, socket) = tokio::join!(
+            Client::connect_with_options(
+                &addr,
+                ClientOptions {
+                    timeout: Duration::from_secs(5),
+                    ..ClientOptions::default()
+                },
+            ),
+            accept(&listener, &addr)
+        );
+        let client = Arc::new(client.unwrap());
+        let pending = {
+            let client = client.clone();
+            tokio::spawn(async move {
+                let start = Instant::now();
+                let res = client.send_msg(BytesMut::from(&b"hello"[..])).await;
+                (res, start.elapsed())
+            })
+        };
+        time::sleep(Duration::from_millis(200)).await;
+        drop(socket);
+        let (res, elapsed) = pending.await.unwrap();
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::ConnectionAborted);
+        assert!(elapsed < Duration::from_secs(5));
+
+        // Echo server for the reconnected client
+        let mut transport = accept(&listener, &addr).await;
+        tokio::spawn(async move {
+            while let Some(Ok(mut frame)) = transport.next().await {
+                let msg_id = frame.get_u64_le();
+                frame.advance(8);
+                let mut res = BytesMut::new();
+                res.put_u64_le(msg_id);
+                res.extend_from_slice(&frame);
+                transport.send(res.freeze()).await.unwrap();
+            }
+        });
+        while !client.is_connected() {
+            time::sleep(Duration::from_millis(50)).await;
+        }
+        let res = client
+            .send_msg(BytesMut::from(&b"hello"[..]))
+            .await
+            .unwrap();
+        assert_eq!(&res[..], b"hello");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn pending_requests_limit() {
+        let _ = env_logger::try_init();
+        let addr = String::from("127.0.0.1:1361");
+        let listener = TcpListener::bind(&addr).await.unwrap();
+        let (client, _transport) = tokio::join!(
+            Client::connect_with_options(
+                &addr,
+                ClientOptions {
+                    max_pending_requests: 2,
+                    ..ClientOptions::default()
+                },
+            ),
+            accept(&listener, &addr)
+        );
+        let client = Arc::new(client.unwrap());
+        // The server never responds, so both requests stay pending
+        for _ in 0..2 {
+            let client = client.clone();
+            tokio::spawn(async move {
+                let _ = client.send_msg(BytesMut::from(&b"hello"[..])).await;
+            });
+        }
+        time::sleep(Duration::from_millis(200)).await;
+        let err = client
+            .send_msg(BytesMut::from(&b"hello"[..]))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            LimitExceeded::from_io_error(&err),
+            Some(&LimitExceeded::PendingRequests(2))
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn request_deadline() {
+        let _ = env_logger::try_init();
+        let addr = String::from("127.0.0.1:1362");
+        let listener = TcpListener::bind(&addr).await.unwrap();
+        let (client, transport) = tokio::join!(Client::connect(&addr), accept(&listener, &addr));
+        let client = client.unwrap();
+        let mut transport = transport;
+        let err = client
+            .send_msg_with_timeout(BytesMut::from(&b"hello"[..]), Duration::from_millis(100))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        // The server learns how long the client waits, the client forgets the request
+        let mut frame = transport.next().await.unwrap().unwrap();
+        frame.advance(8);
+        assert_eq!(frame.get_u64_le(), 100);
+        assert!(client.conn.as_ref().unwrap().senders.lock().is_empty());
+    }
+}
@@ -0,0 +1,286 @@
+// Length delimited frames, optionally compressed once both ends agreed on it during the
+// handshake. With compression every frame starts with a flag telling whether the rest
+// of it is compressed, so frames under the threshold, or those compression would not
+// shrink, are sent as they are.
+
+use super::codec;
+use super::LimitExceeded;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::io;
+use tokio_util::codec::{Decoder, Encoder, LengthDelimitedCodec};
+
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 1024;
+
+const PLAIN: u8 = 0;
+const LZ4: u8 = 1;
+// The flag comes on top of the frame, not counted against the frame size limit
+const FLAG_SIZE: usize = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Lz4,
+}
+
+impl Compression {
+    pub fn id(&self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Lz4 => 1,
+        }
+    }
+
+    // Unknown ids fall back to no compression
+    pub fn from_id(id: u8) -> Self {
+        match id {
+            1 => Compression::Lz4,
+            _ => Compression::None,
+        }
+    }
+
+    // What the server uses when the client asks for `requested`
+    pub fn negotiate(requested: Compression, allowed: Compression) -> Self {
+        if requested == allowed {
+            requested
+        } else {
+            Compression::None
+        }
+    }
+}
+
+pub struct FrameCodec {
+    inner: LengthDelimitedCodec,
+    max_frame_size: usize,
+    compression: Compression,
+    threshold: usize,
+}
+
+impl FrameCodec {
+    pub fn new(max_frame_size: usize) -> Self {
+        FrameCodec {
+            inner: codec(max_frame_size),
+            max_frame_size,
+            compression: Compression::None,
+            threshold: DEFAULT_COMPRESSION_THRESHOLD,
+        }
+    }
+
+    // Frames smaller than `threshold` are not worth compressing
+    pub fn with_threshold(mut self, threshold: usize) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    // Both ends have to switch at the same point, right after the handshake
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.compression = compression;
+        self.inner = match compression {
+            Compression::None => codec(self.max_frame_size),
+            Compression::Lz4 => codec(self.max_frame_size + FLAG_SIZE),
+        };
+    }
+
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    fn unpack(&self, mut frame: BytesMut) -> io::Result<BytesMut> {
+        if self.compression == Compression::None {
+            return Ok(frame);
+        }
+        if frame.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Empty frame"));
+        }
+        match frame.get_u8() {
+            PLAIN => Ok(frame),
+            LZ4 => {
+                if frame.len() < 4 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Truncated frame",
+                    ));
+                }
+                let size = (&frame[..4]).get_u32_le() as usize;
+                // Checked before allocating for the decompressed frame
+                if size > self.max_frame_size {
+                    return Err(LimitExceeded::FrameSize {
+                        size,
+                        max: self.max_frame_size,
+                    }
+                    .into_io_error());
+                }
+                lz4_flex::decompress_size_prepended(&frame)
+                    .map(|data| BytesMut::from(data.as_slice()))
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+            }
+            flag => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unknown frame flag {}", flag),
+            )),
+        }
+    }
+
+    fn pack(&self, frame: Bytes) -> Bytes {
+        match self.compression {
+            Compression::None => frame,
+            Compression::Lz4 if frame.len() >= self.threshold => {
+                let compressed = lz4_flex::compress_prepend_size(&frame);
+                if compressed.len() >= frame.len() {
+                    return self.pack_plain(frame);
+                }
+                let mut packed = BytesMut::with_capacity(FLAG_SIZE + compressed.len());
+                packed.put_u8(LZ4);
+                packed.extend_from_slice(&compressed);
+                packed.freeze()
+            }
+            Compression::Lz4 => self.pack_plain(frame),
+        }
+    }
+
+    fn pack_plain(&self, frame: Bytes) -> Bytes {
+        let mut packed = BytesMut::with_capacity(FLAG_SIZE + frame.len());
+        packed.put_u8(PLAIN);
+        packed.extend_from_slice(&frame);
+        packed.freeze()
+    }
+}
+
+impl Decoder for FrameCodec {
+    type Item = BytesMut;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<BytesMut>> {
+        match self.inner.decode(src)? {
+            Some(frame) => self.unpack(frame).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+impl Encoder<Bytes> for FrameCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, frame: Bytes, dst: &mut BytesMut) -> io::Result<()> {
+        let packed = self.pack(frame);
+        self.inner.encode(packed, dst)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tcp::client::{Client, ClientOptions};
+    use crate::tcp::server::{Server, ServerOptions, TcpReq};
+    use crate::tcp::sim::SimNetwork;
+    use futures::FutureExt;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn round_trip(codec: &mut FrameCodec, frame: &[u8]) -> (usize, BytesMut) {
+        let mut wire = BytesMut::new();
+        codec
+            .encode(Bytes::copy_from_slice(frame), &mut wire)
+            .unwrap();
+        let wire_len = wire.len();
+        (wire_len, codec.decode(&mut wire).unwrap().unwrap())
+    }
+
+    #[test]
+    fn compression_threshold() {
+        let mut codec = FrameCodec::new(1024 * 1024).with_threshold(64);
+        codec.set_compression(Compression::Lz4);
+        let small = b"heartbeat";
+        let (wire_len, decoded) = round_trip(&mut codec, small);
+        assert_eq!(&decoded[..], small);
+        // Length prefix, flag and the frame itself
+        assert_eq!(wire_len, 4 + 1 + small.len());
+
+        let large = vec![42u8; 64 * 1024];
+        let (wire_len, decoded) = round_trip(&mut codec, &large);
+        assert_eq!(&decoded[..], &large[..]);
+        assert!(wire_len < large.len() / 10);
+    }
+
+    #[test]
+    fn incompressible_frames() {
+        let mut codec = FrameCodec::new(1024).with_threshold(0);
+        codec.set_compression(Compression::Lz4);
+        // Random bytes grow when compressed, a full frame still fits with its flag
+        let frame: Vec<u8> = (0..1024).map(|_| rand::random::<u8>()).collect();
+        let (wire_len, decoded) = round_trip(&mut codec, &frame);
+        assert_eq!(&decoded[..], &frame[..]);
+        assert_eq!(wire_len, 4 + 1 + frame.len());
+    }
+
+    #[test]
+    fn decompressed_size_limit() {
+        let mut sender = FrameCodec::new(1024 * 1024).with_threshold(0);
+        sender.set_compression(Compression::Lz4);
+        let mut receiver = FrameCodec::new(1024);
+        receiver.set_compression(Compression::Lz4);
+        let mut wire = BytesMut::new();
+        sender
+            .encode(Bytes::from(vec![0u8; 64 * 1024]), &mut wire)
+            .unwrap();
+        let err = receiver.decode(&mut wire).unwrap_err();
+        assert!(LimitExceeded::from_io_error(&err).is_some());
+    }
+
+    #[test]
+    fn negotiation() {
+        assert_eq!(
+            Compression::negotiate(Compression::Lz4, Compression::Lz4),
+            Compression::Lz4
+        );
+        assert_eq!(
+            Compression::negotiate(Compression::Lz4, Compression::None),
+            Compression::None
+        );
+        assert_eq!(
+            Compression::from_id(Compression::Lz4.id()),
+            Compression::Lz4
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn negotiated_compression() {
+        let _ = env_logger::try_init();
+        let network = SimNetwork::new(3);
+        for (node, compression) in vec![("lz4", Compression::Lz4), ("plain", Compression::None)] {
+            let options = ServerOptions {
+                transport: network.node(node),
+                compression,
+                ..ServerOptions::default()
+            };
+            let addr = node.to_string();
+            tokio::spawn(async move {
+                Server::new_with_options(
+                    &addr,
+                    Arc::new(|data: TcpReq| async move { data }.boxed()),
+                    options,
+                )
+                .await
+                .unwrap();
+            });
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let payload: Vec<u8> = (0..256 * 1024).map(|i| (i % 7) as u8).collect();
+        // The server without compression answers with none, the client follows
+        for node in vec!["lz4", "plain"] {
+            let client = Client::connect_with_options(
+                &node.to_string(),
+                ClientOptions {
+                    transport: network.node("client"),
+                    compression: Compression::Lz4,
+                    ..ClientOptions::default()
+                },
+            )
+            .await
+            .unwrap();
+            for frame in vec![&payload[..], b"small"] {
+                let res = client.send_msg(BytesMut::from(frame)).await.unwrap();
+                assert_eq!(&res[..], frame);
+            }
+        }
+    }
+}
@@ -0,0 +1,262 @@
+// First frames on every connection, the client sends its handshake and the server answers
+// with its own before any request. Peers from another cluster, protocol version or codec
+// are rejected instead of failing to decode messages later on. The handshake also settles
+// frame compression, the server answers with what the connection will use.
+
+use super::frame::Compression;
+use super::{FramedStream, LimitExceeded};
+use bytes::{Buf, BufMut, BytesMut};
+use futures::{SinkExt, StreamExt};
+use std::io;
+use std::time::Duration;
+use tokio::time;
+
+pub const PROTOCOL_VERSION: u32 = 6;
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+const MAGIC: &'static [u8; 4] = b"BFRT";
+// Sent by servers instead of their handshake when they are out of connections
+const REJECT_MAGIC: &'static [u8; 4] = b"BFRX";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Handshake {
+    pub protocol_version: u32,
+    pub codec: String,
+    pub cluster_id: u64,
+    // Id of the server the connection is meant for, the client sends the id it expects
+    pub server_id: u64,
+    // Requested by the client, the one picked by the server in its answer
+    pub compression: Compression,
+}
+
+impl Handshake {
+    pub fn new(cluster_id: u64, server_id: u64) -> Self {
+        Handshake {
+            protocol_version: PROTOCOL_VERSION,
+            codec: crate::utils::serde::codec().name().to_string(),
+            cluster_id,
+            server_id,
+            compression: Compression::None,
+        }
+    }
+
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    pub fn encode(&self) -> BytesMut {
+        let codec = self.codec.as_bytes();
+        let mut frame = BytesMut::with_capacity(MAGIC.len() + 22 + codec.len());
+        frame.extend_from_slice(MAGIC);
+        frame.put_u32_le(self.protocol_version);
+        frame.put_u64_le(self.cluster_id);
+        frame.put_u64_le(self.server_id);
+        frame.put_u8(codec.len() as u8);
+        frame.extend_from_slice(codec);
+        frame.put_u8(self.compression.id());
+        frame
+    }
+
+    pub fn decode(mut frame: BytesMut) -> io::Result<Self> {
+        if frame.len() < MAGIC.len() + 22 || &frame[..MAGIC.len()] != MAGIC {
+            return Err(incompatible(
+                "peer did not send a bifrost handshake".to_string(),
+            ));
+        }
+        frame.advance(MAGIC.len());
+        let protocol_version = frame.get_u32_le();
+        let cluster_id = frame.get_u64_le();
+        let server_id = frame.get_u64_le();
+        let codec_len = frame.get_u8() as usize;
+        if frame.len() != codec_len + 1 {
+            return Err(incompatible("malformed handshake".to_string()));
+        }
+        let codec = String::from_utf8_lossy(&frame[..codec_len]).to_string();
+        frame.advance(codec_len);
+        Ok(Handshake {
+            protocol_version,
+            codec,
+            cluster_id,
+            server_id,
+            compression: Compression::from_id(frame.get_u8()),
+        })
+    }
+
+    // Checks whether the remote end can talk to us
+    pub fn check(&self, remote: &Handshake) -> io::Result<()> {
+        if remote.protocol_version != self.protocol_version {
+            return Err(incompatible(format!(
+                "peer speaks protocol version {}, expected {}",
+                remote.protocol_version, self.protocol_version
+            )));
+        }
+        if remote.codec != self.codec {
+            return Err(incompatible(format!(
+                "peer uses codec {}, expected {}",
+                remote.codec, self.codec
+            )));
+        }
+        if remote.cluster_id != self.cluster_id {
+            return Err(incompatible(format!(
+                "peer is in cluster {}, expected {}",
+                remote.cluster_id, self.cluster_id
+            )));
+        }
+        if remote.server_id != self.server_id {
+            // Same server reached by another name, ids are derived from the address string
+            warn!(
+                "Handshake server id {} differs from local {}",
+                remote.server_id, self.server_id
+            );
+        }
+        Ok(())
+    }
+}
+
+fn incompatible(reason: String) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Incompatible peer, {}", reason),
+    )
+}
+
+async fn receive(transport: &mut FramedStream, timeout: Duration) -> io::Result<Handshake> {
+    match time::timeout(timeout, transport.next()).await? {
+        Some(frame) => {
+            let mut frame = frame?;
+            if frame.len() == REJECT_MAGIC.len() + 8 && &frame[..REJECT_MAGIC.len()] == REJECT_MAGIC
+            {
+                frame.advance(REJECT_MAGIC.len());
+                let max = frame.get_u64_le() as usize;
+                return Err(LimitExceeded::Connections(max).into_io_error());
+            }
+            Handshake::decode(frame)
+        }
+        None => Err(io::Error::new(
+            io::ErrorKind::ConnectionAborted,
+            "Connection closed during handshake",
+        )),
+    }
+}
+
+// Client side, requests must not be sent before the server has answered
+pub async fn initiate(
+    transport: &mut FramedStream,
+    local: &Handshake,
+    timeout: Duration,
+) -> io::Result<Handshake> {
+    time::timeout(timeout, transport.send(local.encode().freeze())).await??;
+    let remote = receive(transport, timeout).await?;
+    local.check(&remote)?;
+    // Servers never answer with something we did not ask for, but do not trust that
+    let compression = Compression::negotiate(remote.compression, local.compression);
+    transport.codec_mut().set_compression(compression);
+    Ok(remote)
+}
+
+// Server side. Our handshake is sent back even to incompatible clients so they can
+// tell why they are rejected.
+pub async fn respond(
+    transport: &mut FramedStream,
+    local: &Handshake,
+    timeout: Duration,
+) -> io::Result<Handshake> {
+    let remote = receive(transport, timeout).await?;
+    let answer = local.clone().with_compression(Compression::negotiate(
+        remote.compression,
+        local.compression,
+    ));
+    time::timeout(timeout, transport.send(answer.encode().freeze())).await??;
+    local.check(&remote)?;
+    transport.codec_mut().set_compression(answer.compression);
+    Ok(remote)
+}
+
+// Server side, turns the client away because the server has `max_connections` already
+pub async fn reject(
+    transport: &mut FramedStream,
+    max_connections: usize,
+    timeout: Duration,
+) -> io::Result<()> {
+    // Read the client handshake first, closing with unread data may reset the connection
+    // before the client sees why
+    receive(transport, timeout).await?;
+    let mut frame = BytesMut::with_capacity(REJECT_MAGIC.len() + 8);
+    frame.extend_from_slice(REJECT_MAGIC);
+    frame.put_u64_le(max_connections as u64);
+    time::timeout(timeout, transport.send(frame.freeze())).await??;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tcp::client::{Client, ClientOptions};
+    use crate::tcp::server::{Server, ServerOptions, TcpReq};
+    use futures::FutureExt;
+    use std::sync::Arc;
+
+    #[test]
+    fn encode_decode() {
+        let handshake = Handshake::new(42, 7).with_compression(Compression::Lz4);
+        let decoded = Handshake::decode(handshake.encode()).unwrap();
+        assert_eq!(handshake, decoded);
+        assert!(handshake.check(&decoded).is_ok());
+        assert!(Handshake::decode(BytesMut::from(&b"hello"[..])).is_err());
+    }
+
+    #[test]
+    fn incompatible_peers() {
+        let local = Handshake::new(1, 7);
+        let mut remote = local.clone();
+        remote.codec = String::from("msgpack");
+        assert_eq!(
+            local.check(&remote).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        let mut remote = local.clone();
+        remote.protocol_version += 1;
+        assert!(local.check(&remote).is_err());
+        assert!(local.check(&Handshake::new(2, 7)).is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn reject_foreign_cluster() {
+        let _ = env_logger::try_init();
+        tokio::spawn(async move {
+            Server::new_with_options(
+                &String::from("127.0.0.1:1380"),
+                Arc::new(|data: TcpReq| async move { data }.boxed()),
+                ServerOptions {
+                    cluster_id: 1,
+                    ..ServerOptions::default()
+                },
+            )
+            .await
+            .unwrap();
+        });
+        time::sleep(Duration::from_millis(500)).await;
+        // Connect by another name of the same address so the shortcut is not taken
+        let addr = String::from("localhost:1380");
+        let client_options = |cluster_id| ClientOptions {
+            cluster_id,
+            ..ClientOptions::default()
+        };
+        let err = Client::connect_with_options(&addr, client_options(2))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("cluster"));
+
+        let client = Client::connect_with_options(&addr, client_options(1))
+            .await
+            .unwrap();
+        let res = client
+            .send_msg(BytesMut::from(&b"hello"[..]))
+            .await
+            .unwrap();
+        assert_eq!(&res[..], b"hello");
+    }
+}
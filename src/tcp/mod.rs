@@ -1,12 +1,82 @@
 use bifrost_hasher::hash_str;
+use std::fmt;
+use std::io;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
 
 pub mod client;
+pub mod frame;
+pub mod handshake;
 pub mod server;
 pub mod shortcut;
+pub mod sim;
+pub mod tls;
+pub mod transport;
 
 pub static STANDALONE_ADDRESS: &'static str = "STANDALONE";
+pub static UNIX_SOCKET_PREFIX: &'static str = "unix:";
+
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 8 * 1024 * 1024;
+// Request frames start with the message id and the milliseconds the client is willing
+// to wait for the response
+pub const FRAME_HEADER_SIZE: usize = 16;
 
 lazy_static! {
     pub static ref STANDALONE_ADDRESS_STRING: String = String::from(STANDALONE_ADDRESS);
     pub static ref STANDALONE_SERVER_ID: u64 = hash_str(&STANDALONE_ADDRESS_STRING);
 }
+
+// TCP sockets, unix sockets and TLS sessions are all carried over this type, so framing
+// does not need to know which one it is talking to
+pub trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AsyncStream for T {}
+
+pub type BoxedStream = Box<dyn AsyncStream>;
+
+pub type FramedStream = Framed<BoxedStream, frame::FrameCodec>;
+
+// Addresses like `unix:/path/to.sock` are served over unix domain sockets
+pub fn unix_socket_path(address: &str) -> Option<&str> {
+    address.strip_prefix(UNIX_SOCKET_PREFIX)
+}
+
+pub fn codec(max_frame_size: usize) -> LengthDelimitedCodec {
+    LengthDelimitedCodec::builder()
+        .max_frame_length(max_frame_size)
+        .new_codec()
+}
+
+// Carried inside `io::Error` when a configured limit is hit, so callers can tell it apart
+// from connection failures
+#[derive(Debug, Clone, PartialEq)]
+pub enum LimitExceeded {
+    FrameSize { size: usize, max: usize },
+    PendingRequests(usize),
+    Connections(usize),
+}
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LimitExceeded::FrameSize { size, max } => {
+                write!(f, "Frame of {} bytes exceeds the limit of {}", size, max)
+            }
+            LimitExceeded::PendingRequests(max) => {
+                write!(f, "More than {} pending requests", max)
+            }
+            LimitExceeded::Connections(max) => write!(f, "More than {} connections", max),
+        }
+    }
+}
+
+impl std::error::Error for LimitExceeded {}
+
+impl LimitExceeded {
+    pub fn into_io_error(self) -> io::Error {
+        io::Error::new(io::ErrorKind::Other, self)
+    }
+
+    pub fn from_io_error(e: &io::Error) -> Option<&LimitExceeded> {
+        e.get_ref().and_then(|inner| inner.downcast_ref())
+    }
+}
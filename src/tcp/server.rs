@@ -1,65 +1,718 @@
-use super::STANDALONE_ADDRESS;
-use crate::tcp::shortcut;
-use bytes::{Buf, BufMut, BytesMut};
-use futures::SinkExt;
+use super::frame::{Compression, FrameCodec, DEFAULT_COMPRESSION_THRESHOLD};
+use super::handshake::{self, Handshake, HANDSHAKE_TIMEOUT};
+use super::transport::{SharedTransport, DEFAULT_TRANSPORT};
+use super::{FramedStream, DEFAULT_MAX_FRAME_SIZE, FRAME_HEADER_SIZE, STANDALONE_ADDRESS};
+use crate::tcp::shortcut::{self, Namespace};
+use crate::tcp::tls::{self, TlsAcceptor};
+use async_std::sync::Mutex;
+use bifrost_hasher::hash_str;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures::{future, SinkExt, StreamExt};
+use parking_lot::Mutex as SyncMutex;
 use std::error::Error;
 use std::future::Future;
 use std::pin::Pin;
 use std::sync::Arc;
-use tokio::net::TcpListener;
-use tokio_stream::StreamExt;
-use tokio_util::codec::{Framed, LengthDelimitedCodec};
+use std::time::Duration;
+use tokio::sync::{mpsc, watch, Semaphore};
+use tokio::task::JoinSet;
+use tokio::time::{self, Instant};
+use tokio_util::codec::Framed;
 
 pub type RPCFuture = dyn Future<Output = TcpRes>;
 pub type BoxedRPCFuture = Box<RPCFuture>;
 pub type TcpReq = BytesMut;
 pub type TcpRes = Pin<Box<dyn Future<Output = BytesMut> + Send>>;
+pub type TcpCallback = Arc<dyn Fn(TcpReq) -> TcpRes + Send + Sync>;
+
+pub const DEFAULT_MAX_INFLIGHT_PER_CONN: usize = 128;
+pub const DEFAULT_MAX_CONNECTIONS: usize = 1024;
+pub const DEFAULT_MAX_REQUEST_TIMEOUT: Duration = Duration::from_secs(600);
+
+#[derive(Clone)]
+pub struct ServerOptions {
+    // Requests from one connection beyond this limit will wait until earlier ones finish
+    pub max_inflight_per_conn: usize,
+    // Clients connecting beyond this limit are rejected during handshake
+    pub max_connections: usize,
+    // Connections sending larger frames are closed, larger responses are not sent
+    pub max_frame_size: usize,
+    // Accept TLS sessions only, see `tls::server_acceptor`
+    pub tls: Option<TlsAcceptor>,
+    pub transport: SharedTransport,
+    // Clients from other clusters are rejected during handshake
+    pub cluster_id: u64,
+    // Only clients in this namespace can reach the server through the shortcut
+    pub namespace: Namespace,
+    // Granted to clients asking for the same compression, others get none
+    pub compression: Compression,
+    // Smaller frames are sent uncompressed
+    pub compression_threshold: usize,
+    // Longer timeouts asked for by clients are cut down to this
+    pub max_request_timeout: Duration,
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        ServerOptions {
+            max_inflight_per_conn: DEFAULT_MAX_INFLIGHT_PER_CONN,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            tls: None,
+            transport: DEFAULT_TRANSPORT.clone(),
+            cluster_id: 0,
+            namespace: Namespace::default(),
+            compression: Compression::None,
+            compression_threshold: DEFAULT_COMPRESSION_THRESHOLD,
+            max_request_timeout: DEFAULT_MAX_REQUEST_TIMEOUT,
+        }
+    }
+}
 
 pub struct Server;
 
+// Stops servers started with `Server::new_with_shutdown`. Every task serving the server
+// holds a guard, shutdown completes once all of them are dropped.
+pub struct Shutdown {
+    deadline: watch::Sender<Option<Instant>>,
+    signal: watch::Receiver<Option<Instant>>,
+    guard: SyncMutex<Option<mpsc::Sender<()>>>,
+    stopped: Mutex<mpsc::Receiver<()>>,
+}
+
+#[derive(Clone)]
+struct ShutdownSignal {
+    deadline: watch::Receiver<Option<Instant>>,
+    _guard: Option<mpsc::Sender<()>>,
+}
+
+impl Shutdown {
+    pub fn new() -> Arc<Self> {
+        let (deadline, signal) = watch::channel(None);
+        let (guard, stopped) = mpsc::channel(1);
+        Arc::new(Shutdown {
+            deadline,
+            signal,
+            guard: SyncMutex::new(Some(guard)),
+            stopped: Mutex::new(stopped),
+        })
+    }
+
+    // Stops accepting connections and waits for in-flight requests until `grace` has passed,
+    // then closes all connections
+    pub async fn shutdown(&self, grace: Duration) {
+        let _ = self.deadline.send(Some(Instant::now() + grace));
+        self.guard.lock().take();
+        // Returns `None` once every guard is dropped
+        let _ = self.stopped.lock().await.recv().await;
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.signal.borrow().is_some()
+    }
+
+    fn signal(&self) -> ShutdownSignal {
+        ShutdownSignal {
+            deadline: self.signal.clone(),
+            _guard: self.guard.lock().clone(),
+        }
+    }
+}
+
+impl ShutdownSignal {
+    fn deadline(&self) -> Option<Instant> {
+        *self.deadline.borrow()
+    }
+
+    async fn triggered(&mut self) -> Instant {
+        loop {
+            if let Some(deadline) = self.deadline() {
+                return deadline;
+            }
+            if self.deadline.changed().await.is_err() {
+                future::pending::<()>().await;
+            }
+        }
+    }
+}
+
 impl Server {
-    pub async fn new(
+    pub async fn new(addr: &String, callback: TcpCallback) -> Result<(), Box<dyn Error>> {
+        Self::new_with_options(addr, callback, ServerOptions::default()).await
+    }
+
+    pub async fn new_with_options(
         addr: &String,
-        callback: Arc<dyn Fn(TcpReq) -> TcpRes + Send + Sync>,
+        callback: TcpCallback,
+        options: ServerOptions,
     ) -> Result<(), Box<dyn Error>> {
-        shortcut::register_server(addr, &callback).await;
-        if !addr.eq(&STANDALONE_ADDRESS) {
-            let listener = TcpListener::bind(&addr).await?;
-            loop {
-                match listener.accept().await {
-                    Ok((socket, _)) => {
-                        // Like with other small servers, we'll `spawn` this client to ensure it
-                        // runs concurrently with all other clients. The `move` keyword is used
-                        // here to move ownership of our db handle into the async closure.
-                        let callback = callback.clone();
-                        tokio::spawn(async move {
-                            let mut transport = Framed::new(socket, LengthDelimitedCodec::new());
-                            while let Some(result) = transport.next().await {
-                                match result {
-                                    Ok(mut data) => {
-                                        let msg_id = data.get_u64_le();
-                                        let call_back_data = callback(data).await;
-                                        let mut res =
-                                            BytesMut::with_capacity(8 + call_back_data.len());
-                                        // debug!("Received TCP message {}", msg_id);
-                                        res.put_u64_le(msg_id);
-                                        res.extend_from_slice(call_back_data.as_ref());
-                                        if let Err(e) = transport.send(res.freeze()).await {
-                                            error!("Error on TCP callback {:?}", e);
+        Self::new_with_shutdown(addr, callback, options, &Shutdown::new()).await
+    }
+
+    pub async fn new_with_shutdown(
+        addr: &String,
+        callback: TcpCallback,
+        options: ServerOptions,
+        shutdown: &Shutdown,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut signal = shutdown.signal();
+        if options.transport.allows_shortcut() {
+            shortcut::register_server(options.namespace, addr, &callback).await;
+        }
+        if addr.eq(&STANDALONE_ADDRESS) {
+            let addr = addr.clone();
+            let namespace = options.namespace;
+            tokio::spawn(async move {
+                signal.triggered().await;
+                shortcut::unregister_server(namespace, &addr).await;
+            });
+            return Ok(());
+        }
+        let mut listener = options.transport.listen(addr).await?;
+        let handshake = Handshake::new(options.cluster_id, hash_str(addr))
+            .with_compression(options.compression);
+        let connections = Arc::new(Semaphore::new(options.max_connections));
+        loop {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = signal.triggered() => break,
+            };
+            match accepted {
+                Ok((socket, peer)) => {
+                    // Like with other small servers, we'll `spawn` this client to ensure it
+                    // runs concurrently with all other clients.
+                    let callback = callback.clone();
+                    let max_inflight = options.max_inflight_per_conn;
+                    let max_connections = options.max_connections;
+                    let max_frame_size = options.max_frame_size;
+                    let max_request_timeout = options.max_request_timeout;
+                    let compression_threshold = options.compression_threshold;
+                    let connection = connections.clone().try_acquire_owned().ok();
+                    let tls = options.tls.clone();
+                    let handshake = handshake.clone();
+                    let mut signal = signal.clone();
+                    tokio::spawn(async move {
+                        let established = async {
+                            let stream = match tls {
+                                Some(acceptor) => {
+                                    let accepted = time::timeout(
+                                        HANDSHAKE_TIMEOUT,
+                                        tls::accept(socket, &acceptor),
+                                    )
+                                    .await;
+                                    match accepted {
+                                        Ok(Ok(stream)) => stream,
+                                        Ok(Err(e)) => {
+                                            warn!("Rejected TLS connection from {}, {}", peer, e);
+                                            return None;
+                                        }
+                                        Err(_) => {
+                                            warn!(
+                                                "Rejected TLS connection from {}, timed out",
+                                                peer
+                                            );
+                                            return None;
                                         }
-                                    }
-                                    Err(e) => {
-                                        error!("error on decoding from socket; error = {:?}", e);
                                     }
                                 }
+                                None => socket,
+                            };
+                            let codec = FrameCodec::new(max_frame_size)
+                                .with_threshold(compression_threshold);
+                            let mut transport = Framed::new(stream, codec);
+                            if connection.is_none() {
+                                warn!("Rejected connection from {}, too many connections", peer);
+                                let _ = handshake::reject(
+                                    &mut transport,
+                                    max_connections,
+                                    HANDSHAKE_TIMEOUT,
+                                )
+                                .await;
+                                return None;
                             }
-                            // The connection will be closed at this point as `lines.next()` has returned `None`.
-                        });
-                    }
-                    Err(e) => error!("error accepting socket; error = {:?}", e),
+                            if let Err(e) =
+                                handshake::respond(&mut transport, &handshake, HANDSHAKE_TIMEOUT)
+                                    .await
+                            {
+                                warn!("Rejected connection from {}, {}", peer, e);
+                                return None;
+                            }
+                            Some(transport)
+                        };
+                        // Connections still shaking hands have nothing in flight to wait for
+                        let transport = tokio::select! {
+                            transport = established => match transport {
+                                Some(transport) => transport,
+                                None => return,
+                            },
+                            _ = signal.triggered() => {
+                                debug!("Dropped connection from {} in handshake, shutting down", peer);
+                                return;
+                            }
+                        };
+                        serve_connection(
+                            transport,
+                            callback,
+                            max_inflight,
+                            max_frame_size,
+                            max_request_timeout,
+                            signal,
+                        )
+                        .await;
+                        drop(connection);
+                    });
                 }
+                Err(e) => error!("error accepting socket; error = {:?}", e),
             }
         }
+        // Connections are drained by their own tasks, the listener is released on return
+        debug!("Server {} stopped accepting connections", addr);
+        shortcut::unregister_server(options.namespace, addr).await;
         Ok(())
     }
 }
+
+// Sent instead of responses over the frame size limit, with their size and the limit. RPC
+// responses never start with its first byte otherwise.
+pub(crate) fn encode_response_too_large(size: usize, max: usize) -> BytesMut {
+    let mut res = BytesMut::with_capacity(17);
+    res.put_u8(3u8);
+    res.put_u64_le(size as u64);
+    res.put_u64_le(max as u64);
+    res
+}
+
+// Frames from one connection are dispatched concurrently and responses are written back
+// as soon as they are ready. Clients match responses to requests by the message id.
+async fn serve_connection(
+    transport: FramedStream,
+    callback: TcpCallback,
+    max_inflight: usize,
+    max_frame_size: usize,
+    max_request_timeout: Duration,
+    mut signal: ShutdownSignal,
+) {
+    let (mut writer, mut reader) = transport.split();
+    let (res_tx, mut res_rx) = mpsc::unbounded_channel::<Bytes>();
+    let mut write_task = tokio::spawn(async move {
+        while let Some(res) = res_rx.recv().await {
+            if let Err(e) = writer.send(res).await {
+                error!("Error on TCP callback {:?}", e);
+                break;
+            }
+        }
+    });
+    let inflight = Arc::new(Semaphore::new(max_inflight));
+    // Aborted when the shutdown deadline passes
+    let mut handlers = JoinSet::new();
+    loop {
+        let result = tokio::select! {
+            result = reader.next() => match result {
+                Some(result) => result,
+                None => break,
+            },
+            Some(_) = handlers.join_next() => continue,
+            _ = signal.triggered() => break,
+        };
+        match result {
+            Ok(mut data) => {
+                if data.len() < FRAME_HEADER_SIZE {
+                    error!("Request frame of {} bytes has no header", data.len());
+                    break;
+                }
+                let msg_id = data.get_u64_le();
+                // Comes from the peer, unbounded it could overflow the deadline
+                let timeout = Duration::from_millis(data.get_u64_le()).min(max_request_timeout);
+                let deadline = Instant::now() + timeout;
+                let permit = tokio::select! {
+                    permit = inflight.clone().acquire_owned() => match permit {
+                        Ok(permit) => permit,
+                        Err(_) => break,
+                    },
+                    _ = signal.triggered() => break,
+                };
+                if Instant::now() >= deadline {
+                    // Held back by the in-flight limit until the client gave up
+                    debug!("Skipped request {}, deadline passed", msg_id);
+                    continue;
+                }
+                let callback = callback.clone();
+                let res_tx = res_tx.clone();
+                handlers.spawn(async move {
+                    // The client stops waiting at the deadline, so do we
+                    let call_back_data = match time::timeout_at(deadline, callback(data)).await {
+                        Ok(data) => data,
+                        Err(_) => {
+                            debug!("Dropped request {} after its deadline", msg_id);
+                            return;
+                        }
+                    };
+                    let mut res = BytesMut::with_capacity(8 + call_back_data.len());
+                    res.put_u64_le(msg_id);
+                    res.extend_from_slice(call_back_data.as_ref());
+                    if res.len() > max_frame_size {
+                        // The client would drop the connection on such frame, it gets told
+                        // the response was too large instead
+                        error!(
+                            "Response {} of {} bytes exceeds frame size limit {}",
+                            msg_id,
+                            res.len(),
+                            max_frame_size
+                        );
+                        let size = res.len();
+                        res.clear();
+                        res.put_u64_le(msg_id);
+                        res.extend_from_slice(&encode_response_too_large(size, max_frame_size));
+                    }
+                    if res_tx.send(res.freeze()).is_err() {
+                        debug!("Connection closed before response {} was sent", msg_id);
+                    }
+                    drop(permit);
+                });
+            }
+            Err(e) => {
+                // Framing is lost after oversized frames, the connection cannot be reused
+                error!("error on decoding from socket; error = {:?}", e);
+                break;
+            }
+        }
+    }
+    // The connection will be closed at this point as `reader.next()` has returned `None`,
+    // or the server is shutting down. Pending responses are still flushed before the
+    // writer is dropped.
+    drop(res_tx);
+    let drained = {
+        let drain = async {
+            while handlers.join_next().await.is_some() {}
+            let _ = (&mut write_task).await;
+        };
+        tokio::pin!(drain);
+        tokio::select! {
+            _ = &mut drain => true,
+            deadline = signal.triggered() => time::timeout_at(deadline, &mut drain).await.is_ok(),
+        }
+    };
+    if !drained {
+        warn!("Dropping in-flight requests after shutdown deadline");
+        handlers.abort_all();
+        write_task.abort();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::FutureExt;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use tokio::net::TcpStream;
+    use tokio::time::sleep;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn out_of_order_responses() {
+        let _ = env_logger::try_init();
+        let addr = String::from("127.0.0.1:1350");
+        {
+            let addr = addr.clone();
+            tokio::spawn(async move {
+                Server::new(
+                    &addr,
+                    Arc::new(|mut data: TcpReq| {
+                        async move {
+                            let delay = data.get_u64_le();
+                            sleep(Duration::from_millis(delay)).await;
+                            data
+                        }
+                        .boxed()
+                    }),
+                )
+                .await
+                .unwrap();
+            });
+        }
+        sleep(Duration::from_millis(500)).await;
+        let socket = TcpStream::connect(&addr).await.unwrap();
+        let mut transport: FramedStream =
+            Framed::new(Box::new(socket), FrameCodec::new(DEFAULT_MAX_FRAME_SIZE));
+        let local = Handshake::new(0, hash_str(&addr));
+        handshake::initiate(&mut transport, &local, HANDSHAKE_TIMEOUT)
+            .await
+            .unwrap();
+        for (msg_id, delay) in vec![(1u64, 1000u64), (2, 0)] {
+            let mut frame = BytesMut::new();
+            frame.put_u64_le(msg_id);
+            frame.put_u64_le(5000);
+            frame.put_u64_le(delay);
+            transport.send(frame.freeze()).await.unwrap();
+        }
+        let mut first = transport.next().await.unwrap().unwrap();
+        let mut second = transport.next().await.unwrap().unwrap();
+        assert_eq!(first.get_u64_le(), 2);
+        assert_eq!(second.get_u64_le(), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn unix_socket() {
+        let _ = env_logger::try_init();
+        let path = std::env::temp_dir().join("bifrost-unix-socket-test.sock");
+        let addr = format!("unix:{}", path.display());
+        {
+            let addr = addr.clone();
+            tokio::spawn(async move {
+                Server::new(&addr, Arc::new(|data: TcpReq| async move { data }.boxed()))
+                    .await
+                    .unwrap();
+            });
+        }
+        sleep(Duration::from_millis(500)).await;
+        // Another spelling of the same path so the shortcut is not taken
+        let client_addr = format!(
+            "unix:{}/./bifrost-unix-socket-test.sock",
+            path.parent().unwrap().display()
+        );
+        let client = crate::tcp::client::Client::connect(&client_addr)
+            .await
+            .unwrap();
+        assert!(!shortcut::is_local(Namespace::default(), client.server_id).await);
+        let res = client
+            .send_msg(BytesMut::from(&b"hello"[..]))
+            .await
+            .unwrap();
+        assert_eq!(&res[..], b"hello");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn graceful_shutdown() {
+        let _ = env_logger::try_init();
+        let addr = String::from("127.0.0.1:1351");
+        let shutdown = Shutdown::new();
+        let server = {
+            let addr = addr.clone();
+            let shutdown = shutdown.clone();
+            tokio::spawn(async move {
+                Server::new_with_shutdown(
+                    &addr,
+                    Arc::new(|data: TcpReq| {
+                        async move {
+                            sleep(Duration::from_millis(500)).await;
+                            data
+                        }
+                        .boxed()
+                    }),
+                    ServerOptions::default(),
+                    &shutdown,
+                )
+                .await
+                .unwrap();
+            })
+        };
+        sleep(Duration::from_millis(500)).await;
+        assert!(shortcut::is_local(Namespace::default(), hash_str(&addr)).await);
+        let socket = TcpStream::connect(&addr).await.unwrap();
+        let mut transport: FramedStream =
+            Framed::new(Box::new(socket), FrameCodec::new(DEFAULT_MAX_FRAME_SIZE));
+        let local = Handshake::new(0, hash_str(&addr));
+        handshake::initiate(&mut transport, &local, HANDSHAKE_TIMEOUT)
+            .await
+            .unwrap();
+        let mut frame = BytesMut::new();
+        frame.put_u64_le(1);
+        frame.put_u64_le(5000);
+        transport.send(frame.freeze()).await.unwrap();
+        sleep(Duration::from_millis(100)).await;
+
+        shutdown.shutdown(Duration::from_secs(5)).await;
+        server.await.unwrap();
+        // In-flight request is answered before the connection is closed
+        let mut res = transport.next().await.unwrap().unwrap();
+        assert_eq!(res.get_u64_le(), 1);
+        assert!(transport.next().await.is_none());
+        assert!(!shortcut::is_local(Namespace::default(), hash_str(&addr)).await);
+        // Port is released
+        tokio::net::TcpListener::bind(&addr).await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn shutdown_deadline() {
+        let _ = env_logger::try_init();
+        let addr = String::from("127.0.0.1:1353");
+        let shutdown = Shutdown::new();
+        let finished = Arc::new(AtomicUsize::new(0));
+        let server = {
+            let addr = addr.clone();
+            let shutdown = shutdown.clone();
+            let finished = finished.clone();
+            tokio::spawn(async move {
+                Server::new_with_shutdown(
+                    &addr,
+                    Arc::new(move |data: TcpReq| {
+                        let finished = finished.clone();
+                        async move {
+                            sleep(Duration::from_millis(2000)).await;
+                            finished.fetch_add(1, Ordering::Relaxed);
+                            data
+                        }
+                        .boxed()
+                    }),
+                    ServerOptions::default(),
+                    &shutdown,
+                )
+                .await
+                .unwrap();
+            })
+        };
+        sleep(Duration::from_millis(500)).await;
+        // Never shakes hands
+        let _idle = TcpStream::connect(&addr).await.unwrap();
+        let socket = TcpStream::connect(&addr).await.unwrap();
+        let mut transport: FramedStream =
+            Framed::new(Box::new(socket), FrameCodec::new(DEFAULT_MAX_FRAME_SIZE));
+        let local = Handshake::new(0, hash_str(&addr));
+        handshake::initiate(&mut transport, &local, HANDSHAKE_TIMEOUT)
+            .await
+            .unwrap();
+        let mut frame = BytesMut::new();
+        frame.put_u64_le(1);
+        frame.put_u64_le(5000);
+        transport.send(frame.freeze()).await.unwrap();
+        sleep(Duration::from_millis(100)).await;
+
+        let started = Instant::now();
+        shutdown.shutdown(Duration::from_millis(300)).await;
+        server.await.unwrap();
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert!(transport.next().await.is_none());
+        // The handler was aborted at the deadline
+        sleep(Duration::from_millis(2500)).await;
+        assert_eq!(finished.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn expired_requests() {
+        let _ = env_logger::try_init();
+        let addr = String::from("127.0.0.1:1352");
+        let finished = Arc::new(AtomicUsize::new(0));
+        {
+            let addr = addr.clone();
+            let finished = finished.clone();
+            tokio::spawn(async move {
+                Server::new(
+                    &addr,
+                    Arc::new(move |data: TcpReq| {
+                        let finished = finished.clone();
+                        async move {
+                            sleep(Duration::from_millis(300)).await;
+                            finished.fetch_add(1, Ordering::Relaxed);
+                            data
+                        }
+                        .boxed()
+                    }),
+                )
+                .await
+                .unwrap();
+            });
+        }
+        sleep(Duration::from_millis(500)).await;
+        let socket = TcpStream::connect(&addr).await.unwrap();
+        let mut transport: FramedStream =
+            Framed::new(Box::new(socket), FrameCodec::new(DEFAULT_MAX_FRAME_SIZE));
+        let local = Handshake::new(0, hash_str(&addr));
+        handshake::initiate(&mut transport, &local, HANDSHAKE_TIMEOUT)
+            .await
+            .unwrap();
+        // The first request gives up before the handler is done and gets cancelled
+        for (msg_id, wait) in vec![(1u64, 100u64), (2, 1000)] {
+            let mut frame = BytesMut::new();
+            frame.put_u64_le(msg_id);
+            frame.put_u64_le(wait);
+            transport.send(frame.freeze()).await.unwrap();
+        }
+        let mut res = transport.next().await.unwrap().unwrap();
+        assert_eq!(res.get_u64_le(), 2);
+        sleep(Duration::from_millis(300)).await;
+        assert_eq!(finished.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn unbounded_timeout() {
+        let _ = env_logger::try_init();
+        let addr = String::from("127.0.0.1:1354");
+        {
+            let addr = addr.clone();
+            tokio::spawn(async move {
+                Server::new(&addr, Arc::new(|data: TcpReq| async move { data }.boxed()))
+                    .await
+                    .unwrap();
+            });
+        }
+        sleep(Duration::from_millis(500)).await;
+        let socket = TcpStream::connect(&addr).await.unwrap();
+        let mut transport: FramedStream =
+            Framed::new(Box::new(socket), FrameCodec::new(DEFAULT_MAX_FRAME_SIZE));
+        let local = Handshake::new(0, hash_str(&addr));
+        handshake::initiate(&mut transport, &local, HANDSHAKE_TIMEOUT)
+            .await
+            .unwrap();
+        // Would overflow the deadline if it was taken as is
+        let mut frame = BytesMut::new();
+        frame.put_u64_le(1);
+        frame.put_u64_le(u64::MAX);
+        frame.put_u64_le(42);
+        transport.send(frame.freeze()).await.unwrap();
+        let mut res = transport.next().await.unwrap().unwrap();
+        assert_eq!(res.get_u64_le(), 1);
+        assert_eq!(res.get_u64_le(), 42);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn oversized_response() {
+        let _ = env_logger::try_init();
+        let addr = String::from("127.0.0.1:1355");
+        {
+            let addr = addr.clone();
+            tokio::spawn(async move {
+                let options = ServerOptions {
+                    max_frame_size: 64,
+                    ..Default::default()
+                };
+                let callback = Arc::new(|data: TcpReq| {
+                    async move {
+                        match data.as_ref() {
+                            b"large" => BytesMut::from(&[0u8; 100][..]),
+                            _ => data,
+                        }
+                    }
+                    .boxed()
+                });
+                Server::new_with_options(&addr, callback, options)
+                    .await
+                    .unwrap();
+            });
+        }
+        sleep(Duration::from_millis(500)).await;
+        let socket = TcpStream::connect(&addr).await.unwrap();
+        let mut transport: FramedStream =
+            Framed::new(Box::new(socket), FrameCodec::new(DEFAULT_MAX_FRAME_SIZE));
+        let local = Handshake::new(0, hash_str(&addr));
+        handshake::initiate(&mut transport, &local, HANDSHAKE_TIMEOUT)
+            .await
+            .unwrap();
+        for (msg_id, body) in vec![(1u64, &b"large"[..]), (2, &b"small"[..])] {
+            let mut frame = BytesMut::new();
+            frame.put_u64_le(msg_id);
+            frame.put_u64_le(1000);
+            frame.extend_from_slice(body);
+            transport.send(frame.freeze()).await.unwrap();
+        }
+        // The connection stays usable after the response that was too large
+        let mut responses = vec![];
+        for _ in 0..2 {
+            let mut res = transport.next().await.unwrap().unwrap();
+            let msg_id = res.get_u64_le();
+            responses.push((msg_id, res));
+        }
+        responses.sort_by_key(|(msg_id, _)| *msg_id);
+        assert_eq!(responses[0].1, encode_response_too_large(108, 64));
+        assert_eq!(responses[1].1, BytesMut::from(&b"small"[..]));
+    }
+}
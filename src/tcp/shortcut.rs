@@ -4,28 +4,47 @@ use bifrost_hasher::hash_str;
 use bytes::BytesMut;
 use std::collections::BTreeMap;
 use std::io::{Error, ErrorKind, Result};
+use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
 use std::sync::Arc;
 
 trait TcpCallbackFunc = Fn(TcpReq) -> TcpRes;
 trait TcpCallbackFuncShareable = TcpCallbackFunc + Send + Sync;
 
+// Servers are only reachable through the shortcut from clients in the same namespace, so
+// independent clusters in one process can reuse addresses. The default namespace is
+// shared by everyone not asking for an isolated one.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Namespace(u64);
+
+static NAMESPACE_COUNTER: AtomicU64 = AtomicU64::new(1);
+
+impl Namespace {
+    pub fn isolated() -> Self {
+        Namespace(NAMESPACE_COUNTER.fetch_add(1, Relaxed))
+    }
+    pub fn id(&self) -> u64 {
+        self.0
+    }
+}
+
 lazy_static! {
-    pub static ref TCP_CALLBACKS: RwLock<BTreeMap<u64, Arc<dyn TcpCallbackFuncShareable>>> =
+    pub static ref TCP_CALLBACKS: RwLock<BTreeMap<(Namespace, u64), Arc<dyn TcpCallbackFuncShareable>>> =
         RwLock::new(BTreeMap::new());
 }
 
 pub async fn register_server(
+    namespace: Namespace,
     server_address: &String,
     callback: &Arc<dyn TcpCallbackFuncShareable>,
 ) {
     let server_id = hash_str(server_address);
     let mut servers_cbs = TCP_CALLBACKS.write().await;
-    servers_cbs.insert(server_id, callback.clone());
+    servers_cbs.insert((namespace, server_id), callback.clone());
 }
 
-pub async fn call(server_id: u64, data: TcpReq) -> Result<BytesMut> {
+pub async fn call(namespace: Namespace, server_id: u64, data: TcpReq) -> Result<BytesMut> {
     let server_cbs = TCP_CALLBACKS.read().await;
-    match server_cbs.get(&server_id) {
+    match server_cbs.get(&(namespace, server_id)) {
         Some(c) => Ok(c(data).await),
         _ => Err(Error::new(
             ErrorKind::Other,
@@ -34,7 +53,13 @@ pub async fn call(server_id: u64, data: TcpReq) -> Result<BytesMut> {
     }
 }
 
-pub async fn is_local(server_id: u64) -> bool {
+pub async fn is_local(namespace: Namespace, server_id: u64) -> bool {
     let cbs = TCP_CALLBACKS.read().await;
-    cbs.contains_key(&server_id)
+    cbs.contains_key(&(namespace, server_id))
+}
+
+pub async fn unregister_server(namespace: Namespace, server_address: &String) {
+    let server_id = hash_str(server_address);
+    let mut servers_cbs = TCP_CALLBACKS.write().await;
+    servers_cbs.remove(&(namespace, server_id));
 }
@@ -0,0 +1,438 @@
+// In-memory network for tests. Frames between every pair of nodes go through a relay
+// that can partition, delay, drop or reorder them.
+//
+// Drops and jitter are drawn from a generator per connection and direction, seeded from
+// the network seed, the two nodes and how many connections they made before. The same
+// frames on the same connections get the same fate in every run, however tasks are
+// scheduled. Delays follow the tokio clock, tests with a paused clock are deterministic.
+
+use super::transport::{BoxedListener, Listener, SharedTransport, Transport};
+use super::{codec, BoxedStream};
+use bifrost_hasher::hash_str;
+use bytes::Bytes;
+use futures::future::{self, BoxFuture};
+use futures::{FutureExt, SinkExt, StreamExt};
+use parking_lot::Mutex;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{DuplexStream, ReadHalf, WriteHalf};
+use tokio::sync::mpsc;
+use tokio::time::{self, Instant};
+use tokio_util::codec::{FramedRead, FramedWrite};
+
+const BUFFER_SIZE: usize = 64 * 1024;
+
+// Rules applied to frames travelling in one direction between two nodes
+#[derive(Clone, Debug, Default)]
+pub struct LinkRules {
+    // Every frame is silently lost, like an unreachable host
+    pub partitioned: bool,
+    // Probability of losing each frame, from 0 to 1
+    pub drop_rate: f64,
+    pub delay: Duration,
+    // Random extra delay per frame up to this value, frames may overtake each other
+    pub jitter: Duration,
+}
+
+enum Delivery {
+    Dropped,
+    InOrder(Duration),
+    Anytime(Duration),
+}
+
+struct Network {
+    listeners: Mutex<HashMap<String, mpsc::UnboundedSender<(BoxedStream, String)>>>,
+    links: Mutex<HashMap<(String, String), LinkRules>>,
+    // Connections made so far from one node to another
+    connections: Mutex<HashMap<(String, String), u64>>,
+    seed: u64,
+}
+
+pub struct SimNetwork {
+    network: Arc<Network>,
+}
+
+struct SimTransport {
+    network: Arc<Network>,
+    node: String,
+}
+
+struct SimListener {
+    streams: mpsc::UnboundedReceiver<(BoxedStream, String)>,
+}
+
+impl SimNetwork {
+    // Random drops and jitter are derived from `seed`
+    pub fn new(seed: u64) -> Self {
+        SimNetwork {
+            network: Arc::new(Network {
+                listeners: Mutex::new(HashMap::new()),
+                links: Mutex::new(HashMap::new()),
+                connections: Mutex::new(HashMap::new()),
+                seed,
+            }),
+        }
+    }
+
+    // Transport for servers and clients on node `node`, which is also the address
+    // its server listens on
+    pub fn node(&self, node: &str) -> SharedTransport {
+        Arc::new(SimTransport {
+            network: self.network.clone(),
+            node: node.to_string(),
+        })
+    }
+
+    pub fn set_link(&self, from: &str, to: &str, rules: LinkRules) {
+        self.network
+            .links
+            .lock()
+            .insert((from.to_string(), to.to_string()), rules);
+    }
+
+    pub fn partition(&self, a: &str, b: &str) {
+        self.update_links(a, b, |rules| rules.partitioned = true);
+    }
+
+    pub fn heal(&self, a: &str, b: &str) {
+        self.update_links(a, b, |rules| rules.partitioned = false);
+    }
+
+    // Isolates `node` from every other node known to the network
+    pub fn isolate(&self, node: &str) {
+        let others: Vec<_> = self
+            .network
+            .listeners
+            .lock()
+            .keys()
+            .filter(|other| other.as_str() != node)
+            .cloned()
+            .collect();
+        for other in others {
+            self.partition(node, &other);
+        }
+    }
+
+    pub fn heal_all(&self) {
+        for rules in self.network.links.lock().values_mut() {
+            rules.partitioned = false;
+        }
+    }
+
+    fn update_links<F>(&self, a: &str, b: &str, update: F)
+    where
+        F: Fn(&mut LinkRules),
+    {
+        let mut links = self.network.links.lock();
+        for key in vec![(a, b), (b, a)] {
+            let rules = links
+                .entry((key.0.to_string(), key.1.to_string()))
+                .or_insert_with(LinkRules::default);
+            update(rules);
+        }
+    }
+}
+
+impl Network {
+    fn is_partitioned(&self, from: &String, to: &String) -> bool {
+        match self.links.lock().get(&(from.clone(), to.clone())) {
+            Some(rules) => rules.partitioned,
+            None => false,
+        }
+    }
+
+    // Generators for frames there and back on the next connection from `from` to `to`
+    fn connection_rngs(&self, from: &String, to: &String) -> (StdRng, StdRng) {
+        let mut connections = self.connections.lock();
+        let count = connections.entry((from.clone(), to.clone())).or_insert(0);
+        *count += 1;
+        let rng = |direction: &str| {
+            let key = format!("{}/{}/{}/{}/{}", self.seed, from, to, count, direction);
+            StdRng::seed_from_u64(hash_str(&key))
+        };
+        (rng("there"), rng("back"))
+    }
+
+    fn delivery(&self, from: &String, to: &String, rng: &mut StdRng) -> Delivery {
+        let rules = match self.links.lock().get(&(from.clone(), to.clone())) {
+            Some(rules) => rules.clone(),
+            None => return Delivery::InOrder(Duration::from_millis(0)),
+        };
+        if rules.partitioned || (rules.drop_rate > 0.0 && rng.gen_bool(rules.drop_rate.min(1.0))) {
+            Delivery::Dropped
+        } else if rules.jitter > Duration::from_millis(0) {
+            let jitter = rng.gen_range(0..=rules.jitter.as_micros() as u64);
+            Delivery::Anytime(rules.delay + Duration::from_micros(jitter))
+        } else {
+            Delivery::InOrder(rules.delay)
+        }
+    }
+}
+
+// Moves frames written by `from` into the stream read by `to`, frame size limits are left
+// to the endpoints
+fn spawn_relay(
+    network: Arc<Network>,
+    from: String,
+    to: String,
+    mut rng: StdRng,
+    reader: ReadHalf<DuplexStream>,
+    writer: WriteHalf<DuplexStream>,
+) {
+    let (tx, mut rx) = mpsc::unbounded_channel::<(Instant, Bytes)>();
+    tokio::spawn(async move {
+        let mut writer = FramedWrite::new(writer, codec(usize::MAX));
+        while let Some((deliver_at, frame)) = rx.recv().await {
+            time::sleep_until(deliver_at).await;
+            if writer.send(frame).await.is_err() {
+                break;
+            }
+        }
+    });
+    tokio::spawn(async move {
+        let mut reader = FramedRead::new(reader, codec(usize::MAX));
+        while let Some(Ok(frame)) = reader.next().await {
+            let frame = frame.freeze();
+            match network.delivery(&from, &to, &mut rng) {
+                Delivery::Dropped => trace!("Dropped frame from {} to {}", from, to),
+                Delivery::InOrder(delay) => {
+                    let _ = tx.send((Instant::now() + delay, frame));
+                }
+                Delivery::Anytime(delay) => {
+                    let tx = tx.clone();
+                    tokio::spawn(async move {
+                        time::sleep(delay).await;
+                        let _ = tx.send((Instant::now(), frame));
+                    });
+                }
+            }
+        }
+    });
+}
+
+impl Transport for SimTransport {
+    // Like a socket, an address can be listened on again once its listener is dropped
+    fn listen(&self, address: &String) -> BoxFuture<'static, io::Result<BoxedListener>> {
+        let mut listeners = self.network.listeners.lock();
+        if let Some(listener) = listeners.get(address) {
+            if !listener.is_closed() {
+                let e = io::Error::new(io::ErrorKind::AddrInUse, format!("{} is in use", address));
+                return future::ready(Err(e)).boxed();
+            }
+        }
+        let (tx, rx) = mpsc::unbounded_channel();
+        listeners.insert(address.clone(), tx);
+        future::ready(Ok(Box::new(SimListener { streams: rx }) as BoxedListener)).boxed()
+    }
+
+    fn connect(&self, address: &String) -> BoxFuture<'static, io::Result<BoxedStream>> {
+        let network = self.network.clone();
+        let from = self.node.clone();
+        let to = address.clone();
+        async move {
+            if network.is_partitioned(&from, &to) {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("{} is partitioned from {}", from, to),
+                ));
+            }
+            let listener = match network.listeners.lock().get(&to) {
+                Some(listener) => listener.clone(),
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::ConnectionRefused,
+                        format!("No simulated server on {}", to),
+                    ))
+                }
+            };
+            let (client, client_relay) = tokio::io::duplex(BUFFER_SIZE);
+            let (server, server_relay) = tokio::io::duplex(BUFFER_SIZE);
+            let (client_reader, client_writer) = tokio::io::split(client_relay);
+            let (server_reader, server_writer) = tokio::io::split(server_relay);
+            let (there, back) = network.connection_rngs(&from, &to);
+            spawn_relay(
+                network.clone(),
+                from.clone(),
+                to.clone(),
+                there,
+                client_reader,
+                server_writer,
+            );
+            spawn_relay(
+                network,
+                to.clone(),
+                from.clone(),
+                back,
+                server_reader,
+                client_writer,
+            );
+            listener
+                .send((Box::new(server), from))
+                .map_err(|_| io::Error::new(io::ErrorKind::ConnectionRefused, to))?;
+            Ok(Box::new(client) as BoxedStream)
+        }
+        .boxed()
+    }
+
+    fn allows_shortcut(&self) -> bool {
+        false
+    }
+}
+
+impl Listener for SimListener {
+    fn accept(&mut self) -> BoxFuture<'_, io::Result<(BoxedStream, String)>> {
+        async move {
+            match self.streams.recv().await {
+                Some(accepted) => Ok(accepted),
+                None => Err(io::Error::new(
+                    io::ErrorKind::NotConnected,
+                    "Simulated network dropped",
+                )),
+            }
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tcp::client::{Client, ClientOptions};
+    use crate::tcp::server::{Server, ServerOptions, TcpReq};
+    use bytes::BytesMut;
+
+    async fn start_echo_server(network: &SimNetwork, node: &str) {
+        let options = ServerOptions {
+            transport: network.node(node),
+            ..ServerOptions::default()
+        };
+        let addr = node.to_string();
+        tokio::spawn(async move {
+            Server::new_with_options(
+                &addr,
+                Arc::new(|data: TcpReq| async move { data }.boxed()),
+                options,
+            )
+            .await
+            .unwrap();
+        });
+        time::sleep(Duration::from_millis(100)).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn partition_and_delay() {
+        let _ = env_logger::try_init();
+        let network = SimNetwork::new(1);
+        start_echo_server(&network, "b").await;
+        let client = Client::connect_with_options(
+            &String::from("b"),
+            ClientOptions {
+                timeout: Duration::from_millis(500),
+                transport: network.node("a"),
+                ..ClientOptions::default()
+            },
+        )
+        .await
+        .unwrap();
+        let hello = || BytesMut::from(&b"hello"[..]);
+        assert_eq!(&client.send_msg(hello()).await.unwrap()[..], b"hello");
+
+        network.partition("a", "b");
+        let err = client.send_msg(hello()).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        network.heal("a", "b");
+        assert_eq!(&client.send_msg(hello()).await.unwrap()[..], b"hello");
+
+        network.set_link(
+            "a",
+            "b",
+            LinkRules {
+                delay: Duration::from_millis(200),
+                ..LinkRules::default()
+            },
+        );
+        let start = Instant::now();
+        assert_eq!(&client.send_msg(hello()).await.unwrap()[..], b"hello");
+        assert!(start.elapsed() >= Duration::from_millis(200));
+
+        network.set_link(
+            "b",
+            "a",
+            LinkRules {
+                drop_rate: 1.0,
+                ..LinkRules::default()
+            },
+        );
+        assert!(client.send_msg(hello()).await.is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn relisten() {
+        let network = SimNetwork::new(4);
+        let transport = network.node("e");
+        let addr = String::from("e");
+        let listener = transport.listen(&addr).await.unwrap();
+        let err = transport.listen(&addr).await.err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+        drop(listener);
+        let _listener = transport.listen(&addr).await.unwrap();
+    }
+
+    // Echoes 20 requests from `c` to `d` over a link with jitter, in the order of replies
+    async fn jittered_echoes(seed: u64) -> Vec<u8> {
+        let network = SimNetwork::new(seed);
+        start_echo_server(&network, "d").await;
+        network.set_link(
+            "c",
+            "d",
+            LinkRules {
+                jitter: Duration::from_millis(300),
+                ..LinkRules::default()
+            },
+        );
+        let client = Arc::new(
+            Client::connect_with_options(
+                &String::from("d"),
+                ClientOptions {
+                    transport: network.node("c"),
+                    ..ClientOptions::default()
+                },
+            )
+            .await
+            .unwrap(),
+        );
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        for i in 0..20u8 {
+            let client = client.clone();
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let res = client.send_msg(BytesMut::from(&[i][..])).await.unwrap();
+                tx.send(res[0]).unwrap();
+            });
+            time::sleep(Duration::from_millis(5)).await;
+        }
+        drop(tx);
+        let mut received = vec![];
+        while let Some(i) = rx.recv().await {
+            received.push(i);
+        }
+        received
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reordering() {
+        let _ = env_logger::try_init();
+        let received = jittered_echoes(2).await;
+        let mut sorted = received.clone();
+        sorted.sort();
+        assert_eq!(sorted, (0..20).collect::<Vec<_>>());
+        // The same seed reorders the same way every time, this one does reorder
+        assert_eq!(jittered_echoes(2).await, received);
+        assert_ne!(received, sorted);
+    }
+}
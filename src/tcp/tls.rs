@@ -0,0 +1,200 @@
+use super::{AsyncStream, BoxedStream};
+use std::io::{BufReader, Error, ErrorKind, Result};
+use std::sync::Arc;
+use tokio_rustls::rustls::internal::pemfile;
+use tokio_rustls::rustls::{
+    AllowAnyAuthenticatedClient, Certificate, ClientConfig, NoClientAuth, PrivateKey,
+    RootCertStore, ServerConfig,
+};
+use tokio_rustls::webpki::DNSNameRef;
+pub use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+#[derive(Clone)]
+pub struct TlsClientOptions {
+    pub connector: TlsConnector,
+    // Name the server certificate is checked against, usually shared by all nodes in a cluster
+    pub server_name: String,
+}
+
+fn invalid_data<M: Into<String>>(msg: M) -> Error {
+    Error::new(ErrorKind::InvalidData, msg.into())
+}
+
+fn parse_certs(pem: &[u8]) -> Result<Vec<Certificate>> {
+    let certs = pemfile::certs(&mut BufReader::new(pem))
+        .map_err(|_| invalid_data("Cannot parse certificates"))?;
+    if certs.is_empty() {
+        return Err(invalid_data("No certificate found"));
+    }
+    Ok(certs)
+}
+
+fn parse_key(pem: &[u8]) -> Result<PrivateKey> {
+    let mut keys = pemfile::pkcs8_private_keys(&mut BufReader::new(pem))
+        .map_err(|_| invalid_data("Cannot parse private key"))?;
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut BufReader::new(pem))
+            .map_err(|_| invalid_data("Cannot parse private key"))?;
+    }
+    keys.pop()
+        .ok_or_else(|| invalid_data("No private key found"))
+}
+
+fn parse_roots(pem: &[u8]) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in parse_certs(pem)? {
+        roots
+            .add(&cert)
+            .map_err(|e| invalid_data(format!("Invalid CA certificate, {:?}", e)))?;
+    }
+    Ok(roots)
+}
+
+// With `client_ca_pem` the server only accepts peers holding a certificate signed by that CA
+pub fn server_acceptor(
+    cert_chain_pem: &[u8],
+    key_pem: &[u8],
+    client_ca_pem: Option<&[u8]>,
+) -> Result<TlsAcceptor> {
+    let verifier = match client_ca_pem {
+        Some(ca) => AllowAnyAuthenticatedClient::new(parse_roots(ca)?),
+        None => NoClientAuth::new(),
+    };
+    let mut config = ServerConfig::new(verifier);
+    config
+        .set_single_cert(parse_certs(cert_chain_pem)?, parse_key(key_pem)?)
+        .map_err(|e| invalid_data(format!("Invalid server certificate, {:?}", e)))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+// `identity` is the (certificate chain, private key) pair presented for mutual TLS
+pub fn client_options(
+    ca_pem: &[u8],
+    server_name: &str,
+    identity: Option<(&[u8], &[u8])>,
+) -> Result<TlsClientOptions> {
+    let mut config = ClientConfig::new();
+    config.root_store = parse_roots(ca_pem)?;
+    if let Some((cert_chain_pem, key_pem)) = identity {
+        config
+            .set_single_client_cert(parse_certs(cert_chain_pem)?, parse_key(key_pem)?)
+            .map_err(|e| invalid_data(format!("Invalid client certificate, {:?}", e)))?;
+    }
+    DNSNameRef::try_from_ascii_str(server_name)
+        .map_err(|_| invalid_data(format!("Invalid server name {}", server_name)))?;
+    Ok(TlsClientOptions {
+        connector: TlsConnector::from(Arc::new(config)),
+        server_name: server_name.to_string(),
+    })
+}
+
+pub async fn connect<S: AsyncStream + 'static>(
+    socket: S,
+    options: &TlsClientOptions,
+) -> Result<BoxedStream> {
+    let name = DNSNameRef::try_from_ascii_str(&options.server_name)
+        .map_err(|_| invalid_data(format!("Invalid server name {}", options.server_name)))?;
+    Ok(Box::new(options.connector.connect(name, socket).await?))
+}
+
+pub async fn accept<S: AsyncStream + 'static>(
+    socket: S,
+    acceptor: &TlsAcceptor,
+) -> Result<BoxedStream> {
+    Ok(Box::new(acceptor.accept(socket).await?))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tcp::client::{Client, ClientOptions};
+    use crate::tcp::server::{Server, ServerOptions, TcpReq};
+    use bytes::BytesMut;
+    use futures::FutureExt;
+    use rcgen::{BasicConstraints, Certificate as GenCert, CertificateParams, IsCa};
+    use std::time::Duration;
+    use tokio::io;
+    use tokio::time::sleep;
+
+    fn gen_ca() -> GenCert {
+        let mut params = CertificateParams::new(vec![]);
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        GenCert::from_params(params).unwrap()
+    }
+
+    // Returns the (certificate, private key) pem pair signed by `ca`
+    fn gen_signed(ca: &GenCert) -> (String, String) {
+        let cert =
+            GenCert::from_params(CertificateParams::new(vec!["localhost".to_string()])).unwrap();
+        (
+            cert.serialize_pem_with_signer(ca).unwrap(),
+            cert.serialize_private_key_pem(),
+        )
+    }
+
+    async fn connect(addr: &String, tls: TlsClientOptions) -> io::Result<BytesMut> {
+        let client = Client::connect_with_options(
+            addr,
+            ClientOptions {
+                tls: Some(tls),
+                ..ClientOptions::default()
+            },
+        )
+        .await?;
+        client.send_msg(BytesMut::from(&b"hello"[..])).await
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn mutual_tls() {
+        let _ = env_logger::try_init();
+        let ca = gen_ca();
+        let ca_pem = ca.serialize_pem().unwrap();
+        let (server_cert, server_key) = gen_signed(&ca);
+        let acceptor = server_acceptor(
+            server_cert.as_bytes(),
+            server_key.as_bytes(),
+            Some(ca_pem.as_bytes()),
+        )
+        .unwrap();
+        tokio::spawn(async move {
+            Server::new_with_options(
+                &String::from("127.0.0.1:1370"),
+                Arc::new(|data: TcpReq| async move { data }.boxed()),
+                ServerOptions {
+                    tls: Some(acceptor),
+                    ..ServerOptions::default()
+                },
+            )
+            .await
+            .unwrap();
+        });
+        sleep(Duration::from_millis(500)).await;
+        // Connect by another name of the same address so the shortcut is not taken
+        let addr = String::from("localhost:1370");
+
+        let (client_cert, client_key) = gen_signed(&ca);
+        let trusted = client_options(
+            ca_pem.as_bytes(),
+            "localhost",
+            Some((client_cert.as_bytes(), client_key.as_bytes())),
+        )
+        .unwrap();
+        let res = connect(&addr, trusted).await.unwrap();
+        assert_eq!(&res[..], b"hello");
+
+        // Client certificate signed by a CA outside of the cluster
+        let foreign_ca = gen_ca();
+        let (foreign_cert, foreign_key) = gen_signed(&foreign_ca);
+        let foreign = client_options(
+            ca_pem.as_bytes(),
+            "localhost",
+            Some((foreign_cert.as_bytes(), foreign_key.as_bytes())),
+        )
+        .unwrap();
+        assert!(connect(&addr, foreign).await.is_err());
+
+        // No client certificate at all
+        let anonymous = client_options(ca_pem.as_bytes(), "localhost", None).unwrap();
+        assert!(connect(&addr, anonymous).await.is_err());
+    }
+}
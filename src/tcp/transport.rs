@@ -0,0 +1,131 @@
+use super::{unix_socket_path, BoxedStream};
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use std::io;
+use std::os::unix::fs::FileTypeExt;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+
+pub trait Listener: Send {
+    // Returns the accepted stream with a description of the peer for logging
+    fn accept(&mut self) -> BoxFuture<'_, io::Result<(BoxedStream, String)>>;
+}
+
+pub type BoxedListener = Box<dyn Listener>;
+
+// Carries framed bytes between clients and servers. TLS is layered on top by the
+// client and server, so implementations only need to move raw bytes.
+pub trait Transport: Send + Sync {
+    fn listen(&self, address: &String) -> BoxFuture<'static, io::Result<BoxedListener>>;
+    fn connect(&self, address: &String) -> BoxFuture<'static, io::Result<BoxedStream>>;
+    // Servers in the same process are called directly when allowed, bypassing the transport
+    fn allows_shortcut(&self) -> bool {
+        true
+    }
+}
+
+pub type SharedTransport = Arc<dyn Transport>;
+
+lazy_static! {
+    pub static ref DEFAULT_TRANSPORT: SharedTransport = Arc::new(NetTransport);
+}
+
+// TCP, or unix domain sockets for `unix:/path/to.sock` addresses
+pub struct NetTransport;
+
+enum NetListener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl Transport for NetTransport {
+    fn listen(&self, address: &String) -> BoxFuture<'static, io::Result<BoxedListener>> {
+        let address = address.clone();
+        async move {
+            let listener = match unix_socket_path(&address) {
+                Some(path) => {
+                    remove_stale_socket(path).await?;
+                    NetListener::Unix(UnixListener::bind(path)?)
+                }
+                None => NetListener::Tcp(TcpListener::bind(&address).await?),
+            };
+            Ok(Box::new(listener) as BoxedListener)
+        }
+        .boxed()
+    }
+
+    fn connect(&self, address: &String) -> BoxFuture<'static, io::Result<BoxedStream>> {
+        let address = address.clone();
+        async move {
+            let stream: BoxedStream = match unix_socket_path(&address) {
+                Some(path) => Box::new(UnixStream::connect(path).await?),
+                None => Box::new(TcpStream::connect(&address).await?),
+            };
+            Ok(stream)
+        }
+        .boxed()
+    }
+}
+
+// A socket file left behind by a previous process would fail the bind. Only sockets nobody
+// listens on are removed, other files and live servers make the address in use.
+async fn remove_stale_socket(path: &str) -> io::Result<()> {
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    let in_use = || io::Error::new(io::ErrorKind::AddrInUse, format!("{} is in use", path));
+    if !metadata.file_type().is_socket() {
+        return Err(in_use());
+    }
+    match UnixStream::connect(path).await {
+        Ok(_) => Err(in_use()),
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => std::fs::remove_file(path),
+        Err(e) => Err(e),
+    }
+}
+
+impl Listener for NetListener {
+    fn accept(&mut self) -> BoxFuture<'_, io::Result<(BoxedStream, String)>> {
+        async move {
+            match self {
+                NetListener::Tcp(listener) => {
+                    let (socket, peer) = listener.accept().await?;
+                    Ok((Box::new(socket) as BoxedStream, peer.to_string()))
+                }
+                NetListener::Unix(listener) => {
+                    let (socket, peer) = listener.accept().await?;
+                    Ok((Box::new(socket) as BoxedStream, format!("{:?}", peer)))
+                }
+            }
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn unix_socket_reuse() {
+        let path = std::env::temp_dir().join(format!("bifrost-{}.sock", rand::random::<u64>()));
+        let addr = format!("unix:{}", path.display());
+        // Left behind by a listener that is gone
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+        let listener = NetTransport.listen(&addr).await.unwrap();
+        // Still served
+        let err = NetTransport.listen(&addr).await.err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+        drop(listener);
+        // Not a socket
+        std::fs::remove_file(&path).unwrap();
+        std::fs::write(&path, b"data").unwrap();
+        let err = NetTransport.listen(&addr).await.err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+        assert_eq!(std::fs::read(&path).unwrap(), b"data");
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -1,70 +1,180 @@
-use serde;
 use bifrost_hasher::hash_bytes;
+use serde;
+use std::sync::atomic::{AtomicU8, Ordering};
 
-#[cfg(not(debug_assertions))]
-pub fn serialize<T>(obj: &T) -> Vec<u8>
-where
-    T: serde::Serialize,
-{
-    match serde_cbor::to_vec(obj) {
-        Ok(data) => data,
-        Err(e) => panic!("Cannot serialize: {:?}", e),
-    }
+// Format of everything bifrost serializes, chosen once per process before any server or
+// client starts. Peers have to agree on it during connection handshakes, files on disk
+// record the one they were written with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Codec {
+    Cbor,
+    Json,
+    // Not self-describing, JSON tools like `bifrost-cli` cannot decode it
+    #[cfg(feature = "bincode")]
+    Bincode,
+    #[cfg(feature = "msgpack")]
+    MessagePack,
 }
 
-#[cfg(not(debug_assertions))]
-pub fn deserialize<'a, T>(data: &'a [u8]) -> Option<T>
-where
-    T: serde::Deserialize<'a>,
-{
-    match serde_cbor::from_slice(data) {
-        Ok(obj) => Some(obj),
-        Err(e) => {
-            warn!(
-                "Error on decoding data for type '{}', {}",
-                std::intrinsics::type_name::<T>(),
-                e
-            );
-            None
+pub const DEFAULT_CODEC: Codec = Codec::Cbor;
+
+impl Codec {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Codec::Cbor => "cbor",
+            Codec::Json => "json",
+            #[cfg(feature = "bincode")]
+            Codec::Bincode => "bincode",
+            #[cfg(feature = "msgpack")]
+            Codec::MessagePack => "msgpack",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "cbor" => Some(Codec::Cbor),
+            "json" => Some(Codec::Json),
+            #[cfg(feature = "bincode")]
+            "bincode" => Some(Codec::Bincode),
+            #[cfg(feature = "msgpack")]
+            "msgpack" => Some(Codec::MessagePack),
+            _ => None,
         }
     }
+
+    // Data can be decoded without knowing its types, as JSON values for instance
+    pub fn self_describing(&self) -> bool {
+        match self {
+            Codec::Cbor | Codec::Json => true,
+            #[cfg(feature = "bincode")]
+            Codec::Bincode => false,
+            #[cfg(feature = "msgpack")]
+            Codec::MessagePack => true,
+        }
+    }
+
+    // Recorded in file headers
+    pub const fn id(&self) -> u8 {
+        match self {
+            Codec::Cbor => 1,
+            Codec::Json => 2,
+            #[cfg(feature = "bincode")]
+            Codec::Bincode => 3,
+            #[cfg(feature = "msgpack")]
+            Codec::MessagePack => 4,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            1 => Some(Codec::Cbor),
+            2 => Some(Codec::Json),
+            #[cfg(feature = "bincode")]
+            3 => Some(Codec::Bincode),
+            #[cfg(feature = "msgpack")]
+            4 => Some(Codec::MessagePack),
+            _ => None,
+        }
+    }
+
+    pub fn serialize<T>(&self, obj: &T) -> Vec<u8>
+    where
+        T: serde::Serialize,
+    {
+        let res = match self {
+            Codec::Cbor => serde_cbor::to_vec(obj).map_err(|e| e.to_string()),
+            Codec::Json => serde_json::to_vec(obj).map_err(|e| e.to_string()),
+            #[cfg(feature = "bincode")]
+            Codec::Bincode => bincode::serialize(obj).map_err(|e| e.to_string()),
+            #[cfg(feature = "msgpack")]
+            Codec::MessagePack => rmp_serde::to_vec_named(obj).map_err(|e| e.to_string()),
+        };
+        match res {
+            Ok(data) => data,
+            Err(e) => panic!("Cannot serialize with {}: {}", self.name(), e),
+        }
+    }
+
+    pub fn deserialize<'a, T>(&self, data: &'a [u8]) -> Option<T>
+    where
+        T: serde::Deserialize<'a>,
+    {
+        let res = match self {
+            Codec::Cbor => serde_cbor::from_slice(data).map_err(|e| e.to_string()),
+            Codec::Json => serde_json::from_slice(data).map_err(|e| e.to_string()),
+            #[cfg(feature = "bincode")]
+            Codec::Bincode => bincode::deserialize(data).map_err(|e| e.to_string()),
+            #[cfg(feature = "msgpack")]
+            Codec::MessagePack => rmp_serde::from_read_ref(data).map_err(|e| e.to_string()),
+        };
+        match res {
+            Ok(obj) => Some(obj),
+            Err(e) => {
+                warn!(
+                    "Error on decoding {} data for type '{}', {}",
+                    self.name(),
+                    std::intrinsics::type_name::<T>(),
+                    e
+                );
+                None
+            }
+        }
+    }
+}
+
+static CODEC: AtomicU8 = AtomicU8::new(DEFAULT_CODEC.id());
+
+pub fn codec() -> Codec {
+    Codec::from_id(CODEC.load(Ordering::Relaxed)).unwrap_or(DEFAULT_CODEC)
+}
+
+// Must be called before any server or client starts, nodes with different codecs cannot
+// talk to each other
+pub fn set_codec(codec: Codec) {
+    CODEC.store(codec.id(), Ordering::Relaxed);
 }
 
-#[cfg(debug_assertions)]
 pub fn serialize<T>(obj: &T) -> Vec<u8>
 where
     T: serde::Serialize,
 {
-    match serde_json::to_vec(obj) {
-        Ok(data) => data,
-        Err(e) => panic!("Cannot serialize: {:?}", e),
-    }
+    codec().serialize(obj)
 }
 
-#[cfg(debug_assertions)]
 pub fn deserialize<'a, T>(data: &'a [u8]) -> Option<T>
 where
     T: serde::Deserialize<'a>,
 {
-    let type_name = std::intrinsics::type_name::<T>();
-    match serde_json::from_slice(data) {
-        Ok(obj) => Some(obj),
-        Err(e) => {
-            warn!(
-                "Error on decoding data for type '{}', {}, json: {}",
-                type_name,
-                e,
-                String::from_utf8_lossy(data)
-            );
-            None
-        }
-    }
+    codec().deserialize(data)
 }
 
+// Always hashes the CBOR of `obj`, ids made from hashes stay the same with any codec
 pub fn hash<T>(obj: &T) -> u64
 where
     T: serde::Serialize,
 {
-    let data = serialize(obj);
+    let data = Codec::Cbor.serialize(obj);
     hash_bytes(data.as_slice())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn codecs() {
+        let value = (String::from("bifrost"), 42u64, vec![1u8, 2, 3]);
+        for codec in vec![Codec::Cbor, Codec::Json] {
+            assert_eq!(Codec::from_name(codec.name()), Some(codec));
+            assert_eq!(Codec::from_id(codec.id()), Some(codec));
+            let data = codec.serialize(&value);
+            assert_eq!(codec.deserialize(&data), Some(value.clone()));
+            assert!(codec.self_describing());
+            assert!(codec.deserialize::<serde_json::Value>(&data).is_some());
+        }
+        let json = Codec::Json.serialize(&value);
+        assert_eq!(Codec::Cbor.deserialize::<(String, u64, Vec<u8>)>(&json), None);
+        assert_eq!(Codec::from_name("xml"), None);
+        assert_eq!(hash(&value), hash_bytes(&Codec::Cbor.serialize(&value)));
+    }
+}